@@ -9,7 +9,7 @@ if [[ $ext -ne 0 ]]; then
 fi
 
 sudo setcap cap_net_admin=eip target/debug/tcp_proto
-RUST_BACKTRACE=1 RUST_LOG=debug target/debug/tcp_proto &
+RUST_BACKTRACE=1 target/debug/tcp_proto --log debug --pcap trace.pcap --output test_recieved.mp4 listen 4000 &
 #target/debug/tcp_proto &
 pid=$!
 
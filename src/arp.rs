@@ -0,0 +1,138 @@
+//! # ARP for IPv4 over Ethernet (RFC 826)
+//!
+//! The packet format and the cache of resolved addresses. Entries expire after
+//! `CACHE_TIMEOUT` so a neighbour that changed its MAC is asked again (RFC 1122 S2.3.2.1).
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+pub type MacAddr = [u8; 6];
+
+pub const BROADCAST: MacAddr = [0xff; 6];
+
+/// EtherType of ARP
+pub const ETHER_TYPE: u16 = 0x0806;
+
+pub const OP_REQUEST: u16 = 1;
+pub const OP_REPLY: u16 = 2;
+
+const HTYPE_ETHERNET: u16 = 1;
+const PTYPE_IPV4: u16 = 0x0800;
+
+/// length of an ARP packet for IPv4 over Ethernet
+pub const PACKET_LEN: usize = 28;
+
+/// how long a resolved address is trusted
+pub const CACHE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// An ARP packet for IPv4 over Ethernet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Packet {
+    pub op: u16,
+    pub sender_mac: MacAddr,
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: MacAddr,
+    pub target_ip: Ipv4Addr,
+}
+
+impl Packet {
+    /// A request for the MAC of `target_ip`
+    pub fn request(sender_mac: MacAddr, sender_ip: Ipv4Addr, target_ip: Ipv4Addr) -> Self {
+        Packet {
+            op: OP_REQUEST,
+            sender_mac,
+            sender_ip,
+            target_mac: [0; 6],
+            target_ip,
+        }
+    }
+
+    /// The reply to `self`, a request for our address `mac`
+    pub fn reply(&self, mac: MacAddr) -> Self {
+        Packet {
+            op: OP_REPLY,
+            sender_mac: mac,
+            sender_ip: self.target_ip,
+            target_mac: self.sender_mac,
+            target_ip: self.sender_ip,
+        }
+    }
+
+    /// Parse an ARP packet, anything but IPv4 over Ethernet is `None`
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < PACKET_LEN {
+            return None;
+        }
+        let htype = u16::from_be_bytes([buf[0], buf[1]]);
+        let ptype = u16::from_be_bytes([buf[2], buf[3]]);
+        if htype != HTYPE_ETHERNET || ptype != PTYPE_IPV4 || buf[4] != 6 || buf[5] != 4 {
+            return None;
+        }
+        let mac = |at: usize| {
+            let mut m = [0u8; 6];
+            m.copy_from_slice(&buf[at..at + 6]);
+            m
+        };
+        let ip = |at: usize| Ipv4Addr::new(buf[at], buf[at + 1], buf[at + 2], buf[at + 3]);
+        Some(Packet {
+            op: u16::from_be_bytes([buf[6], buf[7]]),
+            sender_mac: mac(8),
+            sender_ip: ip(14),
+            target_mac: mac(18),
+            target_ip: ip(24),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; PACKET_LEN] {
+        let mut buf = [0u8; PACKET_LEN];
+        buf[0..2].copy_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+        buf[2..4].copy_from_slice(&PTYPE_IPV4.to_be_bytes());
+        buf[4] = 6;
+        buf[5] = 4;
+        buf[6..8].copy_from_slice(&self.op.to_be_bytes());
+        buf[8..14].copy_from_slice(&self.sender_mac);
+        buf[14..18].copy_from_slice(&self.sender_ip.octets());
+        buf[18..24].copy_from_slice(&self.target_mac);
+        buf[24..28].copy_from_slice(&self.target_ip.octets());
+        buf
+    }
+}
+
+/// Resolved addresses and when we learned them
+#[derive(Default)]
+pub struct Cache {
+    entries: HashMap<Ipv4Addr, (MacAddr, Instant)>,
+}
+
+impl Cache {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The MAC of `ip`, unless we don't know it or it has expired at `now`
+    pub fn lookup(&mut self, ip: Ipv4Addr, now: Instant) -> Option<MacAddr> {
+        match self.entries.get(&ip) {
+            Some(&(mac, at)) if now.saturating_duration_since(at) < CACHE_TIMEOUT => Some(mac),
+            Some(_) => {
+                self.entries.remove(&ip);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Refresh the entry of `ip` if there is one, returns whether there was (RFC 826 merge flag)
+    pub fn update(&mut self, ip: Ipv4Addr, mac: MacAddr, now: Instant) -> bool {
+        match self.entries.get_mut(&ip) {
+            Some(entry) => {
+                *entry = (mac, now);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn insert(&mut self, ip: Ipv4Addr, mac: MacAddr, now: Instant) {
+        self.entries.insert(ip, (mac, now));
+    }
+}
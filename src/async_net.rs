@@ -0,0 +1,355 @@
+//! # The stack on tokio
+//!
+//! With the `tokio` feature the stack can run as a task: `AsyncStack::spawn` hands it to the
+//! runtime, which wakes the task when the tun fd is readable or the next timer is due.
+//! `AsyncTcpListener` and `AsyncTcpStream` implement tokio's `AsyncRead` and `AsyncWrite`, so
+//! hyper, tonic and the rest of the ecosystem run on top unchanged.
+//!
+//! Everything shares one lock around the stack. Whenever the task has handled packets or
+//! timers it wakes every stream that waits, and they look again.
+use crate::nic::NetDevice;
+use crate::tcp::{control_message, flow, tcp, timer};
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::Instant;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+
+/// bytes a stream may queue before writes wait for the peer to acknowledge some
+pub const SEND_BUFFER: usize = 256 * 1024;
+/// packets handled in one go before the task yields
+const BUDGET: usize = 64;
+
+struct State<D: NetDevice> {
+    tcp: tcp<D>,
+    /// tasks waiting for something to change on the stack
+    waiting: Vec<Waker>,
+    /// the stack's task ended, with the error it ended on
+    failed: Option<io::ErrorKind>,
+}
+
+struct Shared<D: NetDevice> {
+    state: Mutex<State<D>>,
+    /// tells the stack's task that an application changed something, like a timer
+    changed: Notify,
+}
+
+impl<D: NetDevice> Shared<D> {
+    fn lock(&self) -> MutexGuard<'_, State<D>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<D: NetDevice> State<D> {
+    /// Wait for the stack to move on, unless its task is gone
+    fn wait(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        if let Some(kind) = self.failed {
+            return Err(io::Error::new(kind, "the stack stopped"));
+        }
+        self.waiting.push(cx.waker().clone());
+        Ok(())
+    }
+}
+
+/// A stack running as a tokio task, clones share the stack
+pub struct AsyncStack<D: NetDevice> {
+    shared: Arc<Shared<D>>,
+}
+
+impl<D: NetDevice> Clone for AsyncStack<D> {
+    fn clone(&self) -> Self {
+        AsyncStack {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+/// the tun fd for `AsyncFd`, which wants to own something with a file descriptor
+struct Fd(RawFd);
+
+impl AsRawFd for Fd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl<D: NetDevice + Send + 'static> AsyncStack<D> {
+    /// Run `stack` as a task on the current tokio runtime, its device is switched to non-blocking mode
+    pub fn spawn(stack: tcp<D>) -> io::Result<Self> {
+        let fd = match stack.nic.as_raw_fd() {
+            Some(fd) => {
+                let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+                if flags < 0
+                    || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                // the stack's task holds the stack, so the device outlives the registration
+                Some(unsafe { AsyncFd::register(Fd(fd)) }?)
+            }
+            None => None,
+        };
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                tcp: stack,
+                waiting: Vec::new(),
+                failed: None,
+            }),
+            changed: Notify::new(),
+        });
+        let task = shared.clone();
+        tokio::spawn(async move {
+            if let Err(e) = drive(&task, fd).await {
+                error!("the stack stopped: {}", e);
+                let mut state = task.lock();
+                state.failed = Some(e.kind());
+                state.waiting.drain(..).for_each(Waker::wake);
+            }
+        });
+        Ok(AsyncStack { shared })
+    }
+}
+
+/// The stack's task: handle packets and timers, then sleep until there are more
+async fn drive<D: NetDevice>(shared: &Shared<D>, fd: Option<AsyncFd<Fd>>) -> io::Result<()> {
+    let mut buf = {
+        let state = shared.lock();
+        vec![0u8; state.tcp.nic.mtu() as usize + state.tcp.nic.capabilities().medium.header_len()]
+    };
+    loop {
+        let (next, backlog) = {
+            let mut state = shared.lock();
+            let mut backlog = true;
+            for _ in 0..BUDGET {
+                let n = match state.tcp.nic.recv(&mut buf) {
+                    Ok(n) => n,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        backlog = false;
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                };
+                state.tcp.action(&buf, n)?;
+            }
+            state.tcp.poll_timers(Instant::now())?;
+            state.waiting.drain(..).for_each(Waker::wake);
+            (state.tcp.next_timeout(), backlog)
+        };
+        if backlog {
+            tokio::task::yield_now().await;
+            continue;
+        }
+        // without a file descriptor the device is polled every tick
+        let next = match (&fd, next) {
+            (Some(_), next) => next,
+            (None, next) => {
+                let tick = Instant::now() + timer::TICK;
+                Some(next.map_or(tick, |n| std::cmp::min(n, tick)))
+            }
+        };
+        let sleep = async {
+            match next {
+                Some(at) => tokio::time::sleep_until(at.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+        let readable = async {
+            match &fd {
+                Some(fd) => fd.readable().await.map(|mut guard| guard.clear_ready()),
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            r = readable => r?,
+            _ = sleep => {}
+            _ = shared.changed.notified() => {}
+        }
+    }
+}
+
+impl<D: NetDevice> AsyncStack<D> {
+    /// Configure or look at the stack, the task waits meanwhile
+    pub fn with<T>(&self, f: impl FnOnce(&mut tcp<D>) -> T) -> T {
+        let result = f(&mut self.shared.lock().tcp);
+        self.shared.changed.notify_one();
+        result
+    }
+
+    /// Listen on `port` of all our addresses
+    pub fn bind(&self, port: u16) -> io::Result<AsyncTcpListener<D>> {
+        self.with(|tcp| tcp.control(control_message::Bind(port)))?;
+        Ok(AsyncTcpListener {
+            stack: self.clone(),
+            port,
+        })
+    }
+
+    /// Connect to `addr` from an ephemeral port, once the handshake is done
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<AsyncTcpStream<D>> {
+        let quad = self.with(|tcp| tcp.connect(addr.ip(), addr.port()))?;
+        poll_fn(|cx| {
+            let mut state = self.shared.lock();
+            let established = match state.tcp.flow(&quad).map(|f| &f.state) {
+                Some(flow::State::SynSent) => false,
+                Some(flow::State::Closed) | None => {
+                    let e = state
+                        .tcp
+                        .take_error(&quad)
+                        .unwrap_or_else(|| io::Error::from(io::ErrorKind::ConnectionRefused));
+                    return Poll::Ready(Err(e));
+                }
+                Some(_) => true,
+            };
+            if established {
+                return Poll::Ready(Ok(()));
+            }
+            state.wait(cx)?;
+            Poll::Pending
+        })
+        .await?;
+        Ok(AsyncTcpStream::new(self.clone(), quad))
+    }
+}
+
+/// Connections to a port, like tokio's `TcpListener`
+pub struct AsyncTcpListener<D: NetDevice> {
+    stack: AsyncStack<D>,
+    port: u16,
+}
+
+impl<D: NetDevice> AsyncTcpListener<D> {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The next connection that completed its handshake, with the address of the peer
+    pub async fn accept(&self) -> io::Result<(AsyncTcpStream<D>, SocketAddr)> {
+        let quad = poll_fn(|cx| {
+            let mut state = self.stack.shared.lock();
+            if let Some(quad) = state.tcp.accept(self.port) {
+                return Poll::Ready(Ok::<_, io::Error>(quad));
+            }
+            state.wait(cx)?;
+            Poll::Pending
+        })
+        .await?;
+        let peer = quad.src.into();
+        Ok((AsyncTcpStream::new(self.stack.clone(), quad), peer))
+    }
+}
+
+/// A connection, like tokio's `TcpStream`. Dropping it closes our side.
+pub struct AsyncTcpStream<D: NetDevice> {
+    stack: AsyncStack<D>,
+    quad: flow::Quad,
+    shut_down: bool,
+}
+
+impl<D: NetDevice> AsyncTcpStream<D> {
+    fn new(stack: AsyncStack<D>, quad: flow::Quad) -> Self {
+        AsyncTcpStream {
+            stack,
+            quad,
+            shut_down: false,
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.quad.src.into()
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.quad.dst.into()
+    }
+}
+
+/// the peer sent its FIN or the connection is gone, whatever is read now is all there is
+fn peer_closed(state: Option<&flow::State>) -> bool {
+    !matches!(
+        state,
+        Some(flow::State::SynRcvd)
+            | Some(flow::State::SynSent)
+            | Some(flow::State::Estab)
+            | Some(flow::State::FinWait1)
+            | Some(flow::State::FinWait2)
+    )
+}
+
+impl<D: NetDevice> AsyncRead for AsyncTcpStream<D> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut state = self.stack.shared.lock();
+        // a closed flow is gone from the stack, what is left is its error or the end of stream
+        let n = match state.tcp.read(&self.quad, buf.initialize_unfilled()) {
+            Ok(n) => n,
+            Err(_) if state.tcp.flow(&self.quad).is_none() => 0,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        if n > 0 {
+            buf.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(e) = state.tcp.take_error(&self.quad) {
+            return Poll::Ready(Err(e));
+        }
+        if peer_closed(state.tcp.flow(&self.quad).map(|f| &f.state)) {
+            // end of stream
+            return Poll::Ready(Ok(()));
+        }
+        state.wait(cx)?;
+        Poll::Pending
+    }
+}
+
+impl<D: NetDevice> AsyncWrite for AsyncTcpStream<D> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.stack.shared.lock();
+        let buffered = state.tcp.flow(&self.quad).map_or(0, |f| f.send_buffered());
+        if buffered >= SEND_BUFFER {
+            state.wait(cx)?;
+            return Poll::Pending;
+        }
+        let n = std::cmp::min(buf.len(), SEND_BUFFER - buffered);
+        let result = state.tcp.write(&self.quad, &buf[..n]);
+        drop(state);
+        self.stack.shared.changed.notify_one();
+        Poll::Ready(result)
+    }
+
+    /// Written data is queued in the stack like in a kernel socket buffer, nothing to flush
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Send our FIN after the queued data
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.shut_down {
+            self.shut_down = true;
+            self.stack.with(|tcp| tcp.close(&self.quad))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<D: NetDevice> Drop for AsyncTcpStream<D> {
+    fn drop(&mut self) {
+        if !self.shut_down {
+            let quad = self.quad;
+            // the connection may be gone already
+            let _ = self.stack.with(|tcp| tcp.close(&quad));
+        }
+    }
+}
@@ -0,0 +1,232 @@
+//! # Ethernet II framing on top of a tap device
+//!
+//! `Ethernet` wraps a device that carries Ethernet frames and is itself a device that carries
+//! IP packets, so the stack runs on it unchanged. Outgoing packets are addressed with ARP:
+//! while an address is being resolved its packets wait in a short queue, and the request is
+//! repeated every `ARP_RETRY` up to `ARP_MAX_REQUESTS` times before they are dropped. The
+//! retries go by the stack's clock, whenever the stack calls `set_now`. There is no NDP, so
+//! IPv6 packets are dropped and counted in `not_ipv4`.
+use crate::arp;
+use crate::arp::MacAddr;
+use crate::nic::{Capabilities, Medium, NetDevice};
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+const ETHER_TYPE_IPV4: u16 = 0x0800;
+
+/// at most one ARP request per address per second (RFC 1122 S2.3.2.1)
+const ARP_RETRY: Duration = Duration::from_secs(1);
+const ARP_MAX_REQUESTS: u8 = 3;
+/// packets kept per unresolved address, the oldest are dropped first (RFC 1122 S2.3.2.2)
+const ARP_QUEUE_LEN: usize = 8;
+
+/// packets waiting for an address to be resolved
+struct Pending {
+    packets: Vec<Vec<u8>>,
+    requested_at: Instant,
+    requests: u8,
+}
+
+/// Ethernet and ARP for the address `ip` and the MAC `mac` on the device `dev`
+pub struct Ethernet<D: NetDevice> {
+    pub dev: D,
+    pub mac: MacAddr,
+    pub ip: Ipv4Addr,
+    arp: arp::Cache,
+    pending: HashMap<Ipv4Addr, Pending>,
+    frame: Vec<u8>,
+    /// the stack's clock, see `NetDevice::set_now`
+    now: Instant,
+    /// packets dropped on the way out because they aren't IPv4
+    pub not_ipv4: u64,
+}
+
+impl<D: NetDevice> Ethernet<D> {
+    pub fn new(dev: D, mac: MacAddr, ip: Ipv4Addr) -> Self {
+        let frame = vec![0u8; dev.mtu() as usize + Medium::Ethernet.header_len()];
+        Ethernet {
+            dev,
+            mac,
+            ip,
+            arp: arp::Cache::new(),
+            pending: Default::default(),
+            frame,
+            now: Instant::now(),
+            not_ipv4: 0,
+        }
+    }
+
+    /// Send `payload` in a frame to `dst`
+    fn send_frame(&mut self, dst: MacAddr, ether_type: u16, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(Medium::Ethernet.header_len() + payload.len());
+        etherparse::Ethernet2Header {
+            destination: dst,
+            source: self.mac,
+            ether_type,
+        }
+        .write(&mut frame)?;
+        frame.extend_from_slice(payload);
+        self.dev.send(&frame)?;
+        Ok(())
+    }
+
+    fn send_arp(&mut self, dst: MacAddr, packet: arp::Packet) -> io::Result<()> {
+        self.send_frame(dst, arp::ETHER_TYPE, &packet.to_bytes())
+    }
+
+    /// Ask for the MAC of `ip` again if the last request is old enough, give up after `ARP_MAX_REQUESTS`
+    fn resolve(&mut self, ip: Ipv4Addr) -> io::Result<()> {
+        let p = match self.pending.get_mut(&ip) {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        if p.requests > 0 && self.now.saturating_duration_since(p.requested_at) < ARP_RETRY {
+            return Ok(());
+        }
+        if p.requests >= ARP_MAX_REQUESTS {
+            debug!(
+                "{} did not answer ARP, dropping {} packets",
+                ip,
+                p.packets.len()
+            );
+            self.pending.remove(&ip);
+            return Ok(());
+        }
+        p.requests += 1;
+        p.requested_at = self.now;
+        self.send_arp(arp::BROADCAST, arp::Packet::request(self.mac, self.ip, ip))
+    }
+
+    /// Ask again for the addresses that are still being resolved, drop those that never answered
+    fn retry(&mut self) {
+        let waiting: Vec<Ipv4Addr> = self.pending.keys().copied().collect();
+        for ip in waiting {
+            if let Err(e) = self.resolve(ip) {
+                warn!("ARP request for {} failed: {}", ip, e);
+            }
+        }
+    }
+
+    /// The receive side of RFC 826
+    fn arp_input(&mut self, packet: arp::Packet) -> io::Result<()> {
+        let merged = self
+            .arp
+            .update(packet.sender_ip, packet.sender_mac, self.now);
+        if packet.target_ip != self.ip {
+            return Ok(());
+        }
+        if !merged {
+            self.arp
+                .insert(packet.sender_ip, packet.sender_mac, self.now);
+        }
+        if packet.op == arp::OP_REQUEST {
+            self.send_arp(packet.sender_mac, packet.reply(self.mac))?;
+        }
+        // whatever was waiting for this address can go now
+        if let Some(p) = self.pending.remove(&packet.sender_ip) {
+            for ip_packet in p.packets {
+                self.send_frame(packet.sender_mac, ETHER_TYPE_IPV4, &ip_packet)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<D: NetDevice> NetDevice for Ethernet<D> {
+    /// Send an IPv4 packet to its destination, queued while the MAC is being resolved.
+    /// An address that can't be resolved drops its packets quietly, like a lossy link,
+    /// and so does a packet that isn't IPv4.
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let iph = match etherparse::Ipv4HeaderSlice::from_slice(buf) {
+            Ok(iph) => iph,
+            Err(_) => {
+                debug!("dropping a packet that isn't IPv4");
+                self.not_ipv4 += 1;
+                return Ok(buf.len());
+            }
+        };
+        let dst = iph.destination_addr();
+        if dst.is_broadcast() {
+            self.send_frame(arp::BROADCAST, ETHER_TYPE_IPV4, buf)?;
+            return Ok(buf.len());
+        }
+        if let Some(mac) = self.arp.lookup(dst, self.now) {
+            self.send_frame(mac, ETHER_TYPE_IPV4, buf)?;
+            return Ok(buf.len());
+        }
+        let now = self.now;
+        let p = self.pending.entry(dst).or_insert_with(|| Pending {
+            packets: Vec::new(),
+            requested_at: now,
+            requests: 0,
+        });
+        if p.packets.len() == ARP_QUEUE_LEN {
+            p.packets.remove(0);
+        }
+        p.packets.push(buf.to_vec());
+        self.resolve(dst)?;
+        Ok(buf.len())
+    }
+
+    /// Receive the next IPv4 packet addressed to our MAC, answering ARP on the way
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.dev.recv(&mut self.frame)?;
+            let eth = match etherparse::Ethernet2HeaderSlice::from_slice(&self.frame[..n]) {
+                Ok(eth) => eth,
+                Err(_) => continue,
+            };
+            let mut dst: MacAddr = [0; 6];
+            dst.copy_from_slice(eth.destination());
+            let ether_type = eth.ether_type();
+            if dst != self.mac && dst != arp::BROADCAST {
+                continue;
+            }
+            let payload_at = Medium::Ethernet.header_len();
+            match ether_type {
+                ETHER_TYPE_IPV4 => {
+                    // short frames are padded to 60 bytes, the IP total length says what is real
+                    let mut len = n - payload_at;
+                    if len >= 4 {
+                        let total_len = u16::from_be_bytes([
+                            self.frame[payload_at + 2],
+                            self.frame[payload_at + 3],
+                        ]);
+                        len = std::cmp::min(len, total_len as usize);
+                    }
+                    let len = std::cmp::min(buf.len(), len);
+                    buf[..len].copy_from_slice(&self.frame[payload_at..payload_at + len]);
+                    return Ok(len);
+                }
+                arp::ETHER_TYPE => {
+                    if let Some(packet) = arp::Packet::parse(&self.frame[payload_at..n]) {
+                        self.arp_input(packet)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn mtu(&self) -> u16 {
+        self.dev.mtu()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            medium: Medium::Ip,
+            ..self.dev.capabilities()
+        }
+    }
+    fn set_now(&mut self, now: Instant) {
+        self.now = now;
+        self.dev.set_now(now);
+        self.retry();
+    }
+    fn as_raw_fd(&self) -> Option<RawFd> {
+        self.dev.as_raw_fd()
+    }
+}
@@ -0,0 +1,184 @@
+//! # An event loop for the stack
+//!
+//! `EventLoop` owns a stack and sleeps in epoll until the device has a packet, a timer of the
+//! stack is due, or the application asks for something through a `Handle`. Handles can be
+//! cloned and sent to other threads: a command is a closure run on the stack by the loop,
+//! and an eventfd wakes the loop to run it.
+//!
+//! Devices without a file descriptor, like `nic::pipe`, are polled every `timer::TICK`.
+use crate::nic::NetDevice;
+use crate::tcp::{tcp, timer};
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+/// epoll token of the device
+const DEVICE: u64 = 0;
+/// epoll token of the eventfd of the handles
+const WAKEUP: u64 = 1;
+/// packets handled in one turn before the timers get their chance
+const BUDGET: usize = 64;
+
+/// Something the application wants done on the stack
+pub type Command<D> = Box<dyn FnOnce(&mut tcp<D>) + Send>;
+
+/// Runs the stack: packets, timers and commands
+pub struct EventLoop<D: NetDevice> {
+    pub stack: tcp<D>,
+    epoll: OwnedFd,
+    wakeup: Arc<OwnedFd>,
+    commands: mpsc::Receiver<Command<D>>,
+    tx: mpsc::Sender<Command<D>>,
+    buf: Vec<u8>,
+    /// the last turn used up its budget, more packets may be waiting
+    backlog: bool,
+}
+
+/// Sends commands to an `EventLoop` from anywhere
+pub struct Handle<D: NetDevice> {
+    tx: mpsc::Sender<Command<D>>,
+    wakeup: Arc<OwnedFd>,
+}
+
+impl<D: NetDevice> Clone for Handle<D> {
+    fn clone(&self) -> Self {
+        Handle {
+            tx: self.tx.clone(),
+            wakeup: self.wakeup.clone(),
+        }
+    }
+}
+
+impl<D: NetDevice> Handle<D> {
+    /// Run `command` on the stack in the loop's thread, as soon as the loop wakes up.
+    /// Results have to come back through the closure, over a channel for example.
+    pub fn run<F: FnOnce(&mut tcp<D>) + Send + 'static>(&self, command: F) -> io::Result<()> {
+        self.tx
+            .send(Box::new(command))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the event loop is gone"))?;
+        let one = 1u64.to_ne_bytes();
+        let n = unsafe { libc::write(self.wakeup.as_raw_fd(), one.as_ptr() as *const _, 8) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// -1 as an io::Error
+fn cvt(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+fn epoll_add(epoll: &OwnedFd, fd: RawFd, token: u64) -> io::Result<()> {
+    let mut event = libc::epoll_event {
+        events: libc::EPOLLIN as u32,
+        u64: token,
+    };
+    cvt(unsafe { libc::epoll_ctl(epoll.as_raw_fd(), libc::EPOLL_CTL_ADD, fd, &mut event) })?;
+    Ok(())
+}
+
+impl<D: NetDevice> EventLoop<D> {
+    /// Take over `stack`, its device is switched to non-blocking mode
+    pub fn new(stack: tcp<D>) -> io::Result<Self> {
+        let epoll = cvt(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        let epoll = unsafe { OwnedFd::from_raw_fd(epoll) };
+        let wakeup = cvt(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) })?;
+        let wakeup = unsafe { OwnedFd::from_raw_fd(wakeup) };
+        epoll_add(&epoll, wakeup.as_raw_fd(), WAKEUP)?;
+        if let Some(fd) = stack.nic.as_raw_fd() {
+            let flags = cvt(unsafe { libc::fcntl(fd, libc::F_GETFL) })?;
+            cvt(unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) })?;
+            epoll_add(&epoll, fd, DEVICE)?;
+        }
+        let (tx, commands) = mpsc::channel();
+        let buf =
+            vec![0u8; stack.nic.mtu() as usize + stack.nic.capabilities().medium.header_len()];
+        Ok(EventLoop {
+            stack,
+            epoll,
+            wakeup: Arc::new(wakeup),
+            commands,
+            tx,
+            buf,
+            backlog: false,
+        })
+    }
+
+    /// A handle to send commands to the loop with
+    pub fn handle(&self) -> Handle<D> {
+        Handle {
+            tx: self.tx.clone(),
+            wakeup: self.wakeup.clone(),
+        }
+    }
+
+    /// Run forever, or until the device or the stack fails
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            self.turn(None)?;
+        }
+    }
+
+    /// Wait for something to happen, at most `timeout` if given, and handle it:
+    /// commands first, then received packets, then the timers that are due.
+    pub fn turn(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        let mut wait = self
+            .stack
+            .next_timeout()
+            .map(|at| at.saturating_duration_since(Instant::now()));
+        if self.stack.nic.as_raw_fd().is_none() {
+            // nothing tells us when such a device has a packet
+            wait = Some(wait.map_or(timer::TICK, |w| std::cmp::min(w, timer::TICK)));
+        }
+        if let Some(timeout) = timeout {
+            wait = Some(wait.map_or(timeout, |w| std::cmp::min(w, timeout)));
+        }
+        if self.backlog {
+            wait = Some(Duration::ZERO);
+        }
+        // round up, waking before the deadline would only mean waiting again
+        let ms = match wait {
+            Some(w) => std::cmp::min(w.as_nanos().div_ceil(1_000_000), i32::MAX as u128) as i32,
+            None => -1,
+        };
+
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; 2];
+        let n = unsafe { libc::epoll_wait(self.epoll.as_raw_fd(), events.as_mut_ptr(), 2, ms) };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+        for event in events.iter().take(std::cmp::max(n, 0) as usize) {
+            if event.u64 == WAKEUP {
+                let mut count = [0u8; 8];
+                unsafe { libc::read(self.wakeup.as_raw_fd(), count.as_mut_ptr() as *mut _, 8) };
+            }
+        }
+
+        while let Ok(command) = self.commands.try_recv() {
+            command(&mut self.stack);
+        }
+        self.backlog = true;
+        for _ in 0..BUDGET {
+            let n = match self.stack.nic.recv(&mut self.buf) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.backlog = false;
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            self.stack.action(&self.buf, n)?;
+        }
+        self.stack.poll_timers(Instant::now())
+    }
+}
@@ -0,0 +1,174 @@
+//! # ICMPv4 (RFC 792)
+//!
+//! Echo requests are answered, destination unreachable messages about our TCP segments are
+//! mapped back to their flow, which treats them as soft or hard errors (RFC 5927) or, for
+//! fragmentation needed, as a new path MTU (RFC 1191).
+use crate::ip;
+use crate::tcp::flow::Quad;
+use crate::tcp::output::Output;
+use std::io;
+use std::net::IpAddr;
+
+/// IP protocol number of ICMP
+pub const PROTOCOL: u8 = 1;
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_DEST_UNREACHABLE: u8 = 3;
+const TYPE_ECHO_REQUEST: u8 = 8;
+
+/// the ICMP header: type, code, checksum and four type specific bytes
+const HEADER_LEN: usize = 8;
+
+/// Destination unreachable codes (RFC 792, RFC 1122 S3.2.2.1)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unreachable {
+    Net,
+    Host,
+    Protocol,
+    Port,
+    /// fragmentation needed and DF set, with the next-hop MTU (0 if the router didn't say)
+    FragmentationNeeded(u16),
+    SourceRouteFailed,
+    /// administratively prohibited and the other codes of RFC 1122
+    Other(u8),
+}
+
+impl Unreachable {
+    fn from_code(code: u8, rest: &[u8]) -> Self {
+        match code {
+            0 => Unreachable::Net,
+            1 => Unreachable::Host,
+            2 => Unreachable::Protocol,
+            3 => Unreachable::Port,
+            4 => Unreachable::FragmentationNeeded(u16::from_be_bytes([rest[2], rest[3]])),
+            5 => Unreachable::SourceRouteFailed,
+            c => Unreachable::Other(c),
+        }
+    }
+
+    /// Protocol and port unreachable say nobody is there, the rest may be transient (RFC 1122 S4.2.3.9)
+    pub fn is_hard(&self) -> bool {
+        matches!(self, Unreachable::Protocol | Unreachable::Port)
+    }
+}
+
+impl From<Unreachable> for io::Error {
+    fn from(e: Unreachable) -> Self {
+        match e {
+            Unreachable::Protocol | Unreachable::Port => {
+                io::Error::new(io::ErrorKind::ConnectionRefused, format!("{:?}", e))
+            }
+            Unreachable::Net => {
+                io::Error::new(io::ErrorKind::NetworkUnreachable, "net unreachable")
+            }
+            Unreachable::Host => io::Error::new(io::ErrorKind::HostUnreachable, "host unreachable"),
+            e => io::Error::other(format!("destination unreachable: {:?}", e)),
+        }
+    }
+}
+
+/// The ICMP messages the stack acts on
+pub enum Message {
+    EchoRequest,
+    /// a destination unreachable about a TCP segment we sent, `seq` is its sequence number
+    Unreachable {
+        error: Unreachable,
+        quad: Quad,
+        seq: u32,
+    },
+}
+
+/// Parse an ICMP message, anything we don't act on or that is malformed gives `None`.
+pub fn parse(icmp: &[u8]) -> Option<Message> {
+    if icmp.len() < HEADER_LEN {
+        return None;
+    }
+    match icmp[0] {
+        TYPE_ECHO_REQUEST => Some(Message::EchoRequest),
+        TYPE_DEST_UNREACHABLE => {
+            // the original IP header plus at least the first 8 bytes of its payload follow
+            let inner = &icmp[HEADER_LEN..];
+            let iph = etherparse::Ipv4HeaderSlice::from_slice(inner).ok()?;
+            if iph.protocol() != ip::PROTOCOL_TCP {
+                return None;
+            }
+            let tcp = inner.get(iph.slice().len()..iph.slice().len() + 8)?;
+            // we sent it, so the original source is us and the destination our peer
+            let quad = Quad {
+                src: (
+                    iph.destination_addr().into(),
+                    u16::from_be_bytes([tcp[2], tcp[3]]),
+                ),
+                dst: (
+                    iph.source_addr().into(),
+                    u16::from_be_bytes([tcp[0], tcp[1]]),
+                ),
+            };
+            Some(Message::Unreachable {
+                error: Unreachable::from_code(icmp[1], &icmp[4..HEADER_LEN]),
+                quad,
+                seq: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Answer the echo request `icmp` that arrived in `iph`.
+pub fn echo_reply(out: &mut Output, iph: &ip::Packet, icmp: &[u8]) -> io::Result<()> {
+    let mut ip = ip::Header::new(iph.dst, iph.src, PROTOCOL);
+    ip.set_payload_len(icmp.len())?;
+    let mut buf = Vec::with_capacity(ip.header_len() + icmp.len());
+    ip.write(&mut buf)?;
+    let start = buf.len();
+    // identifier, sequence number and data are echoed back unchanged
+    buf.extend_from_slice(icmp);
+    buf[start] = TYPE_ECHO_REPLY;
+    buf[start + 2..start + 4].copy_from_slice(&[0, 0]);
+    let sum = checksum(&buf[start..]);
+    buf[start + 2..start + 4].copy_from_slice(&sum.to_be_bytes());
+    out.transmit(&buf);
+    Ok(())
+}
+
+/// Tell the sender of `iph` that nobody listens on the port it was sent to (RFC 1122 S4.1.3.1).
+/// Nothing is sent for broadcast or multicast packets (RFC 1122 S3.2.2).
+pub fn port_unreachable(out: &mut Output, iph: &ip::Packet) -> io::Result<()> {
+    if iph.dst.is_multicast() || matches!(iph.dst, IpAddr::V4(a) if a.is_broadcast()) {
+        return Ok(());
+    }
+    let mut message = vec![TYPE_DEST_UNREACHABLE, 3, 0, 0, 0, 0, 0, 0];
+    // as much of the original packet as fits in 576 bytes (RFC 1812 S4.3.2.3)
+    message.extend(
+        iph.header
+            .iter()
+            .chain(iph.payload)
+            .take(576 - 20 - HEADER_LEN),
+    );
+    let sum = checksum(&message);
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+    let mut ip = ip::Header::new(iph.dst, iph.src, PROTOCOL);
+    ip.set_payload_len(message.len())?;
+    let mut buf = Vec::with_capacity(ip.header_len() + message.len());
+    ip.write(&mut buf)?;
+    buf.extend_from_slice(&message);
+    out.transmit(&buf);
+    Ok(())
+}
+
+/// The internet checksum (RFC 1071)
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in data.chunks(2) {
+        let word = if chunk.len() == 2 {
+            u16::from_be_bytes([chunk[0], chunk[1]])
+        } else {
+            u16::from_be_bytes([chunk[0], 0])
+        };
+        sum += word as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
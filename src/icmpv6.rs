@@ -0,0 +1,106 @@
+//! # ICMPv6 (RFC 4443)
+//!
+//! The IPv6 counterpart of `icmp`: echo requests are answered, destination unreachable and
+//! packet too big about our TCP segments go to their flow. Packet too big is the only way an
+//! IPv6 path reports its MTU, routers don't fragment (RFC 8201).
+use crate::icmp::{Message, Unreachable};
+use crate::ip;
+use crate::tcp::flow::Quad;
+use crate::tcp::output::Output;
+use std::io;
+use std::net::IpAddr;
+
+/// next header value of ICMPv6
+pub const PROTOCOL: u8 = 58;
+
+const TYPE_DEST_UNREACHABLE: u8 = 1;
+const TYPE_PACKET_TOO_BIG: u8 = 2;
+const TYPE_ECHO_REQUEST: u8 = 128;
+const TYPE_ECHO_REPLY: u8 = 129;
+
+/// type, code, checksum and four type specific bytes, like ICMPv4
+const HEADER_LEN: usize = 8;
+
+/// Parse an ICMPv6 message, anything we don't act on or that is malformed gives `None`.
+pub fn parse(icmp: &[u8]) -> Option<Message> {
+    if icmp.len() < HEADER_LEN {
+        return None;
+    }
+    let error = match icmp[0] {
+        TYPE_ECHO_REQUEST => return Some(Message::EchoRequest),
+        TYPE_DEST_UNREACHABLE => match icmp[1] {
+            0 => Unreachable::Net,
+            3 => Unreachable::Host,
+            4 => Unreachable::Port,
+            c => Unreachable::Other(c),
+        },
+        TYPE_PACKET_TOO_BIG => {
+            let mtu = u32::from_be_bytes([icmp[4], icmp[5], icmp[6], icmp[7]]);
+            Unreachable::FragmentationNeeded(std::cmp::min(mtu, u16::MAX as u32) as u16)
+        }
+        _ => return None,
+    };
+    // as much of the original packet as fits follows, we need its header and 8 bytes of TCP
+    let inner = ip::parse(&icmp[HEADER_LEN..])?;
+    if inner.protocol != ip::PROTOCOL_TCP {
+        return None;
+    }
+    let tcp = inner.payload.get(..8)?;
+    // we sent it, so the original source is us and the destination our peer
+    let quad = Quad {
+        src: (inner.dst, u16::from_be_bytes([tcp[2], tcp[3]])),
+        dst: (inner.src, u16::from_be_bytes([tcp[0], tcp[1]])),
+    };
+    Some(Message::Unreachable {
+        error,
+        quad,
+        seq: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+    })
+}
+
+/// Answer the echo request `icmp` that arrived in `iph`.
+pub fn echo_reply(out: &mut Output, iph: &ip::Packet, icmp: &[u8]) -> io::Result<()> {
+    let mut ip = ip::Header::new(iph.dst, iph.src, PROTOCOL);
+    ip.set_payload_len(icmp.len())?;
+    let mut buf = Vec::with_capacity(ip.header_len() + icmp.len());
+    ip.write(&mut buf)?;
+    let start = buf.len();
+    // identifier, sequence number and data are echoed back unchanged
+    buf.extend_from_slice(icmp);
+    buf[start] = TYPE_ECHO_REPLY;
+    buf[start + 2..start + 4].copy_from_slice(&[0, 0]);
+    let sum = checksum(iph.dst, iph.src, &buf[start..]);
+    buf[start + 2..start + 4].copy_from_slice(&sum.to_be_bytes());
+    out.transmit(&buf);
+    Ok(())
+}
+
+/// Tell the sender of `iph` that nobody listens on the port it was sent to (RFC 4443 S3.1).
+/// Nothing is sent for multicast packets (RFC 4443 S2.4).
+pub fn port_unreachable(out: &mut Output, iph: &ip::Packet) -> io::Result<()> {
+    if iph.dst.is_multicast() {
+        return Ok(());
+    }
+    let mut message = vec![TYPE_DEST_UNREACHABLE, 4, 0, 0, 0, 0, 0, 0];
+    // as much of the original packet as fits in the minimum MTU
+    message.extend(
+        iph.header
+            .iter()
+            .chain(iph.payload)
+            .take(1280 - 40 - HEADER_LEN),
+    );
+    let sum = checksum(iph.dst, iph.src, &message);
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+    let mut ip = ip::Header::new(iph.dst, iph.src, PROTOCOL);
+    ip.set_payload_len(message.len())?;
+    let mut buf = Vec::with_capacity(ip.header_len() + message.len());
+    ip.write(&mut buf)?;
+    buf.extend_from_slice(&message);
+    out.transmit(&buf);
+    Ok(())
+}
+
+/// The ICMPv6 checksum, it covers a pseudo header like TCP (RFC 8200 S8.1)
+pub fn checksum(src: IpAddr, dst: IpAddr, message: &[u8]) -> u16 {
+    ip::checksum(src, dst, PROTOCOL, message)
+}
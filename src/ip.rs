@@ -0,0 +1,184 @@
+//! # IPv4 and IPv6
+//!
+//! The stack sees both versions through `Packet`, what arrived, and `Header`, what we send.
+//! IPv6 extension headers are skipped on receive and never sent.
+use std::io;
+use std::net::IpAddr;
+
+/// IP protocol number of TCP
+pub const PROTOCOL_TCP: u8 = 6;
+/// IP protocol number of UDP
+pub const PROTOCOL_UDP: u8 = 17;
+
+/// The parts of an incoming IPv4 or IPv6 packet the stack looks at
+pub struct Packet<'a> {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    /// protocol of the payload, the last next header for IPv6
+    pub protocol: u8,
+    /// the ECN field (RFC 3168)
+    pub ecn: u8,
+    /// the IP header, including IPv6 extension headers
+    pub header: &'a [u8],
+    /// the payload up to the length the header gives, without link layer padding
+    pub payload: &'a [u8],
+}
+
+/// Parse an IPv4 or IPv6 packet, anything malformed gives `None`
+pub fn parse(buf: &[u8]) -> Option<Packet<'_>> {
+    match buf.first()? >> 4 {
+        4 => {
+            let iph = etherparse::Ipv4HeaderSlice::from_slice(buf).ok()?;
+            let header_len = iph.slice().len();
+            let end = std::cmp::min(buf.len(), iph.total_len() as usize);
+            Some(Packet {
+                src: iph.source_addr().into(),
+                dst: iph.destination_addr().into(),
+                protocol: iph.protocol(),
+                ecn: iph.ecn(),
+                header: &buf[..header_len],
+                payload: buf.get(header_len..end)?,
+            })
+        }
+        6 => {
+            let iph = etherparse::Ipv6HeaderSlice::from_slice(buf).ok()?;
+            let end = std::cmp::min(buf.len(), 40 + iph.payload_length() as usize);
+            let (protocol, rest) = etherparse::Ipv6Header::skip_all_header_extensions_in_slice(
+                &buf[40..end],
+                iph.next_header(),
+            )
+            .ok()?;
+            let header_len = end - rest.len();
+            Some(Packet {
+                src: iph.source_addr().into(),
+                dst: iph.destination_addr().into(),
+                protocol,
+                ecn: iph.traffic_class() & 0b11,
+                header: &buf[..header_len],
+                payload: rest,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Whether the header checksum of an IPv4 packet is right, IPv6 has none
+pub fn header_checksum_ok(buf: &[u8]) -> bool {
+    match etherparse::Ipv4HeaderSlice::from_slice(buf) {
+        Ok(iph) => !sum_folded(iph.slice()) == 0,
+        Err(_) => true,
+    }
+}
+
+/// The internet checksum of `segment` behind the pseudo header of `src`, `dst` and `protocol`,
+/// as TCP, UDP and ICMPv6 use it (RFC 793 S3.1, RFC 768, RFC 8200 S8.1).
+/// Over a segment that carries a correct checksum the result is 0.
+pub fn checksum(src: IpAddr, dst: IpAddr, protocol: u8, segment: &[u8]) -> u16 {
+    let len = segment.len() as u32;
+    let pseudo = match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            sum(&src.octets()) + sum(&dst.octets()) + protocol as u64 + len as u64
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            sum(&src.octets()) + sum(&dst.octets()) + sum(&len.to_be_bytes()) + protocol as u64
+        }
+        _ => panic!("no pseudo header from {} to {}", src, dst),
+    };
+    !fold(pseudo + sum(segment))
+}
+
+fn sum_folded(data: &[u8]) -> u16 {
+    fold(sum(data))
+}
+
+/// add the carries back in, one's complement style
+fn fold(mut total: u64) -> u16 {
+    while total >> 16 != 0 {
+        total = (total & 0xffff) + (total >> 16);
+    }
+    total as u16
+}
+
+/// sum of the 16 bit words of `data`, padded with a zero byte
+fn sum(data: &[u8]) -> u64 {
+    data.chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u64)
+        .sum()
+}
+
+/// The IP header of the packets we send
+#[derive(Clone, Debug)]
+pub enum Header {
+    V4(etherparse::Ipv4Header),
+    V6(etherparse::Ipv6Header),
+}
+
+impl Header {
+    /// A header from `src` to `dst` carrying `protocol`.
+    /// Both addresses have to be of the same family, the quads the stack builds always are.
+    pub fn new(src: IpAddr, dst: IpAddr, protocol: u8) -> Self {
+        match (src, dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                let mut ip = etherparse::Ipv4Header::new(
+                    0,
+                    64,
+                    etherparse::IpTrafficClass::Tcp,
+                    src.octets(),
+                    dst.octets(),
+                );
+                ip.protocol = protocol;
+                Header::V4(ip)
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => Header::V6(etherparse::Ipv6Header {
+                traffic_class: 0,
+                flow_label: 0,
+                payload_length: 0,
+                next_header: protocol,
+                hop_limit: 64,
+                source: src.octets(),
+                destination: dst.octets(),
+            }),
+            _ => panic!("no header from {} to {}", src, dst),
+        }
+    }
+
+    pub fn header_len(&self) -> usize {
+        match self {
+            Header::V4(ip) => ip.header_len(),
+            Header::V6(_) => 40,
+        }
+    }
+
+    pub fn set_payload_len(&mut self, len: usize) -> io::Result<()> {
+        match self {
+            Header::V4(ip) => ip.set_payload_len(len),
+            Header::V6(ip) => ip.set_payload_length(len),
+        }
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e)))
+    }
+
+    /// Set the ECN field (RFC 3168 S5)
+    pub fn set_ecn(&mut self, ecn: u8) {
+        match self {
+            Header::V4(ip) => ip.explicit_congestion_notification = ecn,
+            Header::V6(ip) => ip.traffic_class = (ip.traffic_class & !0b11) | ecn,
+        }
+    }
+
+    pub fn write<T: io::Write>(&self, writer: &mut T) -> io::Result<()> {
+        match self {
+            Header::V4(ip) => ip.write(writer),
+            Header::V6(ip) => ip.write(writer),
+        }
+        .map_err(|e| io::Error::other(format!("{:?}", e)))
+    }
+
+    /// The TCP checksum over the pseudo header of this IP header, `tcp` and `payload`
+    pub fn tcp_checksum(&self, tcp: &etherparse::TcpHeader, payload: &[u8]) -> io::Result<u16> {
+        match self {
+            Header::V4(ip) => tcp.calc_checksum_ipv4(ip, payload),
+            Header::V6(ip) => tcp.calc_checksum_ipv6(ip, payload),
+        }
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e)))
+    }
+}
@@ -1,5 +1,18 @@
+// the stack names its types after the protocol objects they model (`tcp`, `flow`, ...)
+#![allow(non_camel_case_types, non_snake_case)]
 #[macro_use]
 extern crate log;
+pub mod arp;
+#[cfg(feature = "tokio")]
+pub mod async_net;
+pub mod ethernet;
+pub mod event;
+pub mod icmp;
+pub mod icmpv6;
+pub mod ip;
 pub mod nic;
+pub mod pcap;
+pub mod reassembly;
 pub mod tcp;
 pub mod test;
+pub mod udp;
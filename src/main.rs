@@ -1,32 +1,325 @@
-use std::io;
-use std::net::Ipv4Addr;
-use tcp_proto::nic::Interface;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use tcp_proto::event::{EventLoop, Handle};
+use tcp_proto::nic::{self, NetDevice};
+use tcp_proto::pcap;
 use tcp_proto::tcp::control_message;
+use tcp_proto::tcp::flow::Quad;
+use tcp_proto::tcp::output::Event;
 use tcp_proto::tcp::tcp;
-use tcp_proto::test::{test1, test2, test3};
 
-use std::{thread, time};
+use std::{env, fs, process, thread, time};
+
+const USAGE: &str = "\
+usage: tcp_proto [options] [listen <port>]... [connect <ip:port>]...
+       tcp_proto [-i <name>] ss
+
+commands:
+    listen <port>          accept connections on <port>
+    connect <ip:port>      connect to <ip:port> from an ephemeral port
+    ss                     print the sockets of the instance running on the interface,
+                           on its own and last
+
+options:
+    -i, --interface <name>     tun device to open [tun0]
+    -a, --address <ip/prefix>  our address and its subnet [192.168.0.2/24],
+                               given again with an IPv6 address for IPv6
+    --log <filter>             log level or RUST_LOG style filter [RUST_LOG]
+    --pcap <file>              write everything sent and received to <file>
+    -o, --output <file>        append the data the connections receive to <file>,
+                               otherwise it is read and thrown away
+    --delay <seconds>          wait before connecting, for the interface to come up [3]
+    -h, --help                 print this
+";
+
+/// What the command line asks for
+struct Options {
+    interface: String,
+    ip: Ipv4Addr,
+    prefix: u8,
+    ip6: Option<Ipv6Addr>,
+    log: Option<String>,
+    pcap: Option<PathBuf>,
+    output: Option<PathBuf>,
+    delay: time::Duration,
+    listen: Vec<u16>,
+    connect: Vec<SocketAddr>,
+    ss: bool,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, String> {
+        let mut options = Options {
+            interface: "tun0".to_string(),
+            ip: Ipv4Addr::new(192, 168, 0, 2),
+            prefix: 24,
+            ip6: None,
+            log: None,
+            pcap: None,
+            output: None,
+            delay: time::Duration::from_secs(3),
+            listen: Vec::new(),
+            connect: Vec::new(),
+            ss: false,
+        };
+        while let Some(arg) = args.next() {
+            let mut value =
+                |what: &str| args.next().ok_or_else(|| format!("{} needs {}", arg, what));
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "-i" | "--interface" => options.interface = value("a device name")?,
+                "-a" | "--address" => {
+                    let value = value("an address")?;
+                    let (ip, prefix) = match value.split_once('/') {
+                        Some((ip, prefix)) => (ip, Some(prefix)),
+                        None => (value.as_str(), None),
+                    };
+                    match ip.parse() {
+                        Ok(IpAddr::V4(ip)) => {
+                            options.ip = ip;
+                            options.prefix = match prefix {
+                                Some(prefix) => prefix
+                                    .parse()
+                                    .ok()
+                                    .filter(|p| *p <= 32)
+                                    .ok_or_else(|| format!("bad prefix length in {}", value))?,
+                                None => 32,
+                            };
+                        }
+                        Ok(IpAddr::V6(ip)) => options.ip6 = Some(ip),
+                        Err(_) => return Err(format!("bad address {}", value)),
+                    }
+                }
+                "--log" => options.log = Some(value("a filter")?),
+                "--pcap" => options.pcap = Some(value("a file name")?.into()),
+                "-o" | "--output" => options.output = Some(value("a file name")?.into()),
+                "--delay" => {
+                    let value = value("seconds")?;
+                    let secs: f64 = value
+                        .parse()
+                        .ok()
+                        .filter(|s: &f64| s.is_finite() && *s >= 0.0)
+                        .ok_or_else(|| format!("bad delay {}", value))?;
+                    options.delay = time::Duration::from_secs_f64(secs);
+                }
+                "listen" => {
+                    let value = value("a port")?;
+                    let port = value.parse().map_err(|_| format!("bad port {}", value))?;
+                    options.listen.push(port);
+                }
+                "connect" => {
+                    let value = value("an address and port")?;
+                    let addr = value
+                        .parse()
+                        .map_err(|_| format!("bad address {}, expected ip:port", value))?;
+                    options.connect.push(addr);
+                }
+                "ss" => {
+                    if let Some(extra) = args.next() {
+                        return Err(format!("ss takes no arguments, got {}", extra));
+                    }
+                    options.ss = true;
+                }
+                _ => return Err(format!("unknown argument {}", arg)),
+            }
+        }
+        if options.ss && !(options.listen.is_empty() && options.connect.is_empty()) {
+            return Err("ss doesn't go with listen or connect".to_string());
+        }
+        Ok(Some(options))
+    }
+
+    /// whether `ip` is on our IPv4 subnet, the host routes everything else elsewhere
+    fn on_link(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+        u32::from(ip) & mask == u32::from(self.ip) & mask
+    }
+}
+
+/// where a running instance on `interface` answers `ss`: $XDG_RUNTIME_DIR, which only its
+/// user can get into, or a directory of our own in the temp directory
+fn socket_path(interface: &str) -> PathBuf {
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => env::temp_dir().join(format!("tcp_proto-{}", unsafe { libc::getuid() })),
+    };
+    dir.join(format!("tcp_proto-{}.sock", interface))
+}
+
+/// Create `dir` for our user only, or make sure it is that already: anyone who can get in
+/// could read the connections of the stack, or stand in for it
+fn private_dir(dir: &Path) -> io::Result<()> {
+    match fs::DirBuilder::new().mode(0o700).create(dir) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+    let meta = fs::symlink_metadata(dir)?;
+    if !meta.is_dir() || meta.uid() != unsafe { libc::getuid() } || meta.mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} isn't a directory only we can use", dir.display()),
+        ));
+    }
+    Ok(())
+}
 
 fn main() -> io::Result<()> {
-    env_logger::init();
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return Ok(());
+        }
+        Err(e) => {
+            eprint!("tcp_proto: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    match &options.log {
+        Some(filter) => env_logger::Builder::new().parse_filters(filter).init(),
+        None => env_logger::init(),
+    }
+
+    if options.ss {
+        return ss(&options.interface);
+    }
+    let nic = nic::Interface::new(&options.interface)?;
+    match &options.pcap {
+        Some(path) => run(
+            tcp::with_device(pcap::Capture::create(nic, path)?, options.ip),
+            options,
+        ),
+        None => run(tcp::with_device(nic, options.ip), options),
+    }
+}
 
-    let mut tcp_instance = tcp::new(Ipv4Addr::new(192, 168, 0, 2))?.unwrap();
+fn run<D: NetDevice + 'static>(mut tcp_instance: tcp<D>, options: Options) -> io::Result<()> {
+    if let Some(ip6) = options.ip6 {
+        tcp_instance.set_ip6(ip6);
+    }
+    for port in &options.listen {
+        tcp_instance.control(control_message::Bind(*port))?;
+        log::info!("listening on port {}", port);
+    }
+    for addr in &options.connect {
+        if let IpAddr::V4(ip) = addr.ip() {
+            if !options.on_link(ip) {
+                log::warn!(
+                    "{} isn't on {}/{}, the host has to route it to {}",
+                    ip,
+                    options.ip,
+                    options.prefix,
+                    options.interface
+                );
+            }
+        }
+    }
 
-    tcp_instance.control(control_message::Bind(4000 as u16));
-    // tcp_instance.control(control_message::Bind(5000 as u16));
+    let mut output = match &options.output {
+        Some(path) => Some(
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?,
+        ),
+        None => None,
+    };
 
-    thread::sleep(time::Duration::from_secs(3));
+    let mut event_loop = EventLoop::new(tcp_instance)?;
+    let handle = event_loop.handle();
+    serve_snapshots(handle.clone(), socket_path(&options.interface))?;
+    if !options.connect.is_empty() {
+        // give run.sh time to bring the interface up, the loop keeps serving meanwhile
+        let (delay, connect) = (options.delay, options.connect);
+        thread::spawn(move || {
+            thread::sleep(delay);
+            handle.run(move |tcp_instance| {
+                for addr in connect {
+                    match tcp_instance.connect(addr.ip(), addr.port()) {
+                        Ok(quad) => log::info!("connecting to {} from port {}", addr, quad.dst.1),
+                        Err(e) => log::error!("connect to {} failed: {}", addr, e),
+                    }
+                }
+            })
+        });
+    }
+    loop {
+        event_loop.turn(None)?;
+        // nothing here writes back, so our side closes as soon as the peer's does
+        while let Some(event) = event_loop.stack.poll_event() {
+            match event {
+                Event::Readable(quad) => drain(&mut event_loop.stack, &quad, &mut output)?,
+                Event::PeerClosed(quad) => {
+                    drain(&mut event_loop.stack, &quad, &mut output)?;
+                    event_loop.stack.close(&quad)?;
+                }
+                _ => {}
+            }
+        }
+    }
+}
 
-    tcp_instance.control(control_message::Connect(
-        4000,
-        Ipv4Addr::new(192, 168, 0, 1),
-        8000 as u16,
-    ));
+/// Read what `quad` received, to `output` if there is one, so its window opens again
+fn drain<D: NetDevice>(
+    tcp_instance: &mut tcp<D>,
+    quad: &Quad,
+    output: &mut Option<fs::File>,
+) -> io::Result<()> {
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = match tcp_instance.read(quad, &mut buf) {
+            Ok(0) | Err(_) => return Ok(()),
+            Ok(n) => n,
+        };
+        if let Some(file) = output {
+            file.write_all(&buf[..n])?;
+        }
+    }
+}
 
-    while true {
-        let mut buf = [0u8; 1504];
-        let mut nbytes = tcp_instance.nic.recv(&mut buf[..])?;
-        tcp_instance.action(&buf, nbytes);
+/// Answer every connection to `path` with a snapshot of the stack
+fn serve_snapshots<D: NetDevice + 'static>(handle: Handle<D>, path: PathBuf) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        private_dir(dir)?;
     }
+    // left over from an instance that didn't exit cleanly
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|mut stream| {
+                let (tx, rx) = mpsc::channel();
+                handle.run(move |tcp_instance| {
+                    let _ = tx.send(tcp_instance.snapshot().to_string());
+                })?;
+                let text = rx.recv().map_err(|_| {
+                    io::Error::new(io::ErrorKind::BrokenPipe, "the event loop is gone")
+                })?;
+                stream.write_all(text.as_bytes())
+            });
+            if let Err(e) = result {
+                log::error!("snapshot failed: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Print the sockets of the instance running on `interface`
+fn ss(interface: &str) -> io::Result<()> {
+    let mut stream = UnixStream::connect(socket_path(interface)).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("no tcp_proto running on {}: {}", interface, e),
+        )
+    })?;
+    let mut text = String::new();
+    stream.read_to_string(&mut text)?;
+    print!("{}", text);
     Ok(())
 }
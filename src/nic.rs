@@ -2,57 +2,192 @@
 //!
 //! A library for modeling nics
 //! extend it to support high performance data plane: layer 2 function, dpdk, netmap, drivers for smart NICs
-//! right now we just support tun/tap
-use std::fmt::Error;
+//! a data plane plugs into the stack by implementing `NetDevice`, right now we ship tun/tap
+//! and an in-memory `pipe` for tests
 use std::io;
-use std::net::Ipv4Addr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::mpsc;
+use std::time::Instant;
 
-pub struct Interface {
-    pub nic: tun_tap::Iface,
-    pub ip: Ipv4Addr,
+/// MTU of a freshly created tun device
+pub const DEFAULT_MTU: u16 = 1500;
+
+/// What a device hands to `recv` and takes in `send`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Medium {
+    /// bare IP packets, like a tun device
+    Ip,
+    /// Ethernet frames, like a tap device
+    Ethernet,
+}
+
+impl Medium {
+    /// bytes of link layer header in front of every packet
+    pub fn header_len(self) -> usize {
+        match self {
+            Medium::Ip => 0,
+            Medium::Ethernet => 14,
+        }
+    }
 }
 
-pub struct recv_result {
-    data: [u8; 1504],
-    nbytes: usize,
+/// Properties of a device the stack has to take into account
+#[derive(Clone, Copy, Debug)]
+pub struct Capabilities {
+    pub medium: Medium,
+    /// the device drops packets with bad IP, TCP and UDP checksums itself, so the stack doesn't check
+    pub checksum_offload: bool,
+}
+
+/// A device the stack sends and receives packets through
+pub trait NetDevice {
+    /// Send one packet, returns the number of bytes sent
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Receive one packet into `buf`, returns its length
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// largest packet the device takes, without any link layer header
+    fn mtu(&self) -> u16;
+
+    fn capabilities(&self) -> Capabilities;
+
+    /// The stack's clock: it is about to handle something that happens at `now`.
+    /// Devices with timers of their own (ARP) go by it instead of the system clock.
+    fn set_now(&mut self, _now: Instant) {}
+
+    /// A file descriptor that polls readable when a packet can be received, for `event::EventLoop`.
+    /// Devices without one are polled every tick of the loop.
+    fn as_raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+/// A tun or tap device
+pub struct Interface {
+    pub nic: tun_tap::Iface,
+    /// largest IP packet the device takes, keep in sync with `ip link set dev tun0 mtu ...`
+    pub mtu: u16,
+    medium: Medium,
 }
 
 impl Interface {
-    pub fn new(ip: Ipv4Addr) -> io::Result<Self> {
-        let nic = tun_tap::Iface::without_packet_info("tun0", tun_tap::Mode::Tun)?;
-        return Ok(Interface { nic, ip: ip });
-    }
-
-    /// Recieve one packet
-    /// # Example
-    /// ```
-    /// use std::io;
-    /// mod nic;
-    /// fn main()-> io::Result<()> {
-    ///     let mut interface = nic::Interface::new()?;
-    ///     while true{
-    ///         let mut buf,nbytes = interface.recieve()?;
-    ///         println!("{} bytes:{:?}",nbytes,&buf[..]);
-    ///     }
-    ///     Ok(())
-    /// }
-    ///
-    /// ```
-    // pub fn recieve(&mut self) -> io::Result(recv_result){
-    //     let mut buf = [0u8; 1504];
-    //
-    //     let nbytes = self.nic.recv(&mut buf[..])?;
-    //
-    //     Ok(a)
-    // }
+    /// Open the tun device `name`, it has to be set up with `ip addr` and `ip link` beforehand (see run.sh)
+    pub fn new(name: &str) -> io::Result<Self> {
+        let nic = tun_tap::Iface::without_packet_info(name, tun_tap::Mode::Tun)?;
+        Ok(Interface {
+            nic,
+            mtu: DEFAULT_MTU,
+            medium: Medium::Ip,
+        })
+    }
 
+    /// Open the tap device `name`, it carries Ethernet frames so wrap it in `ethernet::Ethernet`
+    pub fn tap(name: &str) -> io::Result<Self> {
+        let nic = tun_tap::Iface::without_packet_info(name, tun_tap::Mode::Tap)?;
+        Ok(Interface {
+            nic,
+            mtu: DEFAULT_MTU,
+            medium: Medium::Ethernet,
+        })
+    }
+}
+
+impl NetDevice for Interface {
     /// a wrapper for tun_tap::Iface::send
-    pub fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.nic.send(buf)
     }
 
     /// a wrapper for tun_tap::Iface::recv
-    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.nic.recv(buf)
     }
+
+    fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            medium: self.medium,
+            checksum_offload: false,
+        }
+    }
+
+    fn as_raw_fd(&self) -> Option<RawFd> {
+        Some(self.nic.as_raw_fd())
+    }
+}
+
+/// One end of an in-memory device pair, what one end sends the other receives.
+/// `recv` doesn't block, it fails with `WouldBlock` when nothing is queued.
+pub struct Pipe {
+    tx: mpsc::Sender<Vec<u8>>,
+    rx: mpsc::Receiver<Vec<u8>>,
+    /// largest packet either end takes, not counting the link layer header
+    pub mtu: u16,
+    /// what the pair carries, set both ends to `Ethernet` to run `ethernet::Ethernet` on top
+    pub medium: Medium,
+}
+
+/// Two connected in-memory devices, to run stacks against each other without a tun device
+pub fn pipe() -> (Pipe, Pipe) {
+    let (a_tx, b_rx) = mpsc::channel();
+    let (b_tx, a_rx) = mpsc::channel();
+    (
+        Pipe {
+            tx: a_tx,
+            rx: a_rx,
+            mtu: DEFAULT_MTU,
+            medium: Medium::Ip,
+        },
+        Pipe {
+            tx: b_tx,
+            rx: b_rx,
+            mtu: DEFAULT_MTU,
+            medium: Medium::Ip,
+        },
+    )
+}
+
+impl NetDevice for Pipe {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.mtu as usize + self.medium.header_len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "packet larger than the MTU",
+            ));
+        }
+        self.tx
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    /// like a tun device, a packet that doesn't fit in `buf` is truncated
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.rx.try_recv() {
+            Ok(packet) => {
+                let n = std::cmp::min(buf.len(), packet.len());
+                buf[..n].copy_from_slice(&packet[..n]);
+                Ok(n)
+            }
+            Err(mpsc::TryRecvError::Empty) => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+            Err(mpsc::TryRecvError::Disconnected) => {
+                Err(io::Error::from(io::ErrorKind::BrokenPipe))
+            }
+        }
+    }
+
+    fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            medium: self.medium,
+            checksum_offload: false,
+        }
+    }
 }
@@ -0,0 +1,324 @@
+//! # Packet capture to pcap files
+//!
+//! `Capture` wraps a device and writes every packet that goes through it, both ways, to a
+//! pcap file that Wireshark and tcpdump read, so a trace comes with every run without a
+//! tshark on the side. Timestamps have nanosecond resolution and are taken when the packet
+//! passes the device.
+//!
+//! `Replay` goes the other way: it hands the packets of a capture to the stack as if they
+//! had arrived and keeps what the stack sends, so a trace turns into a regression test.
+use crate::nic::{Capabilities, Medium, NetDevice, DEFAULT_MTU};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// magic number of pcap files with nanosecond timestamps
+pub const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+/// magic number of pcap files with microsecond timestamps
+pub const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+/// Ethernet II frames
+pub const LINKTYPE_ETHERNET: u32 = 1;
+/// bare IPv4 and IPv6 packets
+pub const LINKTYPE_RAW: u32 = 101;
+/// we never cut packets short
+const SNAPLEN: u32 = 65535;
+/// the largest packet we read from a capture, what tcpdump takes at most
+const MAX_SNAPLEN: u32 = 262_144;
+
+/// The link type that describes what a device of `medium` carries
+pub fn link_type(medium: Medium) -> u32 {
+    match medium {
+        Medium::Ip => LINKTYPE_RAW,
+        Medium::Ethernet => LINKTYPE_ETHERNET,
+    }
+}
+
+/// The medium of a capture of `link_type` packets
+fn medium(link_type: u32) -> io::Result<Medium> {
+    match link_type {
+        LINKTYPE_RAW => Ok(Medium::Ip),
+        LINKTYPE_ETHERNET => Ok(Medium::Ethernet),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported pcap link type {}", link_type),
+        )),
+    }
+}
+
+/// A packet of a capture
+pub struct Record {
+    pub time: SystemTime,
+    pub data: Vec<u8>,
+}
+
+/// Reads packets from a pcap stream, written by `Writer` or by tcpdump and Wireshark
+pub struct Reader<R: Read> {
+    input: R,
+    pub link_type: u32,
+    /// the longest packet the file may hold
+    snaplen: u32,
+    /// the file was written on a machine of the other byte order
+    swapped: bool,
+    /// timestamps are in nanoseconds, not microseconds
+    nanos: bool,
+}
+
+impl<R: Read> Reader<R> {
+    /// Read the file header of the pcap stream `input`
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut header = [0u8; 24];
+        input.read_exact(&mut header)?;
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let (swapped, nanos) = match magic {
+            MAGIC_MICROS => (false, false),
+            MAGIC_NANOS => (false, true),
+            m if m.swap_bytes() == MAGIC_MICROS => (true, false),
+            m if m.swap_bytes() == MAGIC_NANOS => (true, true),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a pcap file (pcapng isn't supported)",
+                ))
+            }
+        };
+        let mut r = Reader {
+            input,
+            link_type: 0,
+            snaplen: 0,
+            swapped,
+            nanos,
+        };
+        r.snaplen = match r.u32_at(&header, 16) {
+            0 => MAX_SNAPLEN,
+            snaplen => std::cmp::min(snaplen, MAX_SNAPLEN),
+        };
+        r.link_type = r.u32_at(&header, 20);
+        Ok(r)
+    }
+
+    fn u32_at(&self, buf: &[u8], at: usize) -> u32 {
+        let v = u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]]);
+        if self.swapped {
+            v.swap_bytes()
+        } else {
+            v
+        }
+    }
+
+    /// The next packet, `None` at the end of the stream
+    pub fn next_packet(&mut self) -> io::Result<Option<Record>> {
+        let mut header = [0u8; 16];
+        match self.input.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let secs = self.u32_at(&header, 0) as u64;
+        let frac = self.u32_at(&header, 4);
+        let captured = self.u32_at(&header, 8);
+        if captured > self.snaplen {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "pcap record of {} bytes, longer than the snapshot length {}",
+                    captured, self.snaplen
+                ),
+            ));
+        }
+        let fraction = if self.nanos {
+            Duration::from_nanos(frac as u64)
+        } else {
+            Duration::from_micros(frac as u64)
+        };
+        let mut data = vec![0u8; captured as usize];
+        self.input.read_exact(&mut data)?;
+        Ok(Some(Record {
+            time: UNIX_EPOCH + Duration::from_secs(secs) + fraction,
+            data,
+        }))
+    }
+}
+
+/// Writes packets to a pcap stream
+pub struct Writer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Writer<W> {
+    /// Start a pcap stream of `link_type` packets on `out`
+    pub fn new(mut out: W, link_type: u32) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&MAGIC_NANOS.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes()); // version 2.4
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes()); // timestamps are UTC
+        header.extend_from_slice(&0u32.to_le_bytes()); // accuracy, always 0
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&link_type.to_le_bytes());
+        out.write_all(&header)?;
+        Ok(Writer { out })
+    }
+
+    /// Write `packet` as seen at `time`. The stream is flushed after every packet
+    /// so the trace is complete even if the program dies right after.
+    pub fn write_packet(&mut self, time: SystemTime, packet: &[u8]) -> io::Result<()> {
+        let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut record = Vec::with_capacity(16 + packet.len());
+        record.extend_from_slice(&(since.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&since.subsec_nanos().to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(packet);
+        self.out.write_all(&record)?;
+        self.out.flush()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.out
+    }
+}
+
+/// A device that records its traffic to a pcap file. A packet that can't be written to the
+/// capture is still sent or received, the capture just misses it.
+pub struct Capture<D: NetDevice, W: Write = BufWriter<File>> {
+    pub dev: D,
+    writer: Writer<W>,
+    /// the first error writing the capture
+    error: Option<io::Error>,
+}
+
+impl<D: NetDevice> Capture<D> {
+    /// Capture the traffic of `dev` to the file `path`, overwriting it
+    pub fn create<P: AsRef<Path>>(dev: D, path: P) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        Capture::new(dev, file)
+    }
+}
+
+impl<D: NetDevice, W: Write> Capture<D, W> {
+    /// Capture the traffic of `dev` to `out`
+    pub fn new(dev: D, out: W) -> io::Result<Self> {
+        let link_type = link_type(dev.capabilities().medium);
+        Ok(Capture {
+            dev,
+            writer: Writer::new(out, link_type)?,
+            error: None,
+        })
+    }
+
+    /// where the capture goes
+    pub fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
+
+    /// The first error writing the capture since the last call, packets are missing from it
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    fn record(&mut self, time: SystemTime, packet: &[u8]) {
+        if let Err(e) = self.writer.write_packet(time, packet) {
+            warn!("couldn't write a packet to the capture: {}", e);
+            self.error.get_or_insert(e);
+        }
+    }
+}
+
+impl<D: NetDevice, W: Write> NetDevice for Capture<D, W> {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let time = SystemTime::now();
+        let n = self.dev.send(buf)?;
+        self.record(time, &buf[..n]);
+        Ok(n)
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.dev.recv(buf)?;
+        self.record(SystemTime::now(), &buf[..n]);
+        Ok(n)
+    }
+
+    fn mtu(&self) -> u16 {
+        self.dev.mtu()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.dev.capabilities()
+    }
+    fn set_now(&mut self, now: Instant) {
+        self.dev.set_now(now)
+    }
+    fn as_raw_fd(&self) -> Option<RawFd> {
+        self.dev.as_raw_fd()
+    }
+}
+
+/// A device that receives the packets of a capture and keeps the packets sent through it.
+/// Once the capture is used up `recv` fails with `WouldBlock`, like an idle `nic::Pipe`.
+pub struct Replay {
+    inbound: VecDeque<Vec<u8>>,
+    /// what the stack sent, oldest first
+    pub sent: Vec<Vec<u8>>,
+    pub mtu: u16,
+    medium: Medium,
+}
+
+impl Replay {
+    /// Replay `packets`, which carry `medium`
+    pub fn new(packets: Vec<Vec<u8>>, medium: Medium) -> Self {
+        Replay {
+            inbound: packets.into(),
+            sent: Vec::new(),
+            mtu: DEFAULT_MTU,
+            medium,
+        }
+    }
+
+    /// Replay the pcap file `path`, all of its packets are taken as inbound
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut reader = Reader::new(BufReader::new(File::open(path)?))?;
+        let medium = medium(reader.link_type)?;
+        let mut packets = Vec::new();
+        while let Some(record) = reader.next_packet()? {
+            packets.push(record.data);
+        }
+        Ok(Replay::new(packets, medium))
+    }
+
+    /// packets not received yet
+    pub fn remaining(&self) -> usize {
+        self.inbound.len()
+    }
+}
+
+impl NetDevice for Replay {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sent.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let packet = self
+            .inbound
+            .pop_front()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))?;
+        let n = std::cmp::min(buf.len(), packet.len());
+        buf[..n].copy_from_slice(&packet[..n]);
+        Ok(n)
+    }
+
+    fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            medium: self.medium,
+            checksum_offload: false,
+        }
+    }
+}
@@ -0,0 +1,271 @@
+//! # IPv4 fragment reassembly (RFC 791, RFC 815)
+//!
+//! Fragments are collected per (source, destination, identification, protocol) until the
+//! datagram is complete. A fragment that overlaps data we already hold with different bytes
+//! drops the whole datagram, like Linux does since overlapping fragments are only ever used to
+//! sneak data past middleboxes (RFC 1858). Incomplete datagrams are dropped after `timeout`,
+//! and the oldest ones go first when the buffers would use more than `max_memory`.
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+/// how long an incomplete datagram is kept, like Linux ipfrag_time
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// bytes of fragments kept at most, like Linux ipfrag_high_thresh
+pub const DEFAULT_MAX_MEMORY: usize = 4 * 1024 * 1024;
+/// an IPv4 datagram can't be longer than its total length field allows
+const MAX_DATAGRAM_LEN: usize = 65535;
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+struct Key {
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    id: u16,
+    protocol: u8,
+}
+
+/// The fragments of one datagram received so far
+struct Buffer {
+    /// the header of the first fragment, once it has arrived
+    header: Option<etherparse::Ipv4Header>,
+    data: Vec<u8>,
+    /// the ranges of `data` we have, sorted and not touching each other
+    received: Vec<(usize, usize)>,
+    /// the payload length, known once the last fragment has arrived
+    len: Option<usize>,
+    created: Instant,
+}
+
+impl Buffer {
+    /// Add `payload` at `offset`, returns false if it conflicts with what we have
+    fn insert(&mut self, offset: usize, payload: &[u8]) -> bool {
+        let end = offset + payload.len();
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        for &(s, e) in &self.received {
+            let (from, to) = (std::cmp::max(s, offset), std::cmp::min(e, end));
+            if from < to && self.data[from..to] != payload[from - offset..to - offset] {
+                return false;
+            }
+        }
+        self.data[offset..end].copy_from_slice(payload);
+        // merge the new range with the ones it touches
+        let (mut start, mut stop) = (offset, end);
+        self.received.retain(|&(s, e)| {
+            if e < start || s > stop {
+                true
+            } else {
+                start = std::cmp::min(start, s);
+                stop = std::cmp::max(stop, e);
+                false
+            }
+        });
+        let at = self.received.partition_point(|&(s, _)| s < start);
+        self.received.insert(at, (start, stop));
+        true
+    }
+
+    fn complete(&self) -> bool {
+        match (self.len, &self.header) {
+            (Some(len), Some(_)) => self.received == [(0, len)],
+            _ => false,
+        }
+    }
+}
+
+/// What became of a fragment
+#[derive(Debug, PartialEq, Eq)]
+pub enum Input {
+    /// it was the last missing one, here is the whole datagram
+    Complete(Vec<u8>),
+    /// kept until the rest arrives
+    Pending,
+    /// too long, or not a multiple of 8 bytes and not the last, dropped on its own
+    Malformed,
+    /// it doesn't agree with what we have, the whole datagram is dropped
+    Overlap,
+}
+
+/// Why an incomplete datagram was given up on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dropped {
+    /// its fragments didn't all arrive within `timeout`
+    TimedOut,
+    /// it was the oldest when `max_memory` ran out
+    Evicted,
+}
+
+/// Reassembly buffers for all datagrams in progress
+pub struct Reassembler {
+    buffers: HashMap<Key, Buffer>,
+    /// bytes held in `buffers`
+    memory: usize,
+    /// datagrams given up on since `take_dropped` was last called, with the data received of them
+    dropped: Vec<(Dropped, Vec<u8>)>,
+    pub max_memory: usize,
+    pub timeout: Duration,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Reassembler {
+            buffers: Default::default(),
+            memory: 0,
+            dropped: Vec::new(),
+            max_memory: DEFAULT_MAX_MEMORY,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+/// Whether `packet` is an IPv4 fragment that has to go through `Reassembler::input`
+pub fn is_fragment(packet: &[u8]) -> bool {
+    match etherparse::Ipv4HeaderSlice::from_slice(packet) {
+        Ok(iph) => iph.more_fragments() || iph.fragments_offset() != 0,
+        Err(_) => false,
+    }
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Number of datagrams being reassembled
+    pub fn pending(&self) -> usize {
+        self.buffers.len()
+    }
+
+    /// The datagrams timed out or evicted since the last call, with the data received of them
+    pub fn take_dropped(&mut self) -> Vec<(Dropped, Vec<u8>)> {
+        mem::take(&mut self.dropped)
+    }
+
+    /// Take the fragment `packet` that arrived at `now`, gives the whole datagram once its last
+    /// missing fragment arrived
+    pub fn input(&mut self, now: Instant, packet: &[u8]) -> Input {
+        self.expire(now);
+        let iph = match etherparse::Ipv4HeaderSlice::from_slice(packet) {
+            Ok(iph) => iph,
+            Err(_) => return Input::Malformed,
+        };
+        let end = std::cmp::min(packet.len(), iph.total_len() as usize);
+        let payload = match packet.get(iph.slice().len()..end) {
+            Some(payload) => payload,
+            None => return Input::Malformed,
+        };
+        let offset = iph.fragments_offset() as usize * 8;
+        let key = Key {
+            src: iph.source_addr(),
+            dst: iph.destination_addr(),
+            id: iph.identification(),
+            protocol: iph.protocol(),
+        };
+        if iph.slice().len() + offset + payload.len() > MAX_DATAGRAM_LEN
+            // all but the last fragment carry a multiple of 8 bytes
+            || (iph.more_fragments() && payload.len() % 8 != 0)
+        {
+            debug!("dropping malformed fragment of {:?}", key);
+            return Input::Malformed;
+        }
+
+        let b = self.buffers.entry(key).or_insert_with(|| Buffer {
+            header: None,
+            data: Vec::new(),
+            received: Vec::new(),
+            len: None,
+            created: now,
+        });
+        let held = b.data.len();
+        // nothing may follow the last fragment
+        let mut ok = b.len.is_none_or(|l| offset + payload.len() <= l) && b.insert(offset, payload);
+        if !iph.more_fragments() {
+            let len = offset + payload.len();
+            // two different ends, or data beyond the end
+            ok &= b.len.is_none_or(|l| l == len) && b.data.len() == len;
+            b.len = Some(len);
+        }
+        if offset == 0 {
+            b.header = Some(iph.to_header());
+        }
+        self.memory = self.memory + b.data.len() - held;
+        if !ok {
+            debug!("dropping datagram {:?} with overlapping fragments", key);
+            self.remove(&key);
+            return Input::Overlap;
+        }
+        if b.complete() {
+            let b = self.remove(&key).expect("the buffer just used");
+            return match reassembled(b) {
+                Ok(packet) => Input::Complete(packet),
+                Err(_) => Input::Malformed,
+            };
+        }
+        self.evict();
+        Input::Pending
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Buffer> {
+        let b = self.buffers.remove(key)?;
+        self.memory -= b.data.len();
+        Some(b)
+    }
+
+    /// When the oldest datagram times out, if there is one
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.buffers
+            .values()
+            .map(|b| b.created + self.timeout)
+            .min()
+    }
+
+    /// Drop datagrams that took too long by `now`, `take_dropped` has them
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        let expired: Vec<Key> = self
+            .buffers
+            .iter()
+            .filter(|(_, b)| now.saturating_duration_since(b.created) >= timeout)
+            .map(|(k, _)| *k)
+            .collect();
+        for key in expired {
+            debug!("reassembly of {:?} timed out", key);
+            if let Some(b) = self.remove(&key) {
+                self.dropped.push((Dropped::TimedOut, b.data));
+            }
+        }
+    }
+
+    /// Drop the oldest datagrams until we are within `max_memory`
+    fn evict(&mut self) {
+        while self.memory > self.max_memory {
+            let oldest = match self.buffers.iter().min_by_key(|(_, b)| b.created) {
+                Some((k, _)) => *k,
+                None => return,
+            };
+            debug!("out of reassembly memory, dropping {:?}", oldest);
+            if let Some(b) = self.remove(&oldest) {
+                self.dropped.push((Dropped::Evicted, b.data));
+            }
+        }
+    }
+}
+
+/// The datagram of a complete buffer: the first header, unfragmented, and all the data
+fn reassembled(b: Buffer) -> io::Result<Vec<u8>> {
+    let mut header = b.header.expect("complete without the first fragment");
+    header.more_fragments = false;
+    header.fragments_offset = 0;
+    header
+        .set_payload_len(b.data.len())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e)))?;
+    let mut packet = Vec::with_capacity(header.header_len() + b.data.len());
+    header
+        .write(&mut packet)
+        .map_err(|e| io::Error::other(format!("{:?}", e)))?;
+    packet.extend_from_slice(&b.data);
+    Ok(packet)
+}
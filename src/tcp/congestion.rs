@@ -0,0 +1,58 @@
+//! Congestion control (RFC 5681)
+//!
+//! Slow start and congestion avoidance on a byte-counted window. Losses and ECN echoes
+//! are both reported through `on_congestion`, retransmission timeouts through `on_timeout`.
+
+pub struct Reno {
+    /// congestion window in bytes
+    pub cwnd: usize,
+    /// slow start threshold in bytes
+    pub ssthresh: usize,
+    mss: usize,
+}
+
+impl Reno {
+    pub fn new(mss: u16) -> Self {
+        let mss = mss as usize;
+        Reno {
+            cwnd: initial_window(mss),
+            ssthresh: usize::MAX,
+            mss,
+        }
+    }
+
+    /// `acked` bytes of new data were acknowledged
+    pub fn on_ack(&mut self, acked: usize) {
+        if self.cwnd < self.ssthresh {
+            // slow start (RFC 5681 S3.1 eq 2)
+            self.cwnd += std::cmp::min(acked, self.mss);
+        } else {
+            // congestion avoidance (RFC 5681 S3.1 eq 3)
+            self.cwnd += std::cmp::max(self.mss * self.mss / self.cwnd, 1);
+        }
+    }
+
+    /// A loss or an ECN-Echo with `flight_size` bytes outstanding (RFC 5681 S3.1 eq 4, RFC 3168 S6.1.2)
+    pub fn on_congestion(&mut self, flight_size: usize) {
+        self.ssthresh = std::cmp::max(flight_size / 2, 2 * self.mss);
+        self.cwnd = self.ssthresh;
+    }
+
+    /// The retransmission timer expired with `flight_size` bytes outstanding, start over
+    /// from the loss window (RFC 5681 S3.1 eq 4, S3.1 LW)
+    pub fn on_timeout(&mut self, flight_size: usize) {
+        self.ssthresh = std::cmp::max(flight_size / 2, 2 * self.mss);
+        self.cwnd = self.mss;
+    }
+}
+
+/// IW (RFC 5681 S3.1)
+fn initial_window(mss: usize) -> usize {
+    if mss > 2190 {
+        2 * mss
+    } else if mss > 1095 {
+        3 * mss
+    } else {
+        4 * mss
+    }
+}
@@ -0,0 +1,62 @@
+//! TCP Fast Open (RFC 7413)
+//!
+//! A listener hands out a cookie that is a keyed hash of the client's address. A client that
+//! presents it again may put data in its SYN, which is delivered before the handshake completes.
+//! Clients keep the cookies they were given, keyed by server address.
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+
+/// length of the cookies we hand out
+pub const COOKIE_LEN: usize = 8;
+/// valid cookie lengths (RFC 7413 S4.1.1)
+const MIN_COOKIE_LEN: usize = 4;
+const MAX_COOKIE_LEN: usize = 16;
+
+pub struct FastOpen {
+    /// whether listeners issue cookies and accept data in SYNs
+    pub enabled: bool,
+    /// the random SipHash keys of a `RandomState` serve as the server key
+    secret: RandomState,
+    /// cookies learned from servers
+    cache: HashMap<IpAddr, Vec<u8>>,
+}
+
+impl Default for FastOpen {
+    fn default() -> Self {
+        FastOpen::new()
+    }
+}
+
+impl FastOpen {
+    pub fn new() -> Self {
+        FastOpen {
+            enabled: false,
+            secret: RandomState::new(),
+            cache: Default::default(),
+        }
+    }
+
+    /// The cookie for `client`
+    pub fn cookie(&self, client: IpAddr) -> [u8; COOKIE_LEN] {
+        self.secret.hash_one(client).to_be_bytes()
+    }
+
+    /// Whether `cookie` is the one we issued to `client`
+    pub fn validate(&self, client: IpAddr, cookie: &[u8]) -> bool {
+        cookie == &self.cookie(client)[..]
+    }
+
+    /// The cookie we were given by `server`, if any
+    pub fn cached(&self, server: IpAddr) -> Option<&[u8]> {
+        self.cache.get(&server).map(|c| &c[..])
+    }
+
+    /// Keep the cookie `server` sent in its SYN-ACK
+    pub fn remember(&mut self, server: IpAddr, cookie: &[u8]) {
+        if (MIN_COOKIE_LEN..=MAX_COOKIE_LEN).contains(&cookie.len()) {
+            self.cache.insert(server, cookie.to_vec());
+        }
+    }
+}
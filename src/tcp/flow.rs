@@ -1,25 +1,26 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::VecDeque;
 use std::io;
-use std::net::Ipv4Addr;
-
-// for file operations
-use std::env;
-use std::fs::File;
 use std::io::Write;
+use std::net::IpAddr;
 
 // for statistics
-use crate::nic;
-use std::alloc::dealloc;
+use crate::icmp;
+use crate::ip;
+use crate::tcp::congestion;
+use crate::tcp::options;
+use crate::tcp::output::{DropReason, Event, Output};
+use crate::tcp::pmtu;
+use crate::tcp::rtt;
 use std::time::{Duration, Instant};
 
 /// A Quad is a 4 tuple
 #[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
 pub struct Quad {
-    pub src: (Ipv4Addr, u16),
-    pub dst: (Ipv4Addr, u16),
+    pub src: (IpAddr, u16),
+    pub dst: (IpAddr, u16),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum State {
     //Listen,
     SynRcvd,
@@ -27,6 +28,8 @@ pub enum State {
     Estab,
     FinWait1,
     FinWait2,
+    /// both sides sent a FIN at the same time, ours isn't acknowledged yet
+    Closing,
     TimeWait,
     CloseWait,
     LastAck,
@@ -35,7 +38,7 @@ pub enum State {
 
 /// State of the Send Sequence Space (RFC 793 S3.2 F4)
 ///
-/// ```
+/// ```text
 ///            1         2          3          4
 ///       ----------|----------|----------|----------
 ///              SND.UNA    SND.NXT    SND.UNA
@@ -46,6 +49,7 @@ pub enum State {
 /// 3 - sequence numbers allowed for new data transmission
 /// 4 - future sequence numbers which are not yet allowed
 /// ```
+#[allow(dead_code)]
 pub struct SendSequenceSpace {
     /// send unacknowledged
     una: u32,
@@ -53,19 +57,21 @@ pub struct SendSequenceSpace {
     nxt: u32,
     /// send window
     wnd: u16,
-    /// send urgent pointer
-    up: bool,
+    /// send urgent pointer, the sequence number following the last urgent byte (RFC 6093)
+    up: u32,
     /// segment sequence number used for last window update
-    wl1: usize,
+    wl1: u32,
     /// segment acknowledgment number used for last window update
-    wl2: usize,
+    wl2: u32,
     /// initial send sequence number
     iss: u32,
+    /// the highest sequence number sent so far, SND.NXT goes back below it to retransmit
+    max: u32,
 }
 
 /// State of the Receive Sequence Space (RFC 793 S3.2 F5)
 ///
-/// ```
+/// ```text
 ///                1          2          3
 ///            ----------|----------|----------
 ///                   RCV.NXT    RCV.NXT
@@ -75,20 +81,153 @@ pub struct SendSequenceSpace {
 /// 2 - sequence numbers allowed for new reception
 /// 3 - future sequence numbers which are not yet allowed
 /// ```
+#[allow(dead_code)]
 pub struct RecvSequenceSpace {
     /// receive next
     nxt: u32,
     /// receive window
     wnd: u16,
-    /// receive urgent pointer
-    up: bool,
+    /// receive urgent pointer, the sequence number following the last urgent byte (RFC 6093)
+    up: u32,
     /// initial receive sequence number
     irs: u32,
 }
 
+/// ECN-Capable Transport codepoint ECT(0) (RFC 3168 S5)
+pub const ECN_ECT0: u8 = 0b10;
+/// Congestion Experienced codepoint (RFC 3168 S5)
+pub const ECN_CE: u8 = 0b11;
+
+/// ECN state of a flow (RFC 3168 S6.1)
+#[derive(Default)]
+pub struct Ecn {
+    /// both ends agreed on ECN in the handshake
+    pub enabled: bool,
+    /// CE was received, ECE goes out on every segment until the peer sends CWR
+    echo: bool,
+    /// the window was reduced for an ECE, CWR goes out on the next new data segment
+    cwr: bool,
+    /// SND.NXT at the last reduction, ECEs for data up to here don't reduce again
+    recover: u32,
+}
+
+/// Deadlines of the timers of a flow, `None` when a timer isn't running
+#[derive(Default)]
+pub struct Timers {
+    pub retransmit: Option<Instant>,
+    pub delayed_ack: Option<Instant>,
+    pub time_wait: Option<Instant>,
+    /// the deadline the stack's timer wheel holds for this flow
+    pub(crate) scheduled: Option<Instant>,
+}
+
+/// The timers of a flow
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timer {
+    Retransmit,
+    DelayedAck,
+    TimeWait,
+}
+
+impl Timers {
+    /// the deadline of the next timer to expire
+    pub fn next(&self) -> Option<Instant> {
+        self.pending().map(|(_, at)| at)
+    }
+
+    /// the next timer to expire and its deadline
+    pub fn pending(&self) -> Option<(Timer, Instant)> {
+        [
+            (Timer::Retransmit, self.retransmit),
+            (Timer::DelayedAck, self.delayed_ack),
+            (Timer::TimeWait, self.time_wait),
+        ]
+        .iter()
+        .filter_map(|(timer, at)| at.map(|at| (*timer, at)))
+        .min_by_key(|(_, at)| *at)
+    }
+}
+
+/// What a flow has counted since it was created
+#[derive(Clone, Debug)]
 pub struct Statistics {
+    /// when the flow was created
     pub timer: Instant,
+    /// bytes of data received in order, SYN data included
     pub size: u64,
+    /// bytes of data sent, retransmissions included
+    pub bytes_sent: u64,
+    /// bytes of data sent again
+    pub bytes_retrans: u64,
+    /// bytes of data the peer acknowledged
+    pub bytes_acked: u64,
+    /// segments sent, pure ACKs and retransmissions included
+    pub segs_out: u64,
+    /// segments received for this flow, acceptable or not
+    pub segs_in: u64,
+    /// segments sent again, by the retransmission timer or to recover from a lost probe
+    pub retrans_segs: u64,
+    /// segments of data that arrived ahead of RCV.NXT
+    pub out_of_order: u64,
+}
+
+impl Statistics {
+    fn new(now: Instant) -> Self {
+        Statistics {
+            timer: now,
+            size: 0,
+            bytes_sent: 0,
+            bytes_retrans: 0,
+            bytes_acked: 0,
+            segs_out: 0,
+            segs_in: 0,
+            retrans_segs: 0,
+            out_of_order: 0,
+        }
+    }
+}
+
+/// Everything there is to know about a flow at one moment, like Linux's TCP_INFO
+#[derive(Clone, Debug)]
+pub struct Info {
+    pub quad: Quad,
+    pub state: State,
+    /// how long the flow exists
+    pub age: Duration,
+    /// smoothed RTT, unknown until the first measurement
+    pub rtt: Option<Duration>,
+    pub rttvar: Duration,
+    /// the retransmission timeout, backed off if the timer expired
+    pub rto: Duration,
+    /// expiries of the retransmission timer in a row
+    pub retransmits: u32,
+    /// the next timer to expire and how long until it does
+    pub timer: Option<(Timer, Duration)>,
+    /// the peer's MSS and the largest segment we send
+    pub mss: u16,
+    pub snd_mss: u16,
+    pub pmtu: u16,
+    pub cwnd: usize,
+    pub ssthresh: usize,
+    /// the window the peer offers
+    pub snd_wnd: u16,
+    /// the window we offer
+    pub rcv_wnd: u16,
+    /// bytes sent and not acknowledged yet
+    pub unacked: u32,
+    /// bytes queued for sending, sent or not
+    pub send_queue: usize,
+    /// bytes received and not read yet
+    pub recv_queue: usize,
+    pub ecn: bool,
+    pub bytes_sent: u64,
+    pub bytes_acked: u64,
+    pub bytes_received: u64,
+    pub bytes_retrans: u64,
+    pub segs_out: u64,
+    pub segs_in: u64,
+    pub retrans_segs: u64,
+    pub out_of_order: u64,
 }
 
 pub struct flow {
@@ -96,216 +235,988 @@ pub struct flow {
     pub state: State,
     pub send: SendSequenceSpace,
     pub recv: RecvSequenceSpace,
+    /// maximum segment size announced by the peer (RFC 879 default when absent)
+    pub mss: u16,
+    pub cc: congestion::Reno,
+    pub ecn: Ecn,
+    /// path MTU discovery state
+    pub pmtu: pmtu::Pmtu,
+    /// duplicate ACKs in a row, used to tell a lost PMTU probe
+    dupacks: u32,
+    /// the last error of this flow, an ICMP error or a timeout, soft unless it closed the flow
+    pub error: Option<io::Error>,
+    pub timers: Timers,
+    pub rtt: rtt::Rtt,
+    /// the end of the segment being timed for an RTT measurement and when it was sent
+    rtt_timing: Option<(u32, Instant)>,
+    /// expiries of the retransmission timer in a row
+    retries: u32,
+    /// segments received and not acknowledged yet
+    ack_pending: u32,
+    /// the application closed its side, a FIN goes out once the queued data is sent
+    fin_pending: bool,
+    /// handed to the application by `tcp::accept`, or opened by it
+    pub(crate) accepted: bool,
+    /// counted among the stack's half-open flows, until it leaves SynRcvd
+    pub(crate) embryonic: bool,
 
-    ip: etherparse::Ipv4Header,
+    ip: ip::Header,
     tcp: etherparse::TcpHeader,
 
     pub stats: Statistics,
     pub(crate) incoming: VecDeque<u8>,
     pub(crate) unacked: VecDeque<u8>,
+    /// segments that arrived ahead of RCV.NXT with their sequence numbers, waiting for the gap
+    ahead: Vec<(u32, Vec<u8>)>,
 }
 
 impl flow {
-    pub fn passive_three_way_handshake<'a>(
-        nic: &mut nic::Interface, // why mutable?
-        iph: etherparse::Ipv4HeaderSlice<'a>,
-        tcph: etherparse::TcpHeaderSlice<'a>,
-    ) -> io::Result<Option<Self>> {
-        let buf = [0u8; 1500];
-        if !tcph.syn() {
-            // only expected SYN packet
-            return Ok(None);
-        }
-
-        let iss = 0;
+    /// Build the state of a flow that has received a SYN and answers it with `iss`.
+    /// Nothing is sent; `passive_three_way_handshake` and the SYN cookie path share this.
+    fn syn_received(
+        iph: &ip::Packet,
+        tcph: &etherparse::TcpHeaderSlice,
+        irs: u32,
+        iss: u32,
+        mss: u16,
+        out: &Output,
+    ) -> Self {
         let wnd = 64240; // same as the window size of cat
 
-        let mut f = flow {
+        flow {
             quad: Quad {
-                src: (iph.source_addr(), tcph.source_port()),
-                dst: (iph.destination_addr(), tcph.destination_port()),
+                src: (iph.src, tcph.source_port()),
+                dst: (iph.dst, tcph.destination_port()),
             },
 
             state: State::SynRcvd,
             send: SendSequenceSpace {
                 una: iss,
                 nxt: iss,
-                wnd: wnd,
-                up: false,
-                wl1: 0,
+                wnd: tcph.window_size(),
+                up: iss,
+                wl1: irs,
                 wl2: 0,
-                iss: iss,
+                iss,
+                max: iss,
             },
             recv: RecvSequenceSpace {
-                irs: tcph.sequence_number(),
-                nxt: tcph.sequence_number() + 1,
-                wnd: tcph.window_size(),
-                up: false,
+                irs,
+                nxt: irs.wrapping_add(1),
+                wnd,
+                up: irs,
+            },
+            mss,
+            cc: congestion::Reno::new(mss),
+            ecn: Ecn {
+                recover: iss,
+                ..Default::default()
             },
+            pmtu: pmtu::Pmtu::new(out.mtu, iph.src.is_ipv6()),
+            dupacks: 0,
+            error: None,
+            timers: Default::default(),
+            rtt: rtt::Rtt::new(),
+            rtt_timing: None,
+            retries: 0,
+            ack_pending: 0,
+            fin_pending: false,
+            accepted: false,
+            embryonic: false,
             incoming: Default::default(),
             unacked: Default::default(),
+            ahead: Vec::new(),
             tcp: etherparse::TcpHeader::new(tcph.destination_port(), tcph.source_port(), iss, wnd),
-            ip: etherparse::Ipv4Header::new(
-                0,
-                64,
-                etherparse::IpTrafficClass::Tcp,
-                [
-                    iph.destination()[0],
-                    iph.destination()[1],
-                    iph.destination()[2],
-                    iph.destination()[3],
-                ],
-                [
-                    iph.source()[0],
-                    iph.source()[1],
-                    iph.source()[2],
-                    iph.source()[3],
-                ],
-            ),
-            stats: Statistics {
-                timer: Instant::now(),
-                size: 0,
-            },
-        };
+            ip: ip::Header::new(iph.dst, iph.src, ip::PROTOCOL_TCP),
+            stats: Statistics::new(out.now),
+        }
+    }
+
+    /// Answer a SYN with a SYN-ACK using `iss`.
+    /// `data` is SYN data accepted under a valid Fast Open cookie, `fastopen` a cookie to hand out.
+    /// With `ecn` an ECN-setup SYN is answered with an ECN-setup SYN-ACK.
+    pub fn passive_three_way_handshake(
+        out: &mut Output,
+        iph: &ip::Packet,
+        tcph: etherparse::TcpHeaderSlice,
+        iss: u32,
+        data: &[u8],
+        fastopen: Option<&[u8]>,
+        ecn: bool,
+    ) -> io::Result<Option<Self>> {
+        if !tcph.syn() {
+            // only expected SYN packet
+            return Ok(None);
+        }
+
+        let mut f = flow::syn_received(
+            iph,
+            &tcph,
+            tcph.sequence_number(),
+            iss,
+            mss_option(&tcph),
+            out,
+        );
+        f.stats.segs_in = 1;
+        // the application sees SYN data before the handshake completes (RFC 7413 S4.2.2)
+        if !data.is_empty() {
+            f.incoming.extend(data);
+            f.stats.size += data.len() as u64;
+            f.recv.nxt = f.recv.nxt.wrapping_add(data.len() as u32);
+        }
+        let mut opts = options::mss(f.advertised_mss(out));
+        if let Some(cookie) = fastopen {
+            opts.extend(options::fastopen(cookie));
+        }
+        f.set_options(&opts)?;
+        // ECN-setup SYN has ECE and CWR, the ECN-setup SYN-ACK only ECE (RFC 3168 S6.1.1)
+        if ecn && tcph.ece() && tcph.cwr() {
+            f.ecn.enabled = true;
+            f.tcp.ece = true;
+        }
         // need to start establishing a connection
         f.tcp.syn = true;
         f.tcp.ack = true;
-        f.write(nic, f.send.nxt, 0)?;
+        f.write(out, f.send.nxt, 0)?;
+        f.set_options(&[])?;
         Ok(Some(f))
     }
 
-    pub fn active_three_way_handshake<'a>(
-        nic: &mut nic::Interface, // why mutable?
+    /// Rebuild a flow in SynRcvd from the final ACK of a handshake answered with a SYN cookie.
+    /// `iss` is the cookie we sent (ACK - 1), `mss` the value decoded from it, `mtu` that of the interface.
+    /// The caller feeds the ACK to `SynRcvd_handler` to complete the handshake.
+    pub fn from_syn_cookie(
+        iph: &ip::Packet,
+        tcph: &etherparse::TcpHeaderSlice,
+        iss: u32,
+        mss: u16,
+        out: &Output,
+    ) -> Self {
+        let mut f = flow::syn_received(
+            iph,
+            tcph,
+            tcph.sequence_number().wrapping_sub(1),
+            iss,
+            mss,
+            out,
+        );
+        // the SYN-ACK has already gone out with the cookie as its sequence number
+        f.send.nxt = iss.wrapping_add(1);
+        f.send.max = f.send.nxt;
+        f.tcp.ack = true;
+        f
+    }
+
+    /// Send a SYN for `quad` with `data` queued for the connection.
+    /// With `fastopen` the SYN carries a Fast Open option: an empty cookie requests one, while a
+    /// cookie we were given lets the first segment of `data` ride on the SYN (RFC 7413).
+    /// With `ecn` the SYN asks for ECN.
+    pub fn active_three_way_handshake(
+        out: &mut Output,
         quad: &Quad,
+        data: &[u8],
+        fastopen: Option<&[u8]>,
+        ecn: bool,
     ) -> io::Result<Option<Self>> {
         // debug!("active_three_way_handshake called");
-        let buf = [0u8; 1500];
-
         let iss = 0;
         let wnd = 64240; // same as the window size of cat
 
         let mut f = flow {
-            quad: quad.clone(),
+            quad: *quad,
             state: State::Closed,
             send: SendSequenceSpace {
                 una: iss,
                 nxt: iss,
-                wnd: wnd,
-                up: false,
+                wnd: 0,
+                up: iss,
                 wl1: 0,
                 wl2: 0,
-                iss: iss,
+                iss,
+                max: iss,
             },
             recv: RecvSequenceSpace {
                 irs: iss,
                 nxt: iss + 1,
-                wnd: 0,
-                up: false,
+                wnd,
+                up: iss,
             },
+            mss: DEFAULT_MSS,
+            cc: congestion::Reno::new(DEFAULT_MSS),
+            ecn: Ecn {
+                recover: iss,
+                ..Default::default()
+            },
+            pmtu: pmtu::Pmtu::new(out.mtu, quad.src.0.is_ipv6()),
+            dupacks: 0,
+            error: None,
+            timers: Default::default(),
+            rtt: rtt::Rtt::new(),
+            rtt_timing: None,
+            retries: 0,
+            ack_pending: 0,
+            fin_pending: false,
+            accepted: true,
+            embryonic: false,
             incoming: Default::default(),
             unacked: Default::default(),
+            ahead: Vec::new(),
             tcp: etherparse::TcpHeader::new(quad.dst.1, quad.src.1, iss, wnd),
-            ip: etherparse::Ipv4Header::new(
-                0,
-                64,
-                etherparse::IpTrafficClass::Tcp,
-                quad.dst.0.octets(),
-                quad.src.0.octets(),
-            ),
-            stats: Statistics {
-                timer: Instant::now(),
-                size: 0,
-            },
+            ip: ip::Header::new(quad.dst.0, quad.src.0, ip::PROTOCOL_TCP),
+            stats: Statistics::new(out.now),
         };
 
+        f.unacked.extend(data);
+        let mut limit = 0;
+        let mut opts = options::mss(f.advertised_mss(out));
+        if let Some(cookie) = fastopen {
+            opts.extend(options::fastopen(cookie));
+            if !cookie.is_empty() {
+                limit = std::cmp::min(data.len(), DEFAULT_MSS as usize);
+            }
+        }
+        f.set_options(&opts)?;
+
+        // ECN-setup SYN, ECN is only used if the SYN-ACK agrees
+        if ecn {
+            f.ecn.enabled = true;
+            f.tcp.ece = true;
+            f.tcp.cwr = true;
+        }
+
         // need to start establishing a connection
         f.tcp.syn = true;
-        f.write(nic, f.send.nxt, 0)?;
+        f.write(out, f.send.nxt, limit)?;
+        f.set_options(&[])?;
         f.state = State::SynSent;
         // debug!("here");
         Ok(Some(f))
     }
 
-    pub fn write(
-        &mut self,
-        nic: &mut nic::Interface,
-        seq: u32,
-        mut limit: usize,
-    ) -> io::Result<usize> {
-        let mut buf = [0u8; 1500];
+    /// Send one segment starting at `seq` carrying at most `limit` bytes of `unacked`,
+    /// together with whatever SYN/FIN flags are set on `self.tcp`.
+    /// Returns the number of payload bytes sent.
+    pub fn write(&mut self, out: &mut Output, seq: u32, limit: usize) -> io::Result<usize> {
+        let mut buf = vec![0u8; out.mtu as usize];
         self.tcp.sequence_number = seq;
         self.tcp.acknowledgment_number = self.recv.nxt;
+
+        // find the payload in unacked, the SYN takes up the first sequence number before any data
+        let mut offset = seq.wrapping_sub(self.send.una) as usize;
+        if self.send.una == self.send.iss {
+            offset = offset.saturating_sub(1);
+        }
+        let offset = std::cmp::min(offset, self.unacked.len());
+        let (mut h, mut t) = self.unacked.as_slices();
+        if h.len() >= offset {
+            h = &h[offset..];
+        } else {
+            let skipped = h.len();
+            h = &[];
+            t = &t[(offset - skipped)..];
+        }
+        let max_data = std::cmp::min(limit, h.len() + t.len());
+
+        // the urgent pointer is an offset from the sequence number of this segment
+        if wrapping_lt(seq, self.send.up) {
+            self.tcp.urg = true;
+            self.tcp.urgent_pointer =
+                std::cmp::min(self.send.up.wrapping_sub(seq), u16::MAX as u32) as u16;
+        } else {
+            self.tcp.urg = false;
+            self.tcp.urgent_pointer = 0;
+        }
+
+        // ECN: SYNs carry the negotiation flags, everything else the state of the flow.
+        // Only new data, from SND.MAX on, is ECN-capable: retransmissions and pure ACKs are not
+        // (RFC 3168 S6.1.5). SND.NXT goes back to SND.UNA to retransmit, so it can't tell.
+        let new_data = max_data > 0 && !self.tcp.syn && !wrapping_lt(seq, self.send.max);
+        if !self.tcp.syn {
+            self.tcp.ece = self.ecn.echo;
+            self.tcp.cwr = self.ecn.cwr && new_data;
+            if self.tcp.cwr {
+                self.ecn.cwr = false;
+            }
+        }
+        self.ip.set_ecn(if self.ecn.enabled && new_data {
+            ECN_ECT0
+        } else {
+            0
+        });
+
         let size = std::cmp::min(
             buf.len(),
-            self.tcp.header_len() as usize + self.ip.header_len() as usize,
+            self.tcp.header_len() as usize + self.ip.header_len() + max_data,
         );
 
-        self.ip
-            .set_payload_len(size - self.ip.header_len() as usize);
+        self.ip.set_payload_len(size - self.ip.header_len())?;
 
         // write out the headers and the payload
-        use std::io::Write;
         let buf_len = buf.len();
         let mut unwritten = &mut buf[..];
 
-        self.ip.write(&mut unwritten);
+        self.ip.write(&mut unwritten)?;
         let ip_header_ends_at = buf_len - unwritten.len();
 
         // postpone writing the tcp header because we need the payload as one contiguous slice to calculate the tcp checksum
         unwritten = &mut unwritten[self.tcp.header_len() as usize..];
         let tcp_header_ends_at = buf_len - unwritten.len();
 
-        // TODO: write out the payload
+        let payload_bytes = {
+            let mut written = 0;
+            let mut limit = size - tcp_header_ends_at;
+            let p1l = std::cmp::min(limit, h.len());
+            written += unwritten.write(&h[..p1l])?;
+            limit -= written;
+            let p2l = std::cmp::min(limit, t.len());
+            written += unwritten.write(&t[..p2l])?;
+            written
+        };
+        let payload_ends_at = buf_len - unwritten.len();
+        self.tcp.psh = payload_bytes > 0;
 
         // calculate the checksum
         self.tcp.checksum = self
-            .tcp
-            .calc_checksum_ipv4(&self.ip, &[])
-            .expect("failed to compute checksum");
+            .ip
+            .tcp_checksum(&self.tcp, &buf[tcp_header_ends_at..payload_ends_at])?;
         let mut tcp_header_buf = &mut buf[ip_header_ends_at..tcp_header_ends_at];
         // debug!("{:?}", self.tcp);
-        self.tcp.write(&mut tcp_header_buf);
+        self.tcp.write(&mut tcp_header_buf)?;
+
+        let mut next_seq = seq.wrapping_add(payload_bytes as u32);
         if self.tcp.syn {
-            self.send.nxt = self.send.nxt.wrapping_add(1);
+            next_seq = next_seq.wrapping_add(1);
             self.tcp.syn = false;
         }
         if self.tcp.fin {
-            self.send.nxt = self.send.nxt.wrapping_add(1);
+            next_seq = next_seq.wrapping_add(1);
             self.tcp.fin = false;
         }
-        // debug!("{:?}", &buf[..tcp_header_ends_at]);
+        if wrapping_lt(self.send.nxt, next_seq) {
+            self.send.nxt = next_seq;
+        }
+        if next_seq != seq {
+            // time one segment of new data at a time (RFC 6298 S3)
+            if self.rtt_timing.is_none() && wrapping_lt(self.send.max, next_seq) {
+                self.rtt_timing = Some((next_seq, out.now));
+            }
+            if self.timers.retransmit.is_none() {
+                self.timers.retransmit = Some(out.now + self.rtt.rto());
+            }
+        }
+        self.stats.segs_out += 1;
+        self.stats.bytes_sent += payload_bytes as u64;
+        out.counters.out_segs += 1;
+        if self.tcp.rst {
+            out.counters.out_rsts += 1;
+        }
+        if next_seq != seq && wrapping_lt(seq, self.send.max) {
+            // this part of the sequence space went out before
+            self.stats.retrans_segs += 1;
+            out.counters.retrans_segs += 1;
+            self.stats.bytes_retrans += payload_bytes as u64;
+        }
+        if wrapping_lt(self.send.max, next_seq) {
+            self.send.max = next_seq;
+        }
+        if self.tcp.ack {
+            // every segment carries the ACK
+            self.ack_pending = 0;
+            self.timers.delayed_ack = None;
+        }
+        // debug!("{:?}", &buf[..payload_ends_at]);
         // debug!("{:?}", self.tcp);
-        nic.send(&buf[..tcp_header_ends_at])
+        out.transmit(&buf[..payload_ends_at]);
+        Ok(payload_bytes)
     }
 
-    /// State::Estab | State::FinWait1 | State::FinWait2
-    /// read data, put into buffer
-    pub fn data_from_segment(
+    /// Set the options of the segments we send
+    fn set_options(&mut self, options: &[u8]) -> io::Result<()> {
+        self.tcp
+            .set_options_raw(options)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e)))
+    }
+
+    /// Send as much of the queued data as the peer's window allows, at most `mss` bytes per segment.
+    fn flush(&mut self, out: &mut Output) -> io::Result<()> {
+        loop {
+            let in_flight = self.send.nxt.wrapping_sub(self.send.una) as usize;
+            let unsent = self.unacked.len().saturating_sub(in_flight);
+            let wnd = std::cmp::min(self.send.wnd as usize, self.cc.cwnd);
+            let allowed = wnd.saturating_sub(in_flight);
+            let mss = self.effective_mss();
+            let n = std::cmp::min(unsent, std::cmp::min(allowed, mss));
+            if n == 0 {
+                if self.fin_pending && unsent == 0 {
+                    self.send_fin(out)?;
+                }
+                return Ok(());
+            }
+            // a PMTU probe is a larger segment of new data, only sent when there is enough of it (RFC 4821 S7.4)
+            if n == mss && (self.mss as usize) > mss && self.send.nxt == self.send.max {
+                if let Some(size) = self.pmtu.probe_size(out.now) {
+                    let probe = std::cmp::min(
+                        (size as usize).saturating_sub(self.headers_len()),
+                        self.mss as usize,
+                    );
+                    if probe > mss && unsent >= probe && allowed >= probe {
+                        let seq = self.send.nxt;
+                        let sent = self.write(out, seq, probe)?;
+                        self.pmtu.probe_sent(size, seq.wrapping_add(sent as u32));
+                        continue;
+                    }
+                }
+            }
+            self.write(out, self.send.nxt, n)?;
+        }
+    }
+
+    /// Send our FIN after the last byte of data (RFC 793 S3.5)
+    fn send_fin(&mut self, out: &mut Output) -> io::Result<()> {
+        self.fin_pending = false;
+        self.tcp.fin = true;
+        self.write(out, self.send.nxt, 0)?;
+        self.state = match self.state {
+            State::CloseWait => State::LastAck,
+            _ => State::FinWait1,
+        };
+        Ok(())
+    }
+
+    /// The application is done sending: the FIN follows whatever is still queued.
+    /// A connection that isn't established yet is aborted.
+    pub fn close(&mut self, out: &mut Output) -> io::Result<()> {
+        match self.state {
+            State::Estab | State::CloseWait => {
+                self.fin_pending = true;
+                self.flush(out)
+            }
+            State::SynSent | State::SynRcvd => self.abort(out),
+            // closing already
+            _ => Ok(()),
+        }
+    }
+
+    /// Drop the connection at once, the peer gets a RST unless it never heard from us
+    /// or is done with us already (RFC 793 S3.9, ABORT Call)
+    pub fn abort(&mut self, out: &mut Output) -> io::Result<()> {
+        let (was, had) = (self.state, self.incoming.len());
+        if let State::SynRcvd
+        | State::Estab
+        | State::FinWait1
+        | State::FinWait2
+        | State::CloseWait = self.state
+        {
+            self.tcp.rst = true;
+            let sent = self.write(out, self.send.nxt, 0);
+            self.tcp.rst = false;
+            sent?;
+        }
+        self.timers = Default::default();
+        self.state = State::Closed;
+        self.report(out, was, had);
+        Ok(())
+    }
+
+    /// Our FIN and everything before it has been acknowledged
+    fn fin_acked(&self) -> bool {
+        self.send.una == self.send.max
+    }
+
+    /// Acknowledge the peer's FIN and wait out 2 MSL for stray segments (RFC 793 S3.5)
+    fn time_wait(&mut self, out: &mut Output) -> io::Result<()> {
+        self.write(out, self.send.nxt, 0)?;
+        self.timers = Default::default();
+        self.timers.time_wait = Some(out.now + TIME_WAIT);
+        self.state = State::TimeWait;
+        Ok(())
+    }
+
+    /// A snapshot of the state and counters of the flow at `now`
+    pub fn info(&self, now: Instant) -> Info {
+        Info {
+            quad: self.quad,
+            state: self.state,
+            age: now.saturating_duration_since(self.stats.timer),
+            rtt: self.rtt.srtt,
+            rttvar: self.rtt.rttvar,
+            rto: self.rtt.rto(),
+            retransmits: self.retries,
+            timer: self
+                .timers
+                .pending()
+                .map(|(timer, at)| (timer, at.saturating_duration_since(now))),
+            mss: self.mss,
+            snd_mss: self.effective_mss() as u16,
+            pmtu: self.pmtu.pmtu,
+            cwnd: self.cc.cwnd,
+            ssthresh: self.cc.ssthresh,
+            snd_wnd: self.send.wnd,
+            rcv_wnd: self.recv.wnd,
+            unacked: self.send.max.wrapping_sub(self.send.una),
+            send_queue: self.unacked.len(),
+            recv_queue: self.incoming.len(),
+            ecn: self.ecn.enabled,
+            bytes_sent: self.stats.bytes_sent,
+            bytes_acked: self.stats.bytes_acked,
+            bytes_received: self.stats.size,
+            bytes_retrans: self.stats.bytes_retrans,
+            segs_out: self.stats.segs_out,
+            segs_in: self.stats.segs_in,
+            retrans_segs: self.stats.retrans_segs,
+            out_of_order: self.stats.out_of_order,
+        }
+    }
+
+    /// Bytes queued for sending, sent or not, that the peer hasn't acknowledged
+    pub fn send_buffered(&self) -> usize {
+        self.unacked.len()
+    }
+
+    /// Received data the application hasn't read yet
+    pub fn recv_buffered(&self) -> usize {
+        self.incoming.len()
+    }
+
+    /// The MSS we announce: whatever fits in the MTU of the interface
+    fn advertised_mss(&self, out: &Output) -> u16 {
+        // the IP header and 20 bytes of TCP header, without options (RFC 879, RFC 8200 S8.3)
+        out.mtu
+            .saturating_sub((self.ip.header_len() + TCP_HEADER_LEN) as u16)
+    }
+
+    fn headers_len(&self) -> usize {
+        self.ip.header_len() + self.tcp.header_len() as usize
+    }
+
+    /// The largest segment we send: the peer's MSS, limited by the path MTU
+    fn effective_mss(&self) -> usize {
+        std::cmp::min(
+            self.mss as usize,
+            (self.pmtu.pmtu as usize).saturating_sub(self.headers_len()),
+        )
+    }
+
+    /// React to an ICMP destination unreachable about our segment with sequence number `seq`.
+    pub fn icmp_error(
+        &mut self,
+        out: &mut Output,
+        error: icmp::Unreachable,
+        seq: u32,
+    ) -> io::Result<()> {
+        // only believe messages about data that is in flight (RFC 5927 S4.1)
+        if !is_between_wrapped(self.send.una.wrapping_sub(1), seq, self.send.nxt) {
+            out.discard(DropReason::OutOfWindow);
+            return Ok(());
+        }
+        let (was, had) = (self.state, self.incoming.len());
+        match error {
+            icmp::Unreachable::FragmentationNeeded(mtu) => {
+                if self.pmtu.on_fragmentation_needed(mtu) {
+                    debug!("path MTU of {:?} lowered to {}", self.quad, self.pmtu.pmtu);
+                    if let State::Estab = self.state {
+                        // whatever is in flight was too big, send it again in smaller segments
+                        self.send.nxt = self.send.una;
+                        self.flush(out)?;
+                    }
+                }
+            }
+            // hard errors only abort connections that are still being set up (RFC 5461 S4)
+            e if e.is_hard() && matches!(self.state, State::SynSent | State::SynRcvd) => {
+                debug!("connection {:?} refused: {:?}", self.quad, e);
+                self.error = Some(e.into());
+                self.timers = Default::default();
+                self.state = State::Closed;
+            }
+            e => {
+                debug!("soft error on {:?}: {:?}", self.quad, e);
+                self.error = Some(e.into());
+            }
+        }
+        self.report(out, was, had);
+        Ok(())
+    }
+
+    /// Queue `data` for transmission and send what the window allows.
+    /// With `urgent` the end of `data` becomes the urgent mark: SND.UP points to the byte following it (RFC 6093).
+    pub fn send(&mut self, out: &mut Output, data: &[u8], urgent: bool) -> io::Result<usize> {
+        match self.state {
+            State::Estab | State::CloseWait => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "connection is not established",
+                ))
+            }
+        }
+        self.unacked.extend(data);
+        if urgent {
+            self.send.up = self.send.una.wrapping_add(self.unacked.len() as u32);
+        }
+        self.flush(out)?;
+        Ok(data.len())
+    }
+
+    /// Move up to `buf.len()` bytes of received data into `buf`.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let n = std::cmp::min(buf.len(), self.incoming.len());
+        for (b, x) in buf.iter_mut().zip(self.incoming.drain(..n)) {
+            *b = x;
+        }
+        n
+    }
+
+    /// Offset into the unread data of the byte following the urgent data, if the peer
+    /// has signalled urgent data that has not been read past yet (RFC 6093).
+    /// The urgent data itself stays inline; the offset may lie beyond what has arrived so far.
+    pub fn urgent_offset(&self) -> Option<usize> {
+        let read = self.stats.size - self.incoming.len() as u64;
+        let unread_at = self.recv.irs.wrapping_add(1).wrapping_add(read as u32);
+        if wrapping_lt(unread_at, self.recv.up) {
+            Some(self.recv.up.wrapping_sub(unread_at) as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Process SEG.ACK and SEG.WND of an acceptable segment (RFC 793 S3.9, ESTABLISHED STATE)
+    /// `data_len` is the payload length of the segment, only pure ACKs count as duplicates.
+    fn ack_from_segment(
         &mut self,
-        data: &[u8],
         tcph: &etherparse::TcpHeaderSlice,
-    ) -> io::Result<u64> {
+        data_len: usize,
+        now: Instant,
+    ) {
         let seqn = tcph.sequence_number();
+        let ackn = tcph.acknowledgment_number();
+        if !is_between_wrapped(
+            self.send.una.wrapping_sub(1),
+            ackn,
+            self.send.max.wrapping_add(1),
+        ) {
+            // an old duplicate or something we never sent
+            return;
+        }
+        // data sent before a retransmission may be acknowledged beyond SND.NXT
+        if wrapping_lt(self.send.nxt, ackn) {
+            self.send.nxt = ackn;
+        }
+        // ECN-Echo counts as a loss, at most once per window of data (RFC 3168 S6.1.2)
+        if self.ecn.enabled && tcph.ece() && wrapping_lt(self.ecn.recover, ackn) {
+            let flight_size = self.send.nxt.wrapping_sub(self.send.una) as usize;
+            self.cc.on_congestion(flight_size);
+            self.ecn.cwr = true;
+            self.ecn.recover = self.send.nxt;
+        }
+        if ackn != self.send.una {
+            let acked = ackn.wrapping_sub(self.send.una) as usize;
+            let acked = std::cmp::min(acked, self.unacked.len());
+            self.unacked.drain(..acked);
+            self.stats.bytes_acked += acked as u64;
+            self.send.una = ackn;
+            self.cc.on_ack(acked);
+            self.dupacks = 0;
+            self.ack_advanced(ackn, now);
+            if let Some((_, end)) = self.pmtu.probe_in_flight() {
+                if !wrapping_lt(ackn, end) {
+                    self.pmtu.probe_acked();
+                    debug!("path MTU of {:?} raised to {}", self.quad, self.pmtu.pmtu);
+                }
+            }
+        } else if data_len == 0
+            && self.send.una != self.send.nxt
+            && tcph.window_size() == self.send.wnd
+        {
+            // RFC 5681 S2 duplicate ACK
+            self.dupacks += 1;
+            if let Some((_, end)) = self.pmtu.probe_in_flight() {
+                // the segments after the probe arrived but the probe did not
+                if self.dupacks >= 3 && wrapping_lt(ackn, end) {
+                    self.pmtu.probe_lost();
+                    self.dupacks = 0;
+                    // send the probe's data again in segments that fit
+                    self.send.nxt = self.send.una;
+                }
+            }
+        }
+        if wrapping_lt(self.send.wl1, seqn)
+            || (self.send.wl1 == seqn && !wrapping_lt(ackn, self.send.wl2))
+        {
+            self.send.wnd = tcph.window_size();
+            self.send.wl1 = seqn;
+            self.send.wl2 = ackn;
+        }
+    }
+
+    /// The IP header of a segment for this flow was marked CE, echo it until the peer sends CWR.
+    pub fn congestion_experienced(&mut self) {
+        if self.ecn.enabled {
+            self.ecn.echo = true;
+        }
+    }
+
+    /// SND.UNA moved up to `ackn`: finish the RTT measurement it covers and restart
+    /// the retransmission timer for what is still in flight (RFC 6298 S5.2, S5.3)
+    fn ack_advanced(&mut self, ackn: u32, now: Instant) {
+        if let Some((end, sent_at)) = self.rtt_timing {
+            if !wrapping_lt(ackn, end) {
+                self.rtt.sample(now.saturating_duration_since(sent_at));
+                self.rtt_timing = None;
+            }
+        }
+        self.retries = 0;
+        self.timers.retransmit = if self.send.una == self.send.max {
+            None
+        } else {
+            Some(now + self.rtt.rto())
+        };
+    }
 
-        let mut unread_data_at = (self.recv.nxt - seqn) as usize;
+    /// Acknowledge received data now, or within `DELAYED_ACK` if nothing is sent before.
+    /// Every second segment is acknowledged right away, and so is data that
+    /// arrived out of order (RFC 1122 S4.2.3.2, RFC 5681 S4.2).
+    fn ack_received(&mut self, out: &mut Output, now: bool) -> io::Result<()> {
+        if self.ack_pending >= 2 || now {
+            self.write(out, self.send.nxt, 0)?;
+        } else if self.timers.delayed_ack.is_none() {
+            self.timers.delayed_ack = Some(out.now + DELAYED_ACK);
+        }
+        Ok(())
+    }
+
+    /// Handle the timers of the flow that are due at `now`
+    pub fn on_timer(&mut self, out: &mut Output) -> io::Result<()> {
+        let (was, had) = (self.state, self.incoming.len());
+        let now = out.now;
+        if self.timers.delayed_ack.is_some_and(|t| t <= now) {
+            self.timers.delayed_ack = None;
+            self.write(out, self.send.nxt, 0)?;
+        }
+        if self.timers.retransmit.is_some_and(|t| t <= now) {
+            self.timers.retransmit = None;
+            self.retransmit(out)?;
+        }
+        if self.timers.time_wait.is_some_and(|t| t <= now) {
+            debug!("TIME-WAIT of {:?} is over", self.quad);
+            self.timers = Default::default();
+            self.state = State::Closed;
+        }
+        self.report(out, was, had);
+        Ok(())
+    }
+
+    /// The retransmission timer expired: send the oldest unacknowledged segment again and
+    /// back off, or give up on the connection after too many tries (RFC 6298 S5.4-5.7)
+    fn retransmit(&mut self, out: &mut Output) -> io::Result<()> {
+        let handshake = matches!(self.state, State::SynSent | State::SynRcvd);
+        if self.retries
+            >= if handshake {
+                MAX_SYN_RETRIES
+            } else {
+                MAX_RETRIES
+            }
+        {
+            debug!("{:?} timed out", self.quad);
+            self.error = Some(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "connection timed out",
+            ));
+            self.timers = Default::default();
+            self.state = State::Closed;
+            return Ok(());
+        }
+        self.retries += 1;
+        self.rtt.backoff();
+        // Karn: whatever was being timed may now be acknowledged for either copy
+        self.rtt_timing = None;
+        match self.state {
+            State::SynSent | State::SynRcvd => {
+                // the SYN or SYN-ACK again, with its MSS option but without data
+                self.tcp.syn = true;
+                self.set_options(&options::mss(self.advertised_mss(out)))?;
+                self.write(out, self.send.iss, 0)?;
+                self.set_options(&[])?;
+            }
+            State::Estab | State::CloseWait => {
+                // repeated timeouts of full sized segments may be a PMTU black hole (RFC 4821 S7.7)
+                if self.retries == BLACK_HOLE_RETRIES && self.pmtu.on_black_hole() {
+                    debug!("path MTU of {:?} lowered to {}", self.quad, self.pmtu.pmtu);
+                }
+                // the probe is sent again in segments that fit, it would never be acknowledged as one
+                self.pmtu.probe_lost();
+                let flight_size = self.send.max.wrapping_sub(self.send.una) as usize;
+                self.cc.on_timeout(flight_size);
+                // go back to the oldest unacknowledged byte, the window allows one segment
+                self.send.nxt = self.send.una;
+                self.flush(out)?;
+            }
+            State::LastAck | State::FinWait1 | State::Closing => {
+                if self.unacked.is_empty() {
+                    // our FIN is the last thing in flight
+                    self.tcp.fin = true;
+                    self.write(out, self.send.max.wrapping_sub(1), 0)?;
+                } else {
+                    // data ahead of the FIN, one segment of it at a time
+                    let n = std::cmp::min(self.unacked.len(), self.effective_mss());
+                    self.write(out, self.send.una, n)?;
+                }
+            }
+            _ => {}
+        }
+        self.timers.retransmit = Some(out.now + self.rtt.rto());
+        Ok(())
+    }
+
+    /// Handle a segment of this flow that arrived at `out.now`
+    pub fn on_segment(
+        &mut self,
+        out: &mut Output,
+        tcph: etherparse::TcpHeaderSlice,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let (was, had) = (self.state, self.incoming.len());
+        self.stats.segs_in += 1;
+        if tcph.rst() && !matches!(self.state, State::SynSent | State::Closed) {
+            self.reset_received(out, &tcph)?;
+            self.report(out, was, had);
+            return Ok(());
+        }
+        match self.state {
+            State::SynRcvd => {
+                self.SynRcvd_handler(out, tcph, data)?;
+            }
+            State::SynSent => {
+                self.SynSent_handler(out, tcph)?;
+            }
+            State::Estab => {
+                self.Estab_handler(out, tcph, data)?;
+            }
+            State::FinWait1 => {
+                self.FinWait1_handler(out, tcph, data)?;
+            }
+            State::FinWait2 => {
+                self.FinWait2_handler(out, tcph, data)?;
+            }
+            State::Closing => {
+                self.Closing_handler(out, tcph)?;
+            }
+            State::TimeWait => {
+                self.TimeWait_handler(out, tcph)?;
+            }
+            State::CloseWait => {
+                self.CloseWait_handler(out, tcph, data)?;
+            }
+            State::LastAck => {
+                self.LastAck_handler(out, tcph)?;
+            }
+            State::Closed => {
+                self.Closed_handler();
+                out.discard(DropReason::FlowClosed);
+            }
+        }
+        self.report(out, was, had);
+        Ok(())
+    }
+
+    /// Tell the application what changed since the flow was in state `was` with `had` bytes unread
+    fn report(&self, out: &mut Output, was: State, had: usize) {
+        let opening = |s| matches!(s, State::SynSent | State::SynRcvd);
+        let peer_closed = |s| {
+            matches!(
+                s,
+                State::CloseWait | State::LastAck | State::Closing | State::TimeWait
+            )
+        };
+        if opening(was) && !opening(self.state) && self.state != State::Closed {
+            out.event(Event::Established(self.quad));
+        }
+        if self.incoming.len() > had {
+            out.event(Event::Readable(self.quad));
+        }
+        if peer_closed(self.state) && !peer_closed(was) {
+            out.event(Event::PeerClosed(self.quad));
+        }
+        if self.state == State::Closed && was != State::Closed {
+            out.event(Event::Closed(self.quad));
+            match was {
+                State::SynSent | State::SynRcvd => out.counters.attempt_fails += 1,
+                State::Estab | State::CloseWait => out.counters.estab_resets += 1,
+                _ => {}
+            }
+        }
+    }
+
+    /// State::Estab | State::FinWait1 | State::FinWait2
+    /// Take the data of an acceptable segment. Data ahead of RCV.NXT waits in `ahead` until the
+    /// gap before it is filled, its FIN is left for the peer to send again.
+    /// Returns whether the FIN of the segment was reached.
+    pub fn data_from_segment(&mut self, data: &[u8], tcph: &etherparse::TcpHeaderSlice) -> bool {
+        let seqn = tcph.sequence_number();
+        // RCV.UP <- max(RCV.UP, SEG.UP), a zero urgent pointer marks nothing
+        if tcph.urg() && tcph.urgent_pointer() != 0 {
+            let up = seqn.wrapping_add(tcph.urgent_pointer() as u32);
+            if wrapping_lt(self.recv.up, up) {
+                self.recv.up = up;
+            }
+        }
+        if wrapping_lt(self.recv.nxt, seqn) {
+            self.queue_ahead(seqn, data);
+            return false;
+        }
+        let unread_data_at = self.recv.nxt.wrapping_sub(seqn) as usize;
         if unread_data_at > data.len() {
-            // ?
-            // we must have received a re-transmitted FIN that we have already seen
-            // nxt points to beyond the fin, but the fin is not in data!
-            assert_eq!(unread_data_at, data.len() + 1);
-            unread_data_at = 0;
+            // everything was received before, a retransmitted FIN included
+            return false;
+        }
+        self.deliver(&data[unread_data_at..]);
+        if tcph.fin() {
+            self.ahead.clear();
+            self.recv.nxt = self.recv.nxt.wrapping_add(1);
+            return true;
+        }
+        // the segment may have filled the gap before some that arrived early
+        while let Some(i) = self
+            .ahead
+            .iter()
+            .position(|(seq, _)| !wrapping_lt(self.recv.nxt, *seq))
+        {
+            let (seq, data) = self.ahead.swap_remove(i);
+            let unread_data_at = self.recv.nxt.wrapping_sub(seq) as usize;
+            if unread_data_at < data.len() {
+                self.deliver(&data[unread_data_at..]);
+            }
         }
-        self.incoming.extend(&data[unread_data_at..]);
-        self.stats.size += data[unread_data_at..].len() as u64;
-        //self.debug_print_buffer();
-        self.recv.nxt = seqn
-            .wrapping_add(data.len() as u32)
-            .wrapping_add(if tcph.fin() { 1 } else { 0 });
-        return Ok(0 as u64);
+        false
+    }
+
+    /// Append in order data to what the application reads, RCV.NXT moves past it
+    fn deliver(&mut self, data: &[u8]) {
+        self.incoming.extend(data);
+        self.stats.size += data.len() as u64;
+        self.recv.nxt = self.recv.nxt.wrapping_add(data.len() as u32);
+    }
+
+    /// Keep `data` that starts at `seqn`, ahead of RCV.NXT, as far as it fits in the window
+    fn queue_ahead(&mut self, seqn: u32, data: &[u8]) {
+        let wend = self.recv.nxt.wrapping_add(self.recv.wnd as u32);
+        let data = &data[..std::cmp::min(data.len(), wend.wrapping_sub(seqn) as usize)];
+        let queued: usize = self.ahead.iter().map(|(_, d)| d.len()).sum();
+        if data.is_empty()
+            || queued + data.len() > self.recv.wnd as usize
+            || self
+                .ahead
+                .iter()
+                .any(|(seq, d)| *seq == seqn && d.len() >= data.len())
+        {
+            return;
+        }
+        self.ahead.push((seqn, data.to_vec()));
     }
 
     /// Segment Receive  Test: called by ESTABLISH
     /// slen: the virtual data len, counting syn or fin
+    ///
+    /// ```text
     ///     Length  Window
     ///     ------- -------  -------------------------------------------
     ///
@@ -325,42 +1236,77 @@ impl flow {
     ///         if tcph.syn() {
     ///             slen += 1;
     ///         };
+    /// ```
     pub fn segment_check(&mut self, slen: u32, seqn: u32) -> bool {
         let wend = self.recv.nxt.wrapping_add(self.recv.wnd as u32);
-        let okay = if slen == 0 {
+        if slen == 0 {
             // zero-length segment has separate rules for acceptance
             if self.recv.wnd == 0 {
-                if seqn != self.recv.nxt {
-                    false
-                } else {
-                    true
-                }
-            } else if !is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend) {
-                false
+                seqn == self.recv.nxt
             } else {
-                true
+                is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend)
             }
+        } else if self.recv.wnd == 0 {
+            false
         } else {
-            if self.recv.wnd == 0 {
-                false
-            } else if !is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend)
-                && !is_between_wrapped(
+            is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend)
+                || is_between_wrapped(
                     self.recv.nxt.wrapping_sub(1),
                     seqn.wrapping_add(slen - 1),
                     wend,
                 )
-            {
-                false
-            } else {
-                true
+        }
+    }
+
+    /// A segment that failed `segment_check` is dropped, and acknowledged unless it is a RST
+    /// so a peer that lost track of us learns RCV.NXT and SND.NXT (RFC 793 S3.9)
+    fn unacceptable(
+        &mut self,
+        out: &mut Output,
+        tcph: &etherparse::TcpHeaderSlice,
+    ) -> io::Result<u64> {
+        if !tcph.rst() {
+            self.write(out, self.send.nxt, 0)?;
+        }
+        out.discard(DropReason::OutOfWindow);
+        Ok(0)
+    }
+
+    /// A RST once our SYN-ACK is out or the connection is synchronized (RFC 793 S3.4).
+    /// It is only believed at exactly RCV.NXT, anywhere else in the window it gets a
+    /// challenge ACK: a real peer answers with the right RST, a blind attacker can't (RFC 5961 S3.2).
+    fn reset_received(
+        &mut self,
+        out: &mut Output,
+        tcph: &etherparse::TcpHeaderSlice,
+    ) -> io::Result<()> {
+        let seqn = tcph.sequence_number();
+        if !self.segment_check(0, seqn) {
+            return self.unacceptable(out, tcph).map(|_| ());
+        }
+        if seqn != self.recv.nxt {
+            self.write(out, self.send.nxt, 0)?;
+            out.discard(DropReason::OutOfWindow);
+            return Ok(());
+        }
+        debug!("connection {:?} reset by the peer", self.quad);
+        match self.state {
+            // nobody accepted it yet, the listener just carries on
+            State::SynRcvd => {}
+            State::Estab | State::FinWait1 | State::FinWait2 | State::CloseWait => {
+                self.error = Some(io::Error::from(io::ErrorKind::ConnectionReset));
             }
-        };
-        return okay;
+            // we were done with it anyway
+            _ => {}
+        }
+        self.timers = Default::default();
+        self.state = State::Closed;
+        Ok(())
     }
 
     pub fn SynRcvd_handler(
         &mut self,
-        nic: &mut nic::Interface,
+        out: &mut Output,
         tcph: etherparse::TcpHeaderSlice,
         data: &[u8],
     ) -> io::Result<u64> {
@@ -369,10 +1315,15 @@ impl flow {
         let seqn = tcph.sequence_number();
         let ackn = tcph.acknowledgment_number();
 
-        // the segement length is data length plus 1 (SYN)
-        let ok = self.segment_check((data.len() + 1) as u32, seqn);
-        if ok == false {
-            return Ok(0 as u64);
+        // the SYN came before, this segment only carries the ACK and maybe data and a FIN
+        let ok = self.segment_check(data.len() as u32, seqn);
+        if !ok {
+            out.discard(DropReason::OutOfWindow);
+            return Ok(0);
+        }
+        if !tcph.ack() {
+            out.discard(DropReason::BadAck);
+            return Ok(0);
         }
 
         // whether ack our previous ack
@@ -385,84 +1336,192 @@ impl flow {
             // and we have only sent one byte (the SYN).
             debug!("connection established!");
             self.state = State::Estab;
+            self.send.una = ackn;
+            self.send.wnd = tcph.window_size();
+            self.send.wl1 = seqn;
+            self.send.wl2 = ackn;
+            self.ack_advanced(ackn, out.now);
         } else {
-            // TODO: <SEQ=SEG.ACK><CTL=RST>
-            return Ok(0 as u64);
+            reset(out, &self.quad, &tcph, data.len())?;
+            out.discard(DropReason::BadAck);
+            return Ok(0);
         }
 
-        self.data_from_segment(data, &tcph);
+        let fin = self.data_from_segment(data, &tcph);
+        if fin {
+            // the peer closed right away, like Estab_handler
+            self.state = State::CloseWait;
+        }
 
         // no need to ack if there is no data
-        if data.len() != 0 {
-            self.write(nic, self.send.nxt, 0)?;
+        if !data.is_empty() || fin {
+            self.write(out, self.send.nxt, 0)?;
         }
-        return Ok(0 as u64);
+        Ok(0)
     }
 
     pub fn Estab_handler(
         &mut self,
-        nic: &mut nic::Interface,
+        out: &mut Output,
         tcph: etherparse::TcpHeaderSlice,
         data: &[u8],
     ) -> io::Result<u64> {
         // debug!("Estab_handler called");
 
         let seqn = tcph.sequence_number();
-        let ackn = tcph.acknowledgment_number();
 
-        let mut unread_data_at = (self.recv.nxt - seqn) as usize;
-        if unread_data_at > data.len() {
-            // ?
-            // we must have received a re-transmitted FIN that we have already seen
-            // nxt points to beyond the fin, but the fin is not in data!
-            assert_eq!(unread_data_at, data.len() + 1);
-            unread_data_at = 0;
-        }
         // debug!("{:?}", self.recv.nxt);
         // debug!("{:?}", self.recv.wnd);
         // debug!("{:?}", seqn);
-        if let ok = self.segment_check(data.len() as u32, seqn) {
-            match (ok) {
-                true => {
-                    self.data_from_segment(data, &tcph);
-
-                    self.write(nic, self.send.nxt, 0)?;
-
-                    if tcph.fin() {
-                        self.state = State::CloseWait;
-                        // sending an FIN immediately
-                        self.tcp.fin = true;
-                        self.write(nic, self.send.nxt, 0)?;
-                        self.state = State::LastAck;
-                    }
-                    return Ok(0 as u64);
+        let ok = self.segment_check(data.len() as u32, seqn);
+        if !ok {
+            return self.unacceptable(out, &tcph);
+        }
+        let in_order = seqn == self.recv.nxt;
+        // old duplicates aren't out of order, only data past a gap is
+        if wrapping_lt(self.recv.nxt, seqn) && !data.is_empty() {
+            self.stats.out_of_order += 1;
+        }
+
+        if tcph.ack() {
+            self.ack_from_segment(&tcph, data.len(), out.now);
+        }
+        if tcph.cwr() {
+            // the peer reduced its window, stop echoing
+            self.ecn.echo = false;
+        }
+        // a segment that fills a gap is acknowledged right away too (RFC 5681 S4.2)
+        let gap = !self.ahead.is_empty();
+        let fin = self.data_from_segment(data, &tcph);
+
+        // only segments that take up sequence space need an ACK
+        if !data.is_empty() {
+            self.ack_pending += 1;
+        }
+        // the ACK may have opened the window for queued data, which carries our ACK too
+        self.flush(out)?;
+        if self.ack_pending > 0 && !fin {
+            self.ack_received(out, !in_order || gap)?;
+        }
+
+        if fin {
+            // nothing more arrives, we may still send until the application closes (RFC 793 S3.5)
+            self.state = State::CloseWait;
+            self.write(out, self.send.nxt, 0)?;
+        }
+        Ok(0)
+    }
+
+    /// We sent our FIN, the peer may still send data until its own FIN
+    pub fn FinWait1_handler(
+        &mut self,
+        out: &mut Output,
+        tcph: etherparse::TcpHeaderSlice,
+        data: &[u8],
+    ) -> io::Result<u64> {
+        if !self.segment_check(data.len() as u32, tcph.sequence_number()) {
+            return self.unacceptable(out, &tcph);
+        }
+        if tcph.ack() {
+            self.ack_from_segment(&tcph, data.len(), out.now);
+        }
+        let fin = self.data_from_segment(data, &tcph);
+        match (self.fin_acked(), fin) {
+            (true, true) => self.time_wait(out)?,
+            (true, false) => {
+                self.state = State::FinWait2;
+                if !data.is_empty() {
+                    self.write(out, self.send.nxt, 0)?;
                 }
-                false => {
-                    debug!("not okay!!!");
-                    return Ok(0 as u64);
+            }
+            (false, true) => {
+                self.write(out, self.send.nxt, 0)?;
+                self.state = State::Closing;
+            }
+            (false, false) => {
+                if !data.is_empty() {
+                    self.write(out, self.send.nxt, 0)?;
                 }
             }
         }
-        return Ok(0 as u64);
+        Ok(0)
     }
 
-    pub fn FinWait1_handler(&mut self) {
-        debug!("FinWait1 called");
+    /// Our FIN is acknowledged, wait for the peer's
+    pub fn FinWait2_handler(
+        &mut self,
+        out: &mut Output,
+        tcph: etherparse::TcpHeaderSlice,
+        data: &[u8],
+    ) -> io::Result<u64> {
+        if !self.segment_check(data.len() as u32, tcph.sequence_number()) {
+            return self.unacceptable(out, &tcph);
+        }
+        if self.data_from_segment(data, &tcph) {
+            self.time_wait(out)?;
+        } else if !data.is_empty() {
+            self.write(out, self.send.nxt, 0)?;
+        }
+        Ok(0)
     }
-    pub fn FinWait2_handler(&mut self) {
-        debug!("FinWait2 called");
+
+    /// Both FINs crossed, wait for the ACK of ours
+    pub fn Closing_handler(
+        &mut self,
+        out: &mut Output,
+        tcph: etherparse::TcpHeaderSlice,
+    ) -> io::Result<u64> {
+        if !self.segment_check(0, tcph.sequence_number()) {
+            return self.unacceptable(out, &tcph);
+        }
+        if tcph.ack() {
+            self.ack_from_segment(&tcph, 0, out.now);
+        }
+        if self.fin_acked() {
+            self.time_wait(out)?;
+        }
+        Ok(0)
     }
-    pub fn TimeWait_handler(&mut self) {
+    /// A segment in TIME-WAIT can only be a retransmission of the peer's FIN, whose ACK
+    /// got lost: acknowledge it again and restart the 2 MSL timeout (RFC 793 S3.9)
+    pub fn TimeWait_handler(
+        &mut self,
+        out: &mut Output,
+        tcph: etherparse::TcpHeaderSlice,
+    ) -> io::Result<u64> {
         debug!("TimeWait called");
+        if tcph.fin() {
+            self.write(out, self.send.nxt, 0)?;
+            self.timers.time_wait = Some(out.now + TIME_WAIT);
+        } else if !self.segment_check(0, tcph.sequence_number()) {
+            return self.unacceptable(out, &tcph);
+        } else {
+            out.discard(DropReason::FlowClosed);
+        }
+        Ok(0)
     }
 
-    pub fn CloseWait_handler(&mut self) {
-        debug!("CloseWait called");
+    /// The peer sent its FIN, we still send and take its ACKs until the application closes
+    pub fn CloseWait_handler(
+        &mut self,
+        out: &mut Output,
+        tcph: etherparse::TcpHeaderSlice,
+        data: &[u8],
+    ) -> io::Result<u64> {
+        if !self.segment_check(data.len() as u32, tcph.sequence_number()) {
+            // a retransmitted FIN is outside the window too, its ACK got lost
+            return self.unacceptable(out, &tcph);
+        }
+        if tcph.ack() {
+            self.ack_from_segment(&tcph, data.len(), out.now);
+        }
+        self.flush(out)?;
+        Ok(0)
     }
 
     pub fn LastAck_handler(
         &mut self,
-        nic: &mut nic::Interface,
+        out: &mut Output,
         tcph: etherparse::TcpHeaderSlice,
     ) -> io::Result<u64> {
         // debug!("LastAck called");
@@ -470,107 +1529,176 @@ impl flow {
         let ackn = tcph.acknowledgment_number();
 
         // the segement length is data length plus 1 (FIN)
-        let ok = self.segment_check(1 as u32, seqn);
-        if ok == false {
-            return Ok(0 as u64);
+        let ok = self.segment_check(1, seqn);
+        if !ok {
+            return self.unacceptable(out, &tcph);
         }
 
-        // whether ack our previous ack
-        if is_between_wrapped(
-            self.send.una.wrapping_sub(1),
-            ackn,
-            self.send.nxt.wrapping_add(1),
-        ) {
-            // must have ACKed our SYN, since we detected at least one acked byte,
-            // and we have only sent one byte (the SYN).
+        if !tcph.ack() {
+            out.discard(DropReason::BadAck);
+            return Ok(0);
+        }
+        if wrapping_lt(self.send.max, ackn) {
+            // an ACK of something we never sent: a synchronized connection answers with
+            // what it did send, not a RST (RFC 793 S3.9, SEGMENT ARRIVES)
+            self.write(out, self.send.nxt, 0)?;
+            out.discard(DropReason::BadAck);
+            return Ok(0);
+        }
+        // data written after the peer's FIN may still be in flight ahead of ours
+        self.ack_from_segment(&tcph, 0, out.now);
+        if self.fin_acked() {
             debug!("connection terminated!");
             self.debug_print_statistics();
-            self.debug_print_buffer();
             self.state = State::Closed;
-        } else {
-            // TODO: <SEQ=SEG.ACK><CTL=RST>
-            return Ok(0 as u64);
+            self.timers = Default::default();
         }
-
-        // how to destroy the connection in memory
-        return Ok(0 as u64);
+        Ok(0)
     }
 
     pub fn Closed_handler(&mut self) {
         debug!("Closed_handler called");
     }
 
+    /// Our SYN is out, only a SYN with an ACK of it or a RST for it is taken
+    /// (RFC 793 S3.9, SYN-SENT STATE)
     pub fn SynSent_handler(
         &mut self,
-        nic: &mut nic::Interface,
+        out: &mut Output,
         tcph: etherparse::TcpHeaderSlice,
     ) -> io::Result<u64> {
-        // debug!("SynSent_handler called");
-
         let seqn = tcph.sequence_number();
         let ackn = tcph.acknowledgment_number();
-        self.recv.nxt = seqn;
-        // the segement length is 0
-        // debug!("{:?}", self.recv.nxt);
-        // debug!("{:?}", self.recv.wnd);
-        // debug!("{:?}", seqn);
-        let ok = self.segment_check(0 as u32, seqn);
-        if ok == false {
-            debug!("not okay");
-            return Ok(0 as u64);
-        }
 
-        // whether ack our previous SYN
-        if is_between_wrapped(
-            self.send.una.wrapping_sub(1),
-            ackn,
-            self.send.nxt.wrapping_add(1),
-        ) {
-            // must have ACKed our SYN, since we detected at least one acked byte,
-            // and we have only sent one byte (the SYN).
-            debug!("connection established!");
-            self.state = State::Estab;
-        } else {
-            // TODO: <SEQ=SEG.ACK><CTL=RST>
-            return Ok(0 as u64);
+        // an ACK must cover our SYN and nothing we never sent
+        let ack_ok = is_between_wrapped(self.send.iss, ackn, self.send.nxt.wrapping_add(1));
+        if tcph.ack() && !ack_ok {
+            reset(out, &self.quad, &tcph, 0)?;
+            out.discard(DropReason::BadAck);
+            return Ok(0);
+        }
+        if tcph.rst() {
+            // only a RST that acknowledges our SYN is believed
+            if tcph.ack() {
+                debug!("connection {:?} refused", self.quad);
+                self.error = Some(io::Error::from(io::ErrorKind::ConnectionRefused));
+                self.timers = Default::default();
+                self.state = State::Closed;
+            } else {
+                out.discard(DropReason::BadAck);
+            }
+            return Ok(0);
+        }
+        // a SYN without an ACK would be a simultaneous open, which isn't supported
+        if !tcph.syn() || !tcph.ack() {
+            out.discard(DropReason::BadAck);
+            return Ok(0);
         }
-        // need to ACK to complete the handshake
-        self.tcp.ack = true;
-        self.recv.nxt = seqn + 1;
-        self.write(nic, self.send.nxt, 0)?;
 
-        return Ok(0 as u64);
-        // self.data_from_segment(data, &tcph);
-        // no need to ack if there is no data
-        // if data.len() != 0 {
-        //     self.write(nic, self.send.nxt, 0)?;
-        // }
-        // return Ok(0 as u64);
-    }
+        self.recv.irs = seqn;
+        self.recv.up = seqn;
+        self.recv.nxt = seqn.wrapping_add(1);
+        self.mss = mss_option(&tcph);
+        self.cc = congestion::Reno::new(self.mss);
 
-    pub fn debug_print_buffer(&mut self) {
-        // print_data_in_the_buffer
-        let temp_directory = env::current_dir().unwrap();
-        //println!("The current directory is {}", temp_directory.display());
-        let temp_file = temp_directory.join("test_recieved.mp4");
-        let mut file = File::create(temp_file).unwrap();
-        file.write(Vec::from(self.incoming.clone()).as_ref())
-            .unwrap();
-        //info!("data in the buffer (self.incoming) {:?}", test);
+        debug!("connection established!");
+        self.state = State::Estab;
+        // drop SYN data the server acknowledged, whatever it ignored is sent again (RFC 7413 S4.2.2)
+        let acked = ackn.wrapping_sub(self.send.una.wrapping_add(1)) as usize;
+        let acked = std::cmp::min(acked, self.unacked.len());
+        self.unacked.drain(..acked);
+        self.send.una = ackn;
+        self.send.nxt = ackn;
+        self.send.max = ackn;
+        self.send.wnd = tcph.window_size();
+        self.send.wl1 = seqn;
+        self.send.wl2 = ackn;
+        self.ack_advanced(ackn, out.now);
+        // an ECN-setup SYN-ACK has ECE but not CWR (RFC 3168 S6.1.1)
+        self.ecn.enabled = self.ecn.enabled && tcph.ece() && !tcph.cwr();
+        // need to ACK to complete the handshake
+        self.tcp.ack = true;
+        self.write(out, self.send.nxt, 0)?;
+        // data queued at connect time
+        self.flush(out)?;
+        Ok(0)
     }
 
     pub fn debug_print_statistics(&mut self) {
         info!("Time elapsed is {:?}", self.stats.timer.elapsed());
         info!(
-            "the size of data transmitted is {:?} bits, {:?} kb, {:?} mb",
+            "the size of data received is {} bytes, {} KiB, {} MiB",
             self.stats.size,
             self.stats.size / 1024,
             self.stats.size / 1024 / 1024
         );
-        info!(
-            "the throughput is {:?} mbps",
-            (self.stats.size) / 1024 / 1024 / (self.stats.timer.elapsed().as_secs())
-        );
+        // a flow younger than a second has a throughput too
+        let secs = self.stats.timer.elapsed().as_secs_f64();
+        if secs > 0.0 {
+            info!(
+                "the throughput is {:.3} Mbit/s",
+                self.stats.size as f64 * 8.0 / 1_000_000.0 / secs
+            );
+        }
+    }
+}
+
+/// MSS assumed when the peer sends no MSS option (RFC 879)
+pub const DEFAULT_MSS: u16 = 536;
+/// how long an ACK may wait for data to ride on, RFC 1122 S4.2.3.2 allows up to 500 ms
+pub const DELAYED_ACK: Duration = Duration::from_millis(200);
+/// 2 MSL, like Linux TCP_TIMEWAIT_LEN
+pub const TIME_WAIT: Duration = Duration::from_secs(60);
+/// retransmissions of data before the connection is given up, like Linux tcp_retries2
+pub const MAX_RETRIES: u32 = 15;
+/// retransmissions of a SYN or SYN-ACK before the connection is given up, like Linux tcp_syn_retries
+pub const MAX_SYN_RETRIES: u32 = 6;
+/// timeouts in a row after which full sized segments are suspected of not getting through
+const BLACK_HOLE_RETRIES: u32 = 2;
+/// a TCP header without options
+const TCP_HEADER_LEN: usize = 20;
+
+/// Answer `tcph` with a RST: its ACK is our sequence number, without one we acknowledge the
+/// segment instead so the peer believes us (RFC 793 S3.4, Reset Generation). `data_len` is the
+/// length of its payload and `quad` names the peer first. A RST is never answered, and neither
+/// is a segment sent to a broadcast or multicast address (RFC 1122 S4.2.3.10).
+pub fn reset(
+    out: &mut Output,
+    quad: &Quad,
+    tcph: &etherparse::TcpHeaderSlice,
+    data_len: usize,
+) -> io::Result<()> {
+    let broadcast = matches!(quad.dst.0, IpAddr::V4(a) if a.is_broadcast());
+    if tcph.rst() || broadcast || quad.dst.0.is_multicast() {
+        return Ok(());
+    }
+    let mut tcp = if tcph.ack() {
+        etherparse::TcpHeader::new(quad.dst.1, quad.src.1, tcph.acknowledgment_number(), 0)
+    } else {
+        let mut tcp = etherparse::TcpHeader::new(quad.dst.1, quad.src.1, 0, 0);
+        let len = data_len as u32 + tcph.syn() as u32 + tcph.fin() as u32;
+        tcp.ack = true;
+        tcp.acknowledgment_number = tcph.sequence_number().wrapping_add(len);
+        tcp
+    };
+    tcp.rst = true;
+    let mut ip = ip::Header::new(quad.dst.0, quad.src.0, ip::PROTOCOL_TCP);
+    ip.set_payload_len(tcp.header_len() as usize)?;
+    tcp.checksum = ip.tcp_checksum(&tcp, &[])?;
+    let mut buf = Vec::with_capacity(ip.header_len() + tcp.header_len() as usize);
+    ip.write(&mut buf)?;
+    tcp.write(&mut buf)?;
+    out.transmit(&buf);
+    out.counters.out_segs += 1;
+    out.counters.out_rsts += 1;
+    Ok(())
+}
+
+/// The MSS option carried by a SYN, or `DEFAULT_MSS` if there is none.
+pub fn mss_option(tcph: &etherparse::TcpHeaderSlice) -> u16 {
+    match options::find(tcph.options(), options::KIND_MSS) {
+        Some(&[hi, lo]) => u16::from_be_bytes([hi, lo]),
+        _ => DEFAULT_MSS,
     }
 }
 
@@ -1,171 +1,885 @@
+pub mod congestion;
+pub mod fastopen;
 pub mod flow;
+pub mod options;
+pub mod output;
+pub mod pmtu;
+pub mod ports;
+pub mod rtt;
+pub mod snapshot;
+pub mod syncookie;
+pub mod timer;
 
-use std::collections::{HashMap, VecDeque};
-use std::net::Ipv4Addr;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
+use crate::icmp;
+use crate::icmpv6;
+use crate::ip;
 use crate::nic;
-use crate::nic::Interface;
+use crate::nic::NetDevice;
+use crate::reassembly;
+use crate::udp;
+use output::DropReason;
 use std::collections::hash_map::Entry;
 use std::collections::HashSet;
 use std::io;
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
 
-pub struct tcp {
+/// default number of half-open (SynRcvd) flows before SYN cookies kick in
+pub const DEFAULT_SYN_BACKLOG: usize = 128;
+
+/// A TCP stack on top of the device `D`, a tun device unless told otherwise
+pub struct tcp<D: NetDevice = nic::Interface> {
     flow_table: HashMap<flow::Quad, flow::flow>, // the mapping from the Quad to the flow
     listening: HashSet<u16>,                     // the mapping from the port
-    pub nic: nic::Interface,
+    syn_backlog: usize,                          // the size of the SYN queue
+    embryonic: usize,                            // the flows in SynRcvd, in the SYN queue
+    syncookies: syncookie::SynCookies,
+    fastopen: fastopen::FastOpen,
+    ecn: bool,                              // whether to negotiate ECN
+    reassembly: reassembly::Reassembler,    // IPv4 fragments waiting for the rest
+    udp: udp::Udp,                          // the bound UDP ports
+    ports: ports::EphemeralPorts,           // local ports for outgoing connections
+    addresses: Vec<IpAddr>,                 // more addresses we accept packets for
+    promiscuous: bool,                      // whether to accept packets for any address
+    timers: timer::TimerWheel<flow::Quad>,  // the flows with a timer running
+    out: output::Output,                    // what the flows want sent, told and counted
+    drop_hook: Option<DropHook>,            // told about every packet that is dropped
+    errors: HashMap<flow::Quad, io::Error>, // errors of closed flows the application hasn't taken
+    pub ip: Ipv4Addr,                       // our address
+    pub ip6: Option<Ipv6Addr>,              // our IPv6 address, if we have one
+    pub nic: D,
+}
+
+/// Called with the reason and the packet whenever the stack drops one
+pub type DropHook = Box<dyn FnMut(DropReason, &[u8]) + Send>;
+
+/// What the stack has counted since it started, the TCP ones named after the MIB (RFC 4022)
+#[derive(Clone, Debug, Default)]
+pub struct Counters {
+    /// connections we opened, CLOSED to SYN-SENT
+    pub active_opens: u64,
+    /// connections we accepted, LISTEN to SYN-RCVD or a valid SYN cookie
+    pub passive_opens: u64,
+    /// handshakes that ended in CLOSED
+    pub attempt_fails: u64,
+    /// ESTABLISHED or CLOSE-WAIT connections that ended without closing, on a timeout or an ICMP error
+    pub estab_resets: u64,
+    /// TCP segments received, those in error included
+    pub in_segs: u64,
+    /// TCP segments sent, retransmissions included
+    pub out_segs: u64,
+    /// TCP segments sent again
+    pub retrans_segs: u64,
+    /// TCP segments received with a bad checksum or header
+    pub in_errs: u64,
+    /// RSTs sent, for segments nobody takes and for aborted connections
+    pub out_rsts: u64,
+    /// packets dropped, indexed by `DropReason`
+    pub(crate) drops: [u64; DropReason::ALL.len()],
+}
+
+impl Counters {
+    /// Packets dropped for `reason`
+    pub fn dropped(&self, reason: DropReason) -> u64 {
+        self.drops[reason as usize]
+    }
+
+    /// Write the counters in the Prometheus text format, `curr_estab` is the ESTABLISHED and
+    /// CLOSE-WAIT connections right now
+    pub fn prometheus(&self, curr_estab: usize) -> String {
+        let counters = [
+            (
+                "tcp_active_opens_total",
+                "Connections opened by the stack.",
+                self.active_opens,
+            ),
+            (
+                "tcp_passive_opens_total",
+                "Connections accepted by the stack.",
+                self.passive_opens,
+            ),
+            (
+                "tcp_attempt_fails_total",
+                "Handshakes that failed.",
+                self.attempt_fails,
+            ),
+            (
+                "tcp_estab_resets_total",
+                "Established connections that were reset or timed out.",
+                self.estab_resets,
+            ),
+            (
+                "tcp_in_segs_total",
+                "TCP segments received, those in error included.",
+                self.in_segs,
+            ),
+            (
+                "tcp_out_segs_total",
+                "TCP segments sent, retransmissions included.",
+                self.out_segs,
+            ),
+            (
+                "tcp_retrans_segs_total",
+                "TCP segments retransmitted.",
+                self.retrans_segs,
+            ),
+            (
+                "tcp_in_errs_total",
+                "TCP segments received in error.",
+                self.in_errs,
+            ),
+            (
+                "tcp_out_rsts_total",
+                "TCP segments sent with the RST flag.",
+                self.out_rsts,
+            ),
+        ];
+        let mut text = String::new();
+        for (name, help, value) in counters.iter() {
+            text += &format!(
+                "# HELP {0} {1}\n# TYPE {0} counter\n{0} {2}\n",
+                name, help, value
+            );
+        }
+        text += "# HELP tcp_curr_estab Connections in ESTABLISHED or CLOSE-WAIT.\n";
+        text += "# TYPE tcp_curr_estab gauge\n";
+        text += &format!("tcp_curr_estab {}\n", curr_estab);
+        text += "# HELP packets_dropped_total Packets the stack dropped, by reason.\n";
+        text += "# TYPE packets_dropped_total counter\n";
+        for reason in DropReason::ALL.iter() {
+            text += &format!(
+                "packets_dropped_total{{reason=\"{}\"}} {}\n",
+                reason.name(),
+                self.dropped(*reason)
+            );
+        }
+        text
+    }
 }
 
 pub enum control_message {
     Bind(u16),
-    Connect(u16, Ipv4Addr, u16),
+    /// connect from a local port to a remote address and port, local port 0 picks an ephemeral one
+    Connect(u16, IpAddr, u16),
+    /// connect and send the data, in the SYN if we hold a Fast Open cookie for the server
+    FastOpen(u16, IpAddr, u16, Vec<u8>),
     Read,
     Write,
 }
 
 impl tcp {
+    /// A stack with address `ip` on the tun device "tun0"
     pub fn new(ip: Ipv4Addr) -> io::Result<Option<Self>> {
-        let mut tcp_instance = tcp {
+        Ok(Some(tcp::with_device(nic::Interface::new("tun0")?, ip)))
+    }
+}
+
+impl<D: NetDevice> tcp<D> {
+    /// A stack with address `ip` on any device
+    pub fn with_device(nic: D, ip: Ipv4Addr) -> Self {
+        tcp {
             flow_table: Default::default(),
             listening: Default::default(),
-            nic: Interface::new(ip)?,
-        };
-        Ok(Some(tcp_instance))
+            syn_backlog: DEFAULT_SYN_BACKLOG,
+            embryonic: 0,
+            syncookies: syncookie::SynCookies::new(Instant::now()),
+            fastopen: fastopen::FastOpen::new(),
+            ecn: false,
+            reassembly: reassembly::Reassembler::new(),
+            udp: udp::Udp::new(),
+            ports: ports::EphemeralPorts::new(),
+            ip,
+            ip6: None,
+            addresses: Vec::new(),
+            promiscuous: false,
+            timers: timer::TimerWheel::new(Instant::now()),
+            out: output::Output::new(Instant::now(), nic.mtu()),
+            drop_hook: None,
+            errors: HashMap::new(),
+            nic,
+        }
+    }
+
+    /// Give the stack an IPv6 address as well, listeners accept on both and `Connect` reaches IPv6 peers.
+    pub fn set_ip6(&mut self, ip6: Ipv6Addr) {
+        self.ip6 = Some(ip6);
+    }
+
+    /// Accept packets sent to `addr` as well. Connections we open still come from `ip` and `ip6`,
+    /// connections to `addr` are answered from `addr`.
+    pub fn add_address(&mut self, addr: IpAddr) {
+        if !self.addresses.contains(&addr) {
+            self.addresses.push(addr);
+        }
+    }
+
+    pub fn remove_address(&mut self, addr: IpAddr) {
+        self.addresses.retain(|a| *a != addr);
+    }
+
+    /// Accept packets whatever their destination address, for debugging.
+    pub fn set_promiscuous(&mut self, enabled: bool) {
+        self.promiscuous = enabled;
+    }
+
+    /// whether `addr` is one of our addresses
+    fn is_local(&self, addr: IpAddr) -> bool {
+        match addr {
+            IpAddr::V4(a) if a == self.ip => true,
+            IpAddr::V6(a) if Some(a) == self.ip6 => true,
+            _ => self.addresses.contains(&addr),
+        }
+    }
+
+    /// Set how many half-open flows are kept before answering SYNs with cookies.
+    /// A backlog of 0 answers every SYN with a cookie.
+    pub fn set_syn_backlog(&mut self, backlog: usize) {
+        self.syn_backlog = backlog;
+    }
+
+    /// Let listeners issue Fast Open cookies and accept data in SYNs.
+    pub fn set_fastopen(&mut self, enabled: bool) {
+        self.fastopen.enabled = enabled;
+    }
+
+    /// Ask for ECN on connections we open and agree to it on connections we accept.
+    pub fn set_ecn(&mut self, enabled: bool) {
+        self.ecn = enabled;
+    }
+
+    /// Limit the memory IPv4 reassembly may use and how long it waits for missing fragments.
+    pub fn set_reassembly_limits(&mut self, max_memory: usize, timeout: Duration) {
+        self.reassembly.max_memory = max_memory;
+        self.reassembly.timeout = timeout;
+    }
+
+    /// Take the local ports of outgoing connections from `range`, by default 49152-65535.
+    pub fn set_ephemeral_ports(&mut self, range: RangeInclusive<u16>) -> io::Result<()> {
+        self.ports.set_range(range)
+    }
+
+    /// whether the SYN queue is full and new SYNs should be answered with cookies
+    fn syn_queue_full(&self) -> bool {
+        self.embryonic >= self.syn_backlog
+    }
+
+    /// Put the next timer of `quad` on the wheel, unless an earlier one is already there
+    fn schedule(&mut self, quad: &flow::Quad) {
+        if let Some(f) = self.flow_table.get_mut(quad) {
+            if let Some(at) = f.timers.next() {
+                if f.timers.scheduled.is_none_or(|s| at < s) {
+                    f.timers.scheduled = Some(at);
+                    self.timers.schedule(at, *quad);
+                }
+            }
+        }
     }
-    pub fn action(&mut self, buf: &[u8], nbytes: usize) {
+
+    /// Forget the flow of `quad` once it is closed, so the quad may be used again, otherwise
+    /// put its next timer on the wheel. The error of a flow the application knows about is
+    /// kept for `take_error`. Keeps the count of flows in the SYN queue up to date too.
+    fn settle(&mut self, quad: &flow::Quad) {
+        if let Some(f) = self.flow_table.get_mut(quad) {
+            let embryonic = f.state == flow::State::SynRcvd;
+            if embryonic != f.embryonic {
+                f.embryonic = embryonic;
+                if embryonic {
+                    self.embryonic += 1;
+                } else {
+                    self.embryonic -= 1;
+                }
+            }
+        }
+        match self.flow_table.get_mut(quad) {
+            Some(f) if f.state == flow::State::Closed => {
+                if let (true, Some(e)) = (f.accepted, f.error.take()) {
+                    self.errors.insert(*quad, e);
+                }
+                self.flow_table.remove(quad);
+            }
+            Some(_) => self.schedule(quad),
+            None => {}
+        }
+    }
+
+    /// When `poll_timers` has work to do next, if any timer is running
+    pub fn next_timeout(&self) -> Option<Instant> {
+        match (self.timers.next_deadline(), self.reassembly.next_deadline()) {
+            (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Run the timers that are due at `now`: retransmissions, delayed ACKs, the end of TIME-WAIT
+    /// and the reassembly timeout. `now` is normally `Instant::now()`, a later time lets tests
+    /// skip ahead.
+    pub fn poll_timers(&mut self, now: Instant) -> io::Result<()> {
+        self.begin(now);
+        self.reassembly.expire(now);
+        self.reassembly_dropped();
+        for quad in self.timers.expire(now) {
+            let f = match self.flow_table.get_mut(&quad) {
+                Some(f) => f,
+                None => continue,
+            };
+            f.timers.scheduled = None;
+            f.on_timer(&mut self.out)?;
+            self.settle(&quad);
+        }
+        self.transmit();
+        Ok(())
+    }
+
+    /// Start handling something that happens at `now`
+    fn begin(&mut self, now: Instant) {
+        self.out.now = now;
+        self.out.mtu = self.nic.mtu();
+        self.nic.set_now(now);
+    }
+
+    /// Send what the flows queued. A packet the device won't take is dropped like one that
+    /// was lost on the way, the flows send it again if it matters.
+    fn transmit(&mut self) {
+        while let Some(packet) = self.out.packets.pop_front() {
+            if let Err(e) = self.nic.send(&packet) {
+                warn!("couldn't send a packet: {}", e);
+                let reason = DropReason::SendFailed;
+                self.out.counters.drops[reason as usize] += 1;
+                if let Some(hook) = self.drop_hook.as_mut() {
+                    hook(reason, &packet);
+                }
+            }
+        }
+    }
+
+    /// The next thing that happened to a flow, if the application hasn't been told yet
+    pub fn poll_event(&mut self) -> Option<output::Event> {
+        self.out.events.pop_front()
+    }
+
+    /// Handle the first `nbytes` of `buf`, a packet the device just received
+    pub fn action(&mut self, buf: &[u8], nbytes: usize) -> io::Result<()> {
+        self.input(Instant::now(), &buf[..nbytes])
+    }
+
+    /// Handle `packet`, which arrived at `now`
+    pub fn input(&mut self, now: Instant, packet: &[u8]) -> io::Result<()> {
+        self.begin(now);
+        self.out.dropped = None;
+        let result = self.handle(packet, packet.len());
+        if let Some(reason) = self.out.dropped.take() {
+            debug!("dropped a packet: {}", reason.name());
+            if let Some(hook) = self.drop_hook.as_mut() {
+                hook(reason, packet);
+            }
+        }
+        self.transmit();
+        result
+    }
+
+    /// Call `hook` with the reason and the packet whenever a packet is dropped, for tracing.
+    /// The counters in `counters()` count drops either way.
+    pub fn set_drop_hook(&mut self, hook: impl FnMut(DropReason, &[u8]) + Send + 'static) {
+        self.drop_hook = Some(Box::new(hook));
+    }
+
+    fn handle(&mut self, buf: &[u8], nbytes: usize) -> io::Result<()> {
+        // a corrupt segment must not drive the state machine or end up in the received data
+        let verify = !self.nic.capabilities().checksum_offload;
+        if verify && !ip::header_checksum_ok(&buf[..nbytes]) {
+            self.out.discard(DropReason::IpChecksum);
+            return Ok(());
+        }
+        // a tun device also hands us whatever is routed through it
+        match ip::parse(&buf[..nbytes]) {
+            Some(iph) if !self.promiscuous && !self.is_local(iph.dst) => {
+                self.out.discard(DropReason::NotForUs);
+                return Ok(());
+            }
+            _ => {}
+        }
+        if reassembly::is_fragment(&buf[..nbytes]) {
+            let input = self.reassembly.input(self.out.now, &buf[..nbytes]);
+            self.reassembly_dropped();
+            match input {
+                reassembly::Input::Complete(packet) => return self.handle(&packet, packet.len()),
+                reassembly::Input::Pending => {}
+                reassembly::Input::Malformed => self.out.discard(DropReason::FragmentMalformed),
+                reassembly::Input::Overlap => self.out.discard(DropReason::FragmentOverlap),
+            }
+            return Ok(());
+        }
         // is it a good choice to leave nic here?
-        match etherparse::Ipv4HeaderSlice::from_slice(&buf[..nbytes]) {
-            Ok(iph) => {
+        match ip::parse(&buf[..nbytes]) {
+            Some(iph) => {
                 // println!("An ip packet!");
-                let src = iph.source_addr();
-                let dst = iph.destination_addr();
+                let src = iph.src;
+                let dst = iph.dst;
 
-                if iph.protocol() != 0x06 {
-                    //debug!("Not TCP");
-                    return;
+                match (iph.protocol, src) {
+                    (icmp::PROTOCOL, IpAddr::V4(_)) | (icmpv6::PROTOCOL, IpAddr::V6(_)) => {
+                        return self.icmp_action(&iph, verify);
+                    }
+                    (ip::PROTOCOL_UDP, _) => {
+                        match self.udp.input(&iph, verify) {
+                            udp::Input::Unbound => {
+                                self.out.discard(DropReason::UdpNoPort);
+                                match src {
+                                    IpAddr::V4(_) => icmp::port_unreachable(&mut self.out, &iph)?,
+                                    IpAddr::V6(_) => icmpv6::port_unreachable(&mut self.out, &iph)?,
+                                }
+                            }
+                            udp::Input::BadChecksum => self.out.discard(DropReason::UdpChecksum),
+                            udp::Input::Malformed => self.out.discard(DropReason::UdpMalformed),
+                            udp::Input::QueueFull => self.out.discard(DropReason::UdpQueueFull),
+                            udp::Input::Queued => {}
+                        }
+                        return Ok(());
+                    }
+                    (ip::PROTOCOL_TCP, _) => {
+                        self.out.counters.in_segs += 1;
+                        if verify && ip::checksum(src, dst, ip::PROTOCOL_TCP, iph.payload) != 0 {
+                            self.out.discard(DropReason::TcpChecksum);
+                            self.out.counters.in_errs += 1;
+                            return Ok(());
+                        }
+                    }
+                    _ => {
+                        self.out.discard(DropReason::UnknownProtocol);
+                        return Ok(());
+                    }
                 }
-                match etherparse::TcpHeaderSlice::from_slice(&buf[iph.slice().len()..nbytes]) {
+                match etherparse::TcpHeaderSlice::from_slice(iph.payload) {
                     Ok(tcph) => {
                         let q = flow::Quad {
                             src: (src, tcph.source_port()),
                             dst: (dst, tcph.destination_port()),
                         };
-                        let idata = (iph.slice().len() + tcph.slice().len());
+                        let data = &iph.payload[tcph.slice().len()..];
+                        let syn_queue_full = tcph.syn() && self.syn_queue_full();
                         match self.flow_table.entry(q) {
                             Entry::Occupied(mut f) => {
                                 // debug!("got packet for known quad {:?}", q);
-                                match f.get_mut().state {
-                                    flow::State::SynRcvd => {
-                                        f.get_mut().SynRcvd_handler(
-                                            &mut self.nic,
-                                            tcph,
-                                            &buf[idata..nbytes],
-                                        );
-                                    }
-                                    flow::State::Estab => {
-                                        f.get_mut().Estab_handler(
-                                            &mut self.nic,
-                                            tcph,
-                                            &buf[idata..nbytes],
-                                        );
-                                    }
-                                    flow::State::CloseWait => {
-                                        f.get_mut().Closed_handler();
-                                    }
-                                    flow::State::LastAck => {
-                                        f.get_mut().LastAck_handler(&mut self.nic, tcph);
-                                    }
-                                    flow::State::TimeWait => {
-                                        f.get_mut().TimeWait_handler();
-                                    }
-
-                                    flow::State::FinWait1 => {
-                                        f.get_mut().FinWait1_handler();
-                                    }
-                                    flow::State::FinWait2 => {
-                                        f.get_mut().FinWait2_handler();
-                                    }
-                                    flow::State::Closed => {
-                                        f.get_mut().Closed_handler();
-                                    }
+                                let cookie = match f.get().state {
                                     flow::State::SynSent => {
-                                        f.get_mut().SynSent_handler(&mut self.nic, tcph);
+                                        options::find(tcph.options(), options::KIND_FASTOPEN)
                                     }
+                                    _ => None,
+                                };
+                                f.get_mut().on_segment(&mut self.out, tcph.clone(), data)?;
+                                if let (flow::State::Estab, Some(cookie)) = (f.get().state, cookie)
+                                {
+                                    self.fastopen.remember(src, cookie);
+                                }
+                                // after the handler so a CWR in the same segment doesn't cancel the echo
+                                if iph.ecn == flow::ECN_CE {
+                                    f.get_mut().congestion_experienced();
                                 }
                             }
                             Entry::Vacant(e) => {
                                 // debug!("got packet for unknown quad {:?}", q);
-                                if self.listening.contains(&q.dst.1) {
+                                if !self.listening.contains(&q.dst.1) {
+                                    self.out.discard(DropReason::NoSocket);
+                                    flow::reset(&mut self.out, &q, &tcph, data.len())?;
+                                } else if syn_queue_full {
+                                    // answer without keeping any state, the flow is rebuilt from the final ACK
+                                    let cookie = self.syncookies.generate(
+                                        &q,
+                                        tcph.sequence_number(),
+                                        flow::mss_option(&tcph),
+                                        self.out.now,
+                                    );
+                                    flow::flow::passive_three_way_handshake(
+                                        &mut self.out,
+                                        &iph,
+                                        tcph,
+                                        cookie,
+                                        &[],
+                                        None,
+                                        false,
+                                    )?;
+                                } else if tcph.syn() {
+                                    // Fast Open: take the data with a valid cookie, otherwise hand one out
+                                    let mut syn_data: &[u8] = &[];
+                                    let mut reply = None;
+                                    if self.fastopen.enabled {
+                                        match options::find(tcph.options(), options::KIND_FASTOPEN)
+                                        {
+                                            Some(cookie) if self.fastopen.validate(src, cookie) => {
+                                                syn_data = data;
+                                            }
+                                            Some(_) => reply = Some(self.fastopen.cookie(src)),
+                                            None => {}
+                                        }
+                                    }
                                     if let Some(new_f) = flow::flow::passive_three_way_handshake(
-                                        &mut self.nic,
-                                        iph,
+                                        &mut self.out,
+                                        &iph,
                                         tcph,
-                                    )
-                                    .unwrap()
-                                    {
+                                        0,
+                                        syn_data,
+                                        reply.as_ref().map(|c| &c[..]),
+                                        self.ecn,
+                                    )? {
+                                        self.out.counters.passive_opens += 1;
+                                        e.insert(new_f);
+                                    }
+                                } else if tcph.ack() && !tcph.rst() {
+                                    // may be the final ACK of a handshake we answered with a cookie
+                                    let cookie = tcph.acknowledgment_number().wrapping_sub(1);
+                                    if let Some(mss) = self.syncookies.validate(
+                                        &q,
+                                        tcph.sequence_number().wrapping_sub(1),
+                                        cookie,
+                                        self.out.now,
+                                    ) {
+                                        debug!("valid SYN cookie for {:?}", q);
+                                        let mut new_f = flow::flow::from_syn_cookie(
+                                            &iph, &tcph, cookie, mss, &self.out,
+                                        );
+                                        self.out.counters.passive_opens += 1;
+                                        new_f.on_segment(&mut self.out, tcph, data)?;
                                         e.insert(new_f);
-                                        return;
                                     } else {
-                                    };
+                                        // an ACK to a listener is refused (RFC 793 S3.9, LISTEN STATE)
+                                        self.out.discard(DropReason::NoSocket);
+                                        flow::reset(&mut self.out, &q, &tcph, data.len())?;
+                                    }
                                 } else {
-                                    //debug!("not listening, so dropping ...");
+                                    self.out.discard(DropReason::NoSocket);
                                 }
                             }
                         }
+                        self.settle(&q);
                     }
-                    Err(e) => {
-                        //debug!("ignoring weird tcp packet {:?}", e);
+                    Err(_e) => {
+                        self.out.discard(DropReason::TcpMalformed);
+                        self.out.counters.in_errs += 1;
                     }
                 }
             }
-            Err(e) => {
-                //debug!("ignoring weird tcp packet {:?}", e);
+            None => self.out.discard(DropReason::IpMalformed),
+        }
+        Ok(())
+    }
+    /// Count the datagrams reassembly gave up on and show them to the drop hook.
+    /// They aren't the packet being handled, the hook gets the data received of them.
+    fn reassembly_dropped(&mut self) {
+        for (dropped, data) in self.reassembly.take_dropped() {
+            let reason = match dropped {
+                reassembly::Dropped::TimedOut => DropReason::FragmentTimeout,
+                reassembly::Dropped::Evicted => DropReason::FragmentMemory,
+            };
+            self.out.counters.drops[reason as usize] += 1;
+            if let Some(hook) = self.drop_hook.as_mut() {
+                hook(reason, &data);
             }
         }
     }
-    pub fn control(&mut self, message: control_message) {
+
+    fn icmp_action(&mut self, iph: &ip::Packet, verify: bool) -> io::Result<()> {
+        // a corrupt error must not cut the path MTU or abort a flow
+        let sum = match iph.src {
+            IpAddr::V4(_) => icmp::checksum(iph.payload),
+            IpAddr::V6(_) => icmpv6::checksum(iph.src, iph.dst, iph.payload),
+        };
+        if verify && sum != 0 {
+            self.out.discard(DropReason::IcmpChecksum);
+            return Ok(());
+        }
+        let message = match iph.src {
+            IpAddr::V4(_) => icmp::parse(iph.payload),
+            IpAddr::V6(_) => icmpv6::parse(iph.payload),
+        };
+        match message {
+            Some(icmp::Message::EchoRequest) => {
+                match iph.src {
+                    IpAddr::V4(_) => icmp::echo_reply(&mut self.out, iph, iph.payload)?,
+                    IpAddr::V6(_) => icmpv6::echo_reply(&mut self.out, iph, iph.payload)?,
+                };
+            }
+            Some(icmp::Message::Unreachable { error, quad, seq }) => {
+                match self.flow_table.get_mut(&quad) {
+                    Some(f) => {
+                        f.icmp_error(&mut self.out, error, seq)?;
+                        self.settle(&quad);
+                    }
+                    None => self.out.discard(DropReason::IcmpNoSocket),
+                }
+            }
+            None => self.out.discard(DropReason::IcmpUnhandled),
+        }
+        Ok(())
+    }
+
+    pub fn control(&mut self, message: control_message) -> io::Result<()> {
         match message {
             control_message::Bind(port) => {
                 self.listening.insert(port);
                 debug!("bind port number {}", port)
             }
             control_message::Connect(src_port, dst_ip, dst_port) => {
-                let q = flow::Quad {
-                    dst: (self.nic.ip, src_port),
-                    src: (dst_ip, dst_port),
-                };
-                match self.flow_table.entry(q) {
-                    Entry::Occupied(mut f) => {
-                        debug!("already have the flow");
-                    }
-                    Entry::Vacant(e) => {
-                        // create a flow
-                        if let Some(new_f) =
-                            flow::flow::active_three_way_handshake(&mut self.nic, &q).unwrap()
-                        {
-                            e.insert(new_f);
-                            return;
-                        } else {
-                        };
-                    }
-                }
+                self.open(src_port, dst_ip, dst_port, &[], None)?;
+            }
+            control_message::FastOpen(src_port, dst_ip, dst_port, data) => {
+                // without a cookie this asks the server for one and sends the data after the handshake
+                let cookie = self.fastopen.cached(dst_ip).unwrap_or(&[]).to_vec();
+                self.open(src_port, dst_ip, dst_port, &data, Some(&cookie))?;
             }
             control_message::Read => unimplemented!(),
             control_message::Write => unimplemented!(),
         }
+        Ok(())
+    }
+
+    /// Connect to `dst_ip`:`dst_port` from an ephemeral port, returns the quad of the new flow.
+    pub fn connect(&mut self, dst_ip: IpAddr, dst_port: u16) -> io::Result<flow::Quad> {
+        self.open(0, dst_ip, dst_port, &[], None)
+    }
+
+    /// Start a connection from `src_port`, 0 for an ephemeral one
+    fn open(
+        &mut self,
+        mut src_port: u16,
+        dst_ip: IpAddr,
+        dst_port: u16,
+        data: &[u8],
+        fastopen: Option<&[u8]>,
+    ) -> io::Result<flow::Quad> {
+        let local = self.local_addr(dst_ip)?;
+        let quad = |port| flow::Quad {
+            dst: (local, port),
+            src: (dst_ip, dst_port),
+        };
+        let (listening, flow_table) = (&self.listening, &self.flow_table);
+        let in_use = |port| listening.contains(&port) || flow_table.contains_key(&quad(port));
+        if src_port == 0 {
+            src_port = self
+                .ports
+                .select(local, (dst_ip, dst_port), in_use)
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::AddrNotAvailable, "no ephemeral port left")
+                })?;
+        } else if in_use(src_port) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "local port is listening or already connected to this peer",
+            ));
+        }
+        let q = quad(src_port);
+        self.begin(Instant::now());
+        match self.flow_table.entry(q) {
+            Entry::Occupied(_f) => unreachable!("checked by in_use"),
+            Entry::Vacant(e) => {
+                // create a flow
+                if let Some(new_f) = flow::flow::active_three_way_handshake(
+                    &mut self.out,
+                    &q,
+                    data,
+                    fastopen,
+                    self.ecn,
+                )? {
+                    self.out.counters.active_opens += 1;
+                    self.errors.remove(&q);
+                    e.insert(new_f);
+                }
+            }
+        }
+        self.schedule(&q);
+        self.transmit();
+        Ok(q)
+    }
+
+    /// Our address of the same family as `peer`
+    fn local_addr(&self, peer: IpAddr) -> io::Result<IpAddr> {
+        match peer {
+            IpAddr::V4(_) => Ok(IpAddr::V4(self.ip)),
+            IpAddr::V6(_) => self.ip6.map(IpAddr::V6).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    "no IPv6 address, see set_ip6",
+                )
+            }),
+        }
+    }
+
+    /// Bind the UDP port `port` so datagrams sent to it are kept for `recv_from`.
+    pub fn udp_bind(&mut self, port: u16) -> io::Result<()> {
+        self.udp.bind(port)
+    }
+
+    /// Unbind the UDP port `port`.
+    pub fn udp_close(&mut self, port: u16) {
+        self.udp.close(port)
+    }
+
+    /// Send `data` in one UDP datagram from our port `port` to `dst`.
+    pub fn send_to(&mut self, port: u16, dst: (IpAddr, u16), data: &[u8]) -> io::Result<usize> {
+        let src = (self.local_addr(dst.0)?, port);
+        self.begin(Instant::now());
+        let n = udp::send_to(&mut self.out, src, dst, data)?;
+        self.transmit();
+        Ok(n)
+    }
+
+    /// Read the next datagram that arrived on the bound UDP port `port`, with its sender.
+    pub fn recv_from(&mut self, port: u16, buf: &mut [u8]) -> io::Result<(usize, (IpAddr, u16))> {
+        self.udp.recv_from(port, buf)
+    }
+
+    /// Queue `data` for transmission on the flow `quad`.
+    /// Like `flow_table`, `quad.src` is the peer and `quad.dst` is us.
+    pub fn write(&mut self, quad: &flow::Quad, data: &[u8]) -> io::Result<usize> {
+        self.begin(Instant::now());
+        let n = match self.flow_table.get_mut(quad) {
+            Some(f) => f.send(&mut self.out, data, false),
+            None => Err(not_connected()),
+        }?;
+        self.schedule(quad);
+        self.transmit();
+        Ok(n)
+    }
+
+    /// Queue `data` as urgent data: the urgent pointer is advanced to the end of `data`.
+    pub fn write_urgent(&mut self, quad: &flow::Quad, data: &[u8]) -> io::Result<usize> {
+        self.begin(Instant::now());
+        let n = match self.flow_table.get_mut(quad) {
+            Some(f) => f.send(&mut self.out, data, true),
+            None => Err(not_connected()),
+        }?;
+        self.schedule(quad);
+        self.transmit();
+        Ok(n)
+    }
+
+    /// Close our side of `quad`: the FIN follows the data that is still queued.
+    pub fn close(&mut self, quad: &flow::Quad) -> io::Result<()> {
+        self.begin(Instant::now());
+        match self.flow_table.get_mut(quad) {
+            Some(f) => f.close(&mut self.out)?,
+            None => return Err(not_connected()),
+        }
+        self.settle(quad);
+        self.transmit();
+        Ok(())
+    }
+
+    /// Drop `quad` at once, the peer gets a RST if it is still connected, like SO_LINGER
+    /// with a zero timeout. Data that is queued or unread is thrown away.
+    pub fn abort(&mut self, quad: &flow::Quad) -> io::Result<()> {
+        self.begin(Instant::now());
+        match self.flow_table.get_mut(quad) {
+            Some(f) => f.abort(&mut self.out)?,
+            None => return Err(not_connected()),
+        }
+        self.settle(quad);
+        self.transmit();
+        Ok(())
+    }
+
+    /// A connection to the listening port `port` that completed its handshake and hasn't
+    /// been handed out yet, like accept(2)
+    pub fn accept(&mut self, port: u16) -> Option<flow::Quad> {
+        let f = self
+            .flow_table
+            .values_mut()
+            .find(|f| waiting_for_accept(f, port))?;
+        f.accepted = true;
+        Some(f.quad)
+    }
+
+    /// What the stack has counted since it started
+    pub fn counters(&self) -> &Counters {
+        &self.out.counters
+    }
+
+    /// The counters in the Prometheus text format, for a metrics endpoint to serve
+    pub fn prometheus(&self) -> String {
+        let curr_estab = self
+            .flow_table
+            .values()
+            .filter(|f| matches!(f.state, flow::State::Estab | flow::State::CloseWait))
+            .count();
+        self.out.counters.prometheus(curr_estab)
+    }
+
+    /// The listeners and the flows with their state, queues, timers and counters, like `ss -tanpi`
+    pub fn snapshot(&self) -> snapshot::Snapshot {
+        let now = Instant::now();
+        let mut listeners: Vec<_> = self
+            .listening
+            .iter()
+            .map(|&port| snapshot::Listener {
+                port,
+                accept_queue: self
+                    .flow_table
+                    .values()
+                    .filter(|f| waiting_for_accept(f, port))
+                    .count(),
+                syn_backlog: self.syn_backlog,
+            })
+            .collect();
+        listeners.sort_by_key(|l| l.port);
+        let mut flows: Vec<_> = self
+            .flow_table
+            .values()
+            .filter(|f| f.state != flow::State::Closed)
+            .map(|f| f.info(now))
+            .collect();
+        flows.sort_by_key(|i| (i.quad.dst, i.quad.src));
+        snapshot::Snapshot {
+            pid: std::process::id(),
+            listeners,
+            flows,
+        }
+    }
+
+    /// The flow of `quad`, to look at its state
+    pub fn flow(&self, quad: &flow::Quad) -> Option<&flow::flow> {
+        self.flow_table.get(quad)
+    }
+
+    /// State, timers and counters of the flow `quad`, like getsockopt(TCP_INFO)
+    pub fn info(&self, quad: &flow::Quad) -> Option<flow::Info> {
+        self.flow_table.get(quad).map(|f| f.info(Instant::now()))
+    }
+
+    /// Read received data of the flow `quad` into `buf`.
+    pub fn read(&mut self, quad: &flow::Quad, buf: &mut [u8]) -> io::Result<usize> {
+        match self.flow_table.get_mut(quad) {
+            Some(f) => Ok(f.read(buf)),
+            None => Err(not_connected()),
+        }
+    }
+
+    /// Take the error an ICMP message or a timeout reported for `quad`, like SO_ERROR.
+    /// The error that closed a flow is still there after the flow is gone.
+    pub fn take_error(&mut self, quad: &flow::Quad) -> Option<io::Error> {
+        match self.flow_table.get_mut(quad) {
+            Some(f) => f.error.take(),
+            None => self.errors.remove(quad),
+        }
+    }
+
+    /// Number of unread bytes of `quad` up to and including the urgent data, if any is pending.
+    pub fn urgent_offset(&self, quad: &flow::Quad) -> Option<usize> {
+        self.flow_table.get(quad).and_then(|f| f.urgent_offset())
     }
 }
 
-fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
-    // From RFC1323:
-    //     TCP determines if a data segment is "old" or "new" by testing
-    //     whether its sequence number is within 2**31 bytes of the left edge
-    //     of the window, and if it is not, discarding the data as "old".  To
-    //     insure that new data is never mistakenly considered old and vice-
-    //     versa, the left edge of the sender's window has to be at most
-    //     2**31 away from the right edge of the receiver's window.
-    lhs.wrapping_sub(rhs) > (1 << 31)
+/// whether `f` completed its handshake on the listening port `port` and `accept` hasn't handed it out
+fn waiting_for_accept(f: &flow::flow, port: u16) -> bool {
+    !f.accepted
+        && f.quad.dst.1 == port
+        && matches!(
+            f.state,
+            flow::State::Estab | flow::State::CloseWait | flow::State::LastAck
+        )
 }
 
-fn is_between_wrapped(start: u32, x: u32, end: u32) -> bool {
-    wrapping_lt(start, x) && wrapping_lt(x, end)
+fn not_connected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "no such flow")
 }
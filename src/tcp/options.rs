@@ -0,0 +1,63 @@
+//! Raw TCP option parsing and encoding
+//!
+//! etherparse stops at the first option kind it doesn't know, which includes TCP Fast Open,
+//! so options are walked here as plain (kind, data) pairs.
+
+pub const KIND_END: u8 = 0;
+pub const KIND_NOP: u8 = 1;
+pub const KIND_MSS: u8 = 2;
+/// TCP Fast Open cookie option (RFC 7413 S4.1.1)
+pub const KIND_FASTOPEN: u8 = 34;
+
+/// Iterator over the (kind, data) pairs of an options field
+pub struct Options<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let kind = *self.rest.first()?;
+            match kind {
+                KIND_END => return None,
+                KIND_NOP => self.rest = &self.rest[1..],
+                _ => {
+                    // a malformed length ends the walk rather than yielding garbage
+                    let len = *self.rest.get(1)? as usize;
+                    if len < 2 || len > self.rest.len() {
+                        return None;
+                    }
+                    let data = &self.rest[2..len];
+                    self.rest = &self.rest[len..];
+                    return Some((kind, data));
+                }
+            }
+        }
+    }
+}
+
+pub fn iter(options: &[u8]) -> Options<'_> {
+    Options { rest: options }
+}
+
+/// The data of the first option of `kind`
+pub fn find(options: &[u8], kind: u8) -> Option<&[u8]> {
+    iter(options)
+        .find(|&(k, _)| k == kind)
+        .map(|(_, data)| data)
+}
+
+/// Encode an MSS option
+pub fn mss(mss: u16) -> Vec<u8> {
+    let [hi, lo] = mss.to_be_bytes();
+    vec![KIND_MSS, 4, hi, lo]
+}
+
+/// Encode a Fast Open option, an empty `cookie` is a cookie request
+pub fn fastopen(cookie: &[u8]) -> Vec<u8> {
+    let mut option = vec![KIND_FASTOPEN, 2 + cookie.len() as u8];
+    option.extend_from_slice(cookie);
+    option
+}
@@ -0,0 +1,179 @@
+//! # What flows produce
+//!
+//! Flows don't know about devices. Everything that happens to a flow, a segment arriving, a
+//! timer expiring or a call from the application, is handled at a time given in `Output::now`,
+//! and whatever the flow wants sent goes into `Output::packets` as a complete IP packet, next to
+//! the events the application should hear about. `Output::counters` adds it all up for the stack.
+//! Whoever drives the flows sends the packets: `tcp` hands them to its `NetDevice`, a test can
+//! look at them or lose some on purpose.
+use crate::tcp::flow::Quad;
+use crate::tcp::Counters;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// events kept for the application, the oldest are dropped when nobody takes them
+pub const MAX_EVENTS: usize = 1024;
+
+/// Something the application may want to know about a flow
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// the handshake completed
+    Established(Quad),
+    /// data arrived
+    Readable(Quad),
+    /// the peer sent its FIN, nothing more will arrive
+    PeerClosed(Quad),
+    /// the flow is closed, normally or for the error `tcp::take_error` returns
+    Closed(Quad),
+}
+
+/// Why a packet was dropped, like the kernel's skb drop reasons
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DropReason {
+    /// the IPv4 header checksum is wrong
+    IpChecksum,
+    /// the IP header doesn't parse
+    IpMalformed,
+    /// an IPv4 fragment too long or cut in the wrong place
+    FragmentMalformed,
+    /// an IPv4 fragment that overlaps the ones we have with other data, the whole datagram goes
+    FragmentOverlap,
+    /// the fragments of a datagram that didn't all arrive in time
+    FragmentTimeout,
+    /// the fragments of the oldest datagram, when reassembly ran out of memory
+    FragmentMemory,
+    /// sent to an address that isn't ours
+    NotForUs,
+    /// neither TCP, UDP nor ICMP
+    UnknownProtocol,
+    /// the ICMP checksum is wrong
+    IcmpChecksum,
+    /// an ICMP message that doesn't parse or that we don't act on
+    IcmpUnhandled,
+    /// an ICMP error about a flow we don't have
+    IcmpNoSocket,
+    /// the UDP checksum is wrong
+    UdpChecksum,
+    /// the UDP header doesn't parse
+    UdpMalformed,
+    /// nobody is bound to the UDP port, the peer gets an ICMP port unreachable
+    UdpNoPort,
+    /// the UDP port's queue is full
+    UdpQueueFull,
+    /// the TCP checksum is wrong
+    TcpChecksum,
+    /// the TCP header doesn't parse
+    TcpMalformed,
+    /// no flow has the segment and no listener takes it, a SYN cookie that doesn't check out included
+    NoSocket,
+    /// the segment is outside the receive window, or an ICMP error is about data that isn't in flight
+    OutOfWindow,
+    /// the segment acknowledges something we never sent
+    BadAck,
+    /// the flow is closed and takes no more segments
+    FlowClosed,
+    /// a packet of ours the device wouldn't send
+    SendFailed,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 22] = [
+        DropReason::IpChecksum,
+        DropReason::IpMalformed,
+        DropReason::FragmentMalformed,
+        DropReason::FragmentOverlap,
+        DropReason::FragmentTimeout,
+        DropReason::FragmentMemory,
+        DropReason::NotForUs,
+        DropReason::UnknownProtocol,
+        DropReason::IcmpChecksum,
+        DropReason::IcmpUnhandled,
+        DropReason::IcmpNoSocket,
+        DropReason::UdpChecksum,
+        DropReason::UdpMalformed,
+        DropReason::UdpNoPort,
+        DropReason::UdpQueueFull,
+        DropReason::TcpChecksum,
+        DropReason::TcpMalformed,
+        DropReason::NoSocket,
+        DropReason::OutOfWindow,
+        DropReason::BadAck,
+        DropReason::FlowClosed,
+        DropReason::SendFailed,
+    ];
+
+    /// The reason in snake case, as logs and metrics label it
+    pub fn name(self) -> &'static str {
+        match self {
+            DropReason::IpChecksum => "ip_checksum",
+            DropReason::IpMalformed => "ip_malformed",
+            DropReason::FragmentMalformed => "fragment_malformed",
+            DropReason::FragmentOverlap => "fragment_overlap",
+            DropReason::FragmentTimeout => "fragment_timeout",
+            DropReason::FragmentMemory => "fragment_memory",
+            DropReason::NotForUs => "not_for_us",
+            DropReason::UnknownProtocol => "unknown_protocol",
+            DropReason::IcmpChecksum => "icmp_checksum",
+            DropReason::IcmpUnhandled => "icmp_unhandled",
+            DropReason::IcmpNoSocket => "icmp_no_socket",
+            DropReason::UdpChecksum => "udp_checksum",
+            DropReason::UdpMalformed => "udp_malformed",
+            DropReason::UdpNoPort => "udp_no_port",
+            DropReason::UdpQueueFull => "udp_queue_full",
+            DropReason::TcpChecksum => "tcp_checksum",
+            DropReason::TcpMalformed => "tcp_malformed",
+            DropReason::NoSocket => "no_socket",
+            DropReason::OutOfWindow => "out_of_window",
+            DropReason::BadAck => "bad_ack",
+            DropReason::FlowClosed => "flow_closed",
+            DropReason::SendFailed => "send_failed",
+        }
+    }
+}
+
+/// The queues flows write to, and the time of what they are handling
+pub struct Output {
+    pub now: Instant,
+    /// the largest packet the link carries
+    pub mtu: u16,
+    /// packets to send, oldest first
+    pub packets: VecDeque<Vec<u8>>,
+    /// events for the application, oldest first
+    pub events: VecDeque<Event>,
+    /// what happened to all the flows, and to the packets around them
+    pub counters: Counters,
+    /// why the packet being handled was dropped, if it was
+    pub dropped: Option<DropReason>,
+}
+
+impl Output {
+    pub fn new(now: Instant, mtu: u16) -> Self {
+        Output {
+            now,
+            mtu,
+            packets: VecDeque::new(),
+            events: VecDeque::new(),
+            counters: Counters::default(),
+            dropped: None,
+        }
+    }
+
+    /// Queue `packet` for sending
+    pub fn transmit(&mut self, packet: &[u8]) {
+        self.packets.push_back(packet.to_vec());
+    }
+
+    /// Drop the packet being handled for `reason`
+    pub fn discard(&mut self, reason: DropReason) {
+        self.counters.drops[reason as usize] += 1;
+        self.dropped = Some(reason);
+    }
+
+    /// Tell the application about `event`
+    pub fn event(&mut self, event: Event) {
+        if self.events.len() == MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+}
@@ -0,0 +1,147 @@
+//! Path MTU discovery
+//!
+//! Segments go out with DF set and ICMP fragmentation needed lowers the PMTU (RFC 1191).
+//! On top of that the packetization layer searches for a larger PMTU with probes made of
+//! application data (RFC 4821, RFC 8899), and a path that silently drops full sized segments
+//! is treated as a black hole that takes the PMTU back down to `BASE_PMTU`.
+use std::time::{Duration, Instant};
+
+/// smallest MTU of an IPv4 link (RFC 791)
+pub const MIN_PMTU: u16 = 68;
+/// smallest MTU of an IPv6 link (RFC 8200 S5)
+pub const MIN_PMTU_V6: u16 = 1280;
+/// PMTU assumed to work on any path we fall back to on a black hole (RFC 8899 S5.1.2 BASE_PLPMTU)
+pub const BASE_PMTU: u16 = 1200;
+/// failed probes of one size before it is considered too big (RFC 8899 MAX_PROBES)
+const MAX_PROBES: u8 = 3;
+/// the search is done once the PMTU is this close to the upper bound
+const SEARCH_GRANULARITY: u16 = 32;
+/// after the search converged, look for a larger PMTU again this often (RFC 8899 PMTU_RAISE_TIMER)
+const RAISE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// plateaus to step down through when fragmentation needed has no next-hop MTU (RFC 1191 S7)
+const PLATEAUS: [u16; 10] = [32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68];
+
+pub struct Pmtu {
+    /// the PMTU segments are sized for
+    pub pmtu: u16,
+    /// MTU of the interface, no packet is ever larger
+    max: u16,
+    /// smallest MTU of any link of the IP version
+    min: u16,
+    /// largest size that may still work: lowered by ICMP and failed probes
+    search_high: u16,
+    /// the probe in flight: its size and the sequence number following its data
+    probe: Option<(u16, u32)>,
+    /// failed probes of the current probe size
+    probe_failures: u8,
+    /// when the search last converged
+    converged_at: Option<Instant>,
+}
+
+impl Pmtu {
+    /// Start with the MTU of the interface, like classic PMTUD
+    pub fn new(mtu: u16, ipv6: bool) -> Self {
+        Pmtu {
+            pmtu: mtu,
+            max: mtu,
+            min: if ipv6 { MIN_PMTU_V6 } else { MIN_PMTU },
+            search_high: mtu,
+            probe: None,
+            probe_failures: 0,
+            converged_at: None,
+        }
+    }
+
+    /// ICMP fragmentation needed or ICMPv6 packet too big with next-hop `mtu`, 0 if the router didn't say.
+    /// Returns whether the PMTU went down.
+    pub fn on_fragmentation_needed(&mut self, mtu: u16) -> bool {
+        let mtu = if mtu == 0 {
+            PLATEAUS
+                .iter()
+                .copied()
+                .find(|&p| p < self.pmtu)
+                .unwrap_or(self.min)
+        } else {
+            mtu
+        };
+        // never raise the PMTU from ICMP, and ignore nonsense below the minimum link MTU
+        if mtu < self.min || mtu >= self.pmtu {
+            return false;
+        }
+        self.pmtu = mtu;
+        self.search_high = mtu;
+        self.probe = None;
+        self.probe_failures = 0;
+        self.converged_at = None;
+        true
+    }
+
+    /// Full sized segments keep getting lost without any ICMP (RFC 4821 S7.7).
+    /// Returns whether the PMTU went down.
+    pub fn on_black_hole(&mut self) -> bool {
+        let base = std::cmp::max(BASE_PMTU, self.min);
+        if self.pmtu <= base {
+            return false;
+        }
+        self.pmtu = base;
+        self.probe = None;
+        self.probe_failures = 0;
+        self.converged_at = None;
+        true
+    }
+
+    /// The size of the probe to send next, if one is due
+    pub fn probe_size(&mut self, now: Instant) -> Option<u16> {
+        if self.probe.is_some() {
+            return None;
+        }
+        if self.search_high.saturating_sub(self.pmtu) < SEARCH_GRANULARITY {
+            match self.converged_at {
+                None => {
+                    self.converged_at = Some(now);
+                    return None;
+                }
+                Some(t) if now.saturating_duration_since(t) < RAISE_INTERVAL => return None,
+                Some(_) => {
+                    // the path may have changed, search up to the interface MTU again
+                    self.converged_at = None;
+                    self.search_high = self.max;
+                    if self.search_high.saturating_sub(self.pmtu) < SEARCH_GRANULARITY {
+                        return None;
+                    }
+                }
+            }
+        }
+        Some(self.pmtu + (self.search_high - self.pmtu).div_ceil(2))
+    }
+
+    /// A probe of `size` went out, its data ends before sequence number `end`
+    pub fn probe_sent(&mut self, size: u16, end: u32) {
+        self.probe = Some((size, end));
+    }
+
+    /// The probe in flight: its size and the sequence number following its data
+    pub fn probe_in_flight(&self) -> Option<(u16, u32)> {
+        self.probe
+    }
+
+    /// The probe in flight was acknowledged, the path carries packets of its size
+    pub fn probe_acked(&mut self) {
+        if let Some((size, _)) = self.probe.take() {
+            self.pmtu = std::cmp::max(self.pmtu, size);
+            self.probe_failures = 0;
+        }
+    }
+
+    /// The probe in flight was lost, after `MAX_PROBES` its size is taken as too big
+    pub fn probe_lost(&mut self) {
+        if let Some((size, _)) = self.probe.take() {
+            self.probe_failures += 1;
+            if self.probe_failures >= MAX_PROBES {
+                self.search_high = size - 1;
+                self.probe_failures = 0;
+            }
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Ephemeral port selection (RFC 6056)
+//!
+//! Local ports of outgoing connections come from a range, by default the IANA dynamic ports
+//! (RFC 6335). The double-hash algorithm of RFC 6056 S3.3.3 picks them: a keyed hash of the
+//! endpoints gives each destination its own starting point in the range, and a small table of
+//! counters, indexed by a second hash, moves it along, so ports are hard to guess from outside
+//! and the same destination doesn't see the same port twice in a row.
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::io;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+
+/// the dynamic and/or private ports (RFC 6335 S6)
+pub const DEFAULT_RANGE: RangeInclusive<u16> = 49152..=65535;
+/// number of counters, RFC 6056 suggests at least a few hundred
+const TABLE_LEN: usize = 256;
+
+pub struct EphemeralPorts {
+    range: RangeInclusive<u16>,
+    /// key of F, the starting point
+    offset_secret: RandomState,
+    /// key of G, the counter
+    index_secret: RandomState,
+    table: [u16; TABLE_LEN],
+}
+
+impl Default for EphemeralPorts {
+    fn default() -> Self {
+        EphemeralPorts::new()
+    }
+}
+
+impl EphemeralPorts {
+    pub fn new() -> Self {
+        EphemeralPorts {
+            range: DEFAULT_RANGE,
+            offset_secret: RandomState::new(),
+            index_secret: RandomState::new(),
+            table: [0; TABLE_LEN],
+        }
+    }
+
+    /// Take ports from `range` from now on
+    pub fn set_range(&mut self, range: RangeInclusive<u16>) -> io::Result<()> {
+        if range.is_empty() || *range.start() == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ephemeral port range is empty or includes port 0",
+            ));
+        }
+        self.range = range;
+        Ok(())
+    }
+
+    /// A port for a connection from `local` to `remote` for which `in_use` is false,
+    /// `None` once every port of the range has been tried
+    pub fn select(
+        &mut self,
+        local: IpAddr,
+        remote: (IpAddr, u16),
+        in_use: impl Fn(u16) -> bool,
+    ) -> Option<u16> {
+        let (min, max) = (*self.range.start() as u32, *self.range.end() as u32);
+        let num = max - min + 1;
+        let endpoints = (local, remote.0, remote.1);
+        let offset = self.offset_secret.hash_one(endpoints) as u32;
+        let index = self.index_secret.hash_one(endpoints) as usize % TABLE_LEN;
+        for _ in 0..num {
+            let next = offset.wrapping_add(self.table[index] as u32);
+            let port = (min + next % num) as u16;
+            self.table[index] = self.table[index].wrapping_add(1);
+            if !in_use(port) {
+                return Some(port);
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,67 @@
+//! Round-trip time estimation and the retransmission timeout (RFC 6298)
+//!
+//! One segment per window is timed, never a retransmitted one (Karn's algorithm), and each
+//! measurement updates the smoothed RTT and its variation. Every expiry of the timer doubles
+//! the timeout until the next measurement.
+use std::time::Duration;
+
+/// RTO before the first measurement (RFC 6298 S2.1)
+pub const INITIAL_RTO: Duration = Duration::from_secs(1);
+/// RFC 6298 S2.4 asks for 1 s, like Linux we go lower so a lost segment on a LAN costs less
+pub const MIN_RTO: Duration = Duration::from_millis(200);
+/// the longest the timer backs off to (RFC 6298 S2.5)
+pub const MAX_RTO: Duration = Duration::from_secs(60);
+/// clock granularity G
+const GRANULARITY: Duration = Duration::from_millis(1);
+
+pub struct Rtt {
+    /// SRTT, unknown until the first measurement
+    pub srtt: Option<Duration>,
+    /// RTTVAR
+    pub rttvar: Duration,
+    /// RTO including the back-off
+    rto: Duration,
+}
+
+impl Default for Rtt {
+    fn default() -> Self {
+        Rtt::new()
+    }
+}
+
+impl Rtt {
+    pub fn new() -> Self {
+        Rtt {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+        }
+    }
+
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// A segment was acknowledged `rtt` after it was sent (RFC 6298 S2.2, S2.3)
+    pub fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                // beta = 1/4, alpha = 1/8
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        let rto = self.srtt.unwrap_or_default() + std::cmp::max(GRANULARITY, self.rttvar * 4);
+        self.rto = rto.clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// The timer expired, wait twice as long next time (RFC 6298 S5.5)
+    pub fn backoff(&mut self) {
+        self.rto = std::cmp::min(self.rto * 2, MAX_RTO);
+    }
+}
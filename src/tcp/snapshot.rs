@@ -0,0 +1,135 @@
+//! # What is on the stack
+//!
+//! `tcp::snapshot` copies out the listeners and the flows with their `flow::Info`, so a
+//! running stack can be looked at from outside. A `Snapshot` prints like `ss -tanpi`: one line
+//! per socket, the flows followed by a line of their internals.
+use crate::tcp::flow::{Info, State, Timer};
+use std::fmt;
+use std::net::SocketAddr;
+
+/// A listening port
+#[derive(Clone, Debug)]
+pub struct Listener {
+    pub port: u16,
+    /// connections that completed their handshake and haven't been accepted yet
+    pub accept_queue: usize,
+    /// half-open connections kept before SYN cookies kick in
+    pub syn_backlog: usize,
+}
+
+/// The listeners and the flows of a stack at one moment
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    /// the process the stack runs in
+    pub pid: u32,
+    pub listeners: Vec<Listener>,
+    pub flows: Vec<Info>,
+}
+
+/// the state as `ss` names it
+fn state_name(state: State) -> &'static str {
+    match state {
+        State::SynRcvd => "SYN-RECV",
+        State::SynSent => "SYN-SENT",
+        State::Estab => "ESTAB",
+        State::FinWait1 => "FIN-WAIT-1",
+        State::FinWait2 => "FIN-WAIT-2",
+        State::Closing => "CLOSING",
+        State::TimeWait => "TIME-WAIT",
+        State::CloseWait => "CLOSE-WAIT",
+        State::LastAck => "LAST-ACK",
+        State::Closed => unreachable!("closed flows aren't listed"),
+    }
+}
+
+/// the timer as `ss -o` names it
+fn timer_name(timer: Timer) -> &'static str {
+    match timer {
+        Timer::Retransmit => "on",
+        Timer::DelayedAck => "delack",
+        Timer::TimeWait => "timewait",
+    }
+}
+
+fn ms(d: std::time::Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let process = format!("pid={}", self.pid);
+        writeln!(
+            f,
+            "{:<11}{:>7}{:>7} {:<40} {:<40} Process",
+            "State", "Recv-Q", "Send-Q", "Local Address:Port", "Peer Address:Port"
+        )?;
+        for l in &self.listeners {
+            writeln!(
+                f,
+                "{:<11}{:>7}{:>7} {:<40} {:<40} {}",
+                "LISTEN",
+                l.accept_queue,
+                l.syn_backlog,
+                format!("*:{}", l.port),
+                "*:*",
+                process
+            )?;
+        }
+        // a closed flow is gone from the stack, it only lingers in an old snapshot
+        for i in self.flows.iter().filter(|i| i.state != State::Closed) {
+            writeln!(
+                f,
+                "{:<11}{:>7}{:>7} {:<40} {:<40} {}",
+                state_name(i.state),
+                i.recv_queue,
+                i.send_queue,
+                SocketAddr::from(i.quad.dst),
+                SocketAddr::from(i.quad.src),
+                process
+            )?;
+            write!(f, "\t")?;
+            if i.ecn {
+                write!(f, " ecn")?;
+            }
+            write!(f, " rto:{:.0}", ms(i.rto))?;
+            if let Some(rtt) = i.rtt {
+                write!(f, " rtt:{:.3}/{:.3}", ms(rtt), ms(i.rttvar))?;
+            }
+            write!(
+                f,
+                " mss:{} pmtu:{} rcvmss:{} cwnd:{}",
+                i.snd_mss, i.pmtu, i.mss, i.cwnd
+            )?;
+            if i.ssthresh != usize::MAX {
+                write!(f, " ssthresh:{}", i.ssthresh)?;
+            }
+            write!(
+                f,
+                " bytes_sent:{} bytes_retrans:{} bytes_acked:{} bytes_received:{} segs_out:{} segs_in:{}",
+                i.bytes_sent, i.bytes_retrans, i.bytes_acked, i.bytes_received, i.segs_out, i.segs_in
+            )?;
+            write!(
+                f,
+                " snd_wnd:{} rcv_wnd:{} unacked:{} retrans:{}/{} ofo:{} age:{:.0}",
+                i.snd_wnd,
+                i.rcv_wnd,
+                i.unacked,
+                i.retransmits,
+                i.retrans_segs,
+                i.out_of_order,
+                ms(i.age)
+            )?;
+            if let Some((timer, left)) = i.timer {
+                write!(
+                    f,
+                    " timer:({},{:.0}ms,{})",
+                    timer_name(timer),
+                    ms(left),
+                    i.retransmits
+                )?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,90 @@
+//! SYN cookies (RFC 4987 S3.6)
+//!
+//! When the SYN queue of a listener is full we stop allocating a `flow` per SYN and instead
+//! encode what we need to rebuild the connection into the ISN of the SYN-ACK:
+//!
+//! ```text
+//!  31       27 26    24 23                                0
+//! +-----------+--------+-----------------------------------+
+//! |  counter  |  MSS   |   keyed hash of quad, IRS, t, MSS |
+//! +-----------+--------+-----------------------------------+
+//! ```
+//!
+//! `counter` is a 5-bit time slot that advances every 64 seconds of the stack's clock and `MSS`
+//! indexes `MSS_TABLE`.
+//! The flow is only created once the final ACK acknowledges a cookie that validates.
+use crate::tcp::flow::Quad;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::time::Instant;
+
+/// MSS values a cookie can encode, the peer's MSS is rounded down to one of them
+const MSS_TABLE: [u16; 4] = [536, 1300, 1440, 1460];
+/// length of one counter slot in seconds
+const COUNTER_PERIOD: u64 = 64;
+/// how many counter slots a cookie stays valid for
+const MAX_AGE: u32 = 2;
+
+pub struct SynCookies {
+    /// the random SipHash keys of a `RandomState` serve as the secret
+    secret: RandomState,
+    /// the start of the first counter slot
+    epoch: Instant,
+}
+
+impl Default for SynCookies {
+    fn default() -> Self {
+        SynCookies::new(Instant::now())
+    }
+}
+
+impl SynCookies {
+    /// Cookies whose counter starts at `now`
+    pub fn new(now: Instant) -> Self {
+        SynCookies {
+            secret: RandomState::new(),
+            epoch: now,
+        }
+    }
+
+    /// Compute the ISN to answer a SYN from `quad` carrying sequence number `irs` and `mss` at `now`.
+    pub fn generate(&self, quad: &Quad, irs: u32, mss: u16, now: Instant) -> u32 {
+        let count = self.counter(now);
+        let index = MSS_TABLE.iter().rposition(|&m| m <= mss).unwrap_or(0) as u32;
+        (count & 0x1f) << 27 | index << 24 | self.hash(quad, irs, count, index)
+    }
+
+    /// Check the cookie acknowledged by the final ACK of a handshake.
+    /// `irs` is the sequence number of the original SYN (SEG.SEQ - 1 of the ACK).
+    /// Returns the MSS encoded in the cookie if it is genuine and recent enough at `now`.
+    pub fn validate(&self, quad: &Quad, irs: u32, cookie: u32, now: Instant) -> Option<u16> {
+        let now = self.counter(now);
+        let age = now.wrapping_sub(cookie >> 27) & 0x1f;
+        if age > MAX_AGE {
+            return None;
+        }
+        let count = now.wrapping_sub(age);
+        let index = (cookie >> 24) & 0x7;
+        if index as usize >= MSS_TABLE.len() {
+            return None;
+        }
+        if self.hash(quad, irs, count, index) != cookie & 0x00ff_ffff {
+            return None;
+        }
+        Some(MSS_TABLE[index as usize])
+    }
+
+    /// the counter slot `now` falls in
+    fn counter(&self, now: Instant) -> u32 {
+        (now.saturating_duration_since(self.epoch).as_secs() / COUNTER_PERIOD) as u32
+    }
+
+    fn hash(&self, quad: &Quad, irs: u32, count: u32, index: u32) -> u32 {
+        let mut h = self.secret.build_hasher();
+        quad.hash(&mut h);
+        irs.hash(&mut h);
+        count.hash(&mut h);
+        index.hash(&mut h);
+        h.finish() as u32 & 0x00ff_ffff
+    }
+}
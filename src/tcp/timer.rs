@@ -0,0 +1,75 @@
+//! Timers of the stack
+//!
+//! A hashed timer wheel (Varghese and Lauck): time is cut into ticks of `TICK`, a timer goes
+//! into the slot of its tick modulo `SLOTS` and waits there until its tick comes up, so
+//! scheduling is constant time however many flows there are. Timers aren't cancelled: flows
+//! keep their own deadlines, and whoever handles an expired key checks what is really due.
+use std::time::{Duration, Instant};
+
+/// the resolution of the wheel, timers fire up to one tick late
+pub const TICK: Duration = Duration::from_millis(10);
+/// slots of the wheel, timers further out than `SLOTS` ticks wait for more than one turn
+const SLOTS: usize = 512;
+
+pub struct TimerWheel<K> {
+    start: Instant,
+    /// the keys waiting in each slot with the tick they are due at
+    slots: Vec<Vec<(u64, K)>>,
+    /// every tick up to here has been expired
+    current: u64,
+}
+
+impl<K> TimerWheel<K> {
+    pub fn new(start: Instant) -> Self {
+        TimerWheel {
+            start,
+            slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+            current: 0,
+        }
+    }
+
+    /// the tick `at` falls in, rounded up so nothing fires early
+    fn tick(&self, at: Instant) -> u64 {
+        let since = at.saturating_duration_since(self.start).as_nanos();
+        since.div_ceil(TICK.as_nanos()) as u64
+    }
+
+    /// Hand out `key` once `at` has passed
+    pub fn schedule(&mut self, at: Instant, key: K) {
+        // a deadline that has passed already fires on the next call to expire
+        let tick = std::cmp::max(self.tick(at), self.current + 1);
+        self.slots[tick as usize % SLOTS].push((tick, key));
+    }
+
+    /// When the next timer is due, if there is any
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let tick = self.slots.iter().flatten().map(|&(tick, _)| tick).min()?;
+        Some(self.start + Duration::from_nanos(tick * TICK.as_nanos() as u64))
+    }
+
+    /// Take the keys of every timer due at `now`
+    pub fn expire(&mut self, now: Instant) -> Vec<K> {
+        // the last tick that has completely passed
+        let now_tick =
+            (now.saturating_duration_since(self.start).as_nanos() / TICK.as_nanos()) as u64;
+        let mut due = Vec::new();
+        if now_tick <= self.current {
+            return due;
+        }
+        // after a long sleep every slot is visited once, not once per missed tick
+        let ticks = std::cmp::min(now_tick - self.current, SLOTS as u64);
+        for i in 1..=ticks {
+            let slot = &mut self.slots[(self.current + i) as usize % SLOTS];
+            let mut j = 0;
+            while j < slot.len() {
+                if slot[j].0 <= now_tick {
+                    due.push(slot.swap_remove(j).1);
+                } else {
+                    j += 1;
+                }
+            }
+        }
+        self.current = now_tick;
+        due
+    }
+}
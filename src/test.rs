@@ -7,24 +7,23 @@ use std::io;
 pub fn test1() -> io::Result<()> {
     let nic = tun_tap::Iface::without_packet_info("tun0", tun_tap::Mode::Tun)?;
 
-    while true {
+    loop {
         let mut buf = [0u8; 1504];
-        let mut nbytes = nic.recv(&mut buf[..])?;
+        let nbytes = nic.recv(&mut buf[..])?;
         println!("{} bytes:{:?}", nbytes, &buf[..nbytes]);
     }
-    Ok(())
 }
 
 pub fn test2() -> io::Result<()> {
     let nic = tun_tap::Iface::without_packet_info("tun0", tun_tap::Mode::Tun)?;
 
-    while true {
+    loop {
         let mut buf = [0u8; 1504];
-        let mut nbytes = nic.recv(&mut buf[..])?;
+        let nbytes = nic.recv(&mut buf[..])?;
         //println!("{} bytes:{:?}",nbytes,&buf[..nbytes]);
 
         match etherparse::Ipv4HeaderSlice::from_slice(&buf[..nbytes]) {
-            Ok(iph) => {
+            Ok(_iph) => {
                 println!("An ip packet!");
             }
             Err(e) => {
@@ -32,22 +31,19 @@ pub fn test2() -> io::Result<()> {
             }
         }
     }
-    Ok(())
 }
 
 pub fn test3() -> io::Result<()> {
     let nic = tun_tap::Iface::without_packet_info("tun0", tun_tap::Mode::Tun)?;
 
-    while true {
+    loop {
         let mut buf = [0u8; 1504];
-        let mut nbytes = nic.recv(&mut buf[..])?;
+        let nbytes = nic.recv(&mut buf[..])?;
         //println!("{} bytes:{:?}",nbytes,&buf[..nbytes]);
 
         match etherparse::Ipv4HeaderSlice::from_slice(&buf[..nbytes]) {
             Ok(iph) => {
                 // println!("An ip packet!");
-                let src = iph.source_addr();
-                let dst = iph.destination_addr();
                 if iph.protocol() != 0x06 {
                     eprintln!("Not TCP");
                     continue;
@@ -60,5 +56,4 @@ pub fn test3() -> io::Result<()> {
             }
         }
     }
-    Ok(())
 }
@@ -0,0 +1,154 @@
+//! # UDP (RFC 768)
+//!
+//! Datagram sockets on the same device and IP layer as TCP. A bound port queues the
+//! datagrams that arrive for it until they are read with `recv_from`, up to
+//! `RECV_QUEUE_LEN`, after which new ones are dropped like a full socket buffer would.
+use crate::ip;
+use crate::tcp::output::Output;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::IpAddr;
+
+/// source port, destination port, length and checksum
+pub const HEADER_LEN: usize = 8;
+/// datagrams queued per port before new ones are dropped
+pub const RECV_QUEUE_LEN: usize = 64;
+
+/// A datagram waiting to be read
+struct Datagram {
+    from: (IpAddr, u16),
+    data: Vec<u8>,
+}
+
+/// What became of an incoming datagram
+#[derive(Debug, PartialEq, Eq)]
+pub enum Input {
+    Queued,
+    /// nobody is bound to the port, worth an ICMP port unreachable
+    Unbound,
+    /// the checksum doesn't match, the datagram was corrupted on the way
+    BadChecksum,
+    /// the header doesn't parse
+    Malformed,
+    /// the port's queue is full
+    QueueFull,
+}
+
+/// The bound ports and their queues
+#[derive(Default)]
+pub struct Udp {
+    sockets: HashMap<u16, VecDeque<Datagram>>,
+}
+
+impl Udp {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn bind(&mut self, port: u16) -> io::Result<()> {
+        if self.sockets.contains_key(&port) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "UDP port already bound",
+            ));
+        }
+        self.sockets.insert(port, VecDeque::new());
+        Ok(())
+    }
+
+    /// Unbind `port`, dropping whatever it hasn't read
+    pub fn close(&mut self, port: u16) {
+        self.sockets.remove(&port);
+    }
+
+    pub fn is_bound(&self, port: u16) -> bool {
+        self.sockets.contains_key(&port)
+    }
+
+    /// Queue the datagram in `iph` on its port, checking its checksum if `verify`
+    pub fn input(&mut self, iph: &ip::Packet, verify: bool) -> Input {
+        let udp = iph.payload;
+        if udp.len() < HEADER_LEN {
+            return Input::Malformed;
+        }
+        let src_port = u16::from_be_bytes([udp[0], udp[1]]);
+        let dst_port = u16::from_be_bytes([udp[2], udp[3]]);
+        let len = u16::from_be_bytes([udp[4], udp[5]]) as usize;
+        let checksum = u16::from_be_bytes([udp[6], udp[7]]);
+        if len < HEADER_LEN || len > udp.len() {
+            return Input::Malformed;
+        }
+        let udp = &udp[..len];
+        // a zero checksum means none was computed, IPv6 doesn't allow that (RFC 8200 S8.1)
+        if verify
+            && (checksum != 0 || iph.src.is_ipv6())
+            && ip::checksum(iph.src, iph.dst, ip::PROTOCOL_UDP, udp) != 0
+        {
+            return Input::BadChecksum;
+        }
+        let queue = match self.sockets.get_mut(&dst_port) {
+            Some(queue) => queue,
+            None => return Input::Unbound,
+        };
+        if queue.len() >= RECV_QUEUE_LEN {
+            debug!("UDP port {} is not reading, dropping", dst_port);
+            return Input::QueueFull;
+        }
+        queue.push_back(Datagram {
+            from: (iph.src, src_port),
+            data: udp[HEADER_LEN..].to_vec(),
+        });
+        Input::Queued
+    }
+
+    /// Take the next datagram of `port`, a datagram longer than `buf` is truncated.
+    /// Fails with `WouldBlock` when there is none.
+    pub fn recv_from(&mut self, port: u16, buf: &mut [u8]) -> io::Result<(usize, (IpAddr, u16))> {
+        let queue = self
+            .sockets
+            .get_mut(&port)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "UDP port not bound"))?;
+        let d = queue
+            .pop_front()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))?;
+        let n = std::cmp::min(buf.len(), d.data.len());
+        buf[..n].copy_from_slice(&d.data[..n]);
+        Ok((n, d.from))
+    }
+}
+
+/// Send `data` from `src` to `dst` in one datagram, we don't fragment so it has to fit the MTU
+pub fn send_to(
+    out: &mut Output,
+    src: (IpAddr, u16),
+    dst: (IpAddr, u16),
+    data: &[u8],
+) -> io::Result<usize> {
+    let mut ip = ip::Header::new(src.0, dst.0, ip::PROTOCOL_UDP);
+    let len = HEADER_LEN + data.len();
+    if ip.header_len() + len > out.mtu as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "datagram larger than the MTU",
+        ));
+    }
+    ip.set_payload_len(len)?;
+    let mut udp = Vec::with_capacity(len);
+    udp.extend_from_slice(&src.1.to_be_bytes());
+    udp.extend_from_slice(&dst.1.to_be_bytes());
+    udp.extend_from_slice(&(len as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(data);
+    let mut checksum = ip::checksum(src.0, dst.0, ip::PROTOCOL_UDP, &udp);
+    if checksum == 0 {
+        // 0 would mean no checksum (RFC 768)
+        checksum = 0xffff;
+    }
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+
+    let mut buf = Vec::with_capacity(ip.header_len() + len);
+    ip.write(&mut buf)?;
+    buf.extend_from_slice(&udp);
+    out.transmit(&buf);
+    Ok(data.len())
+}
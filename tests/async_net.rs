@@ -0,0 +1,83 @@
+//! Two stacks running as tokio tasks, talking through `AsyncRead` and `AsyncWrite`
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use tcp_proto::async_net::AsyncStack;
+use tcp_proto::nic;
+use tcp_proto::tcp::flow::Quad;
+use tcp_proto::tcp::tcp;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const LEN: usize = 100_000;
+
+#[tokio::test]
+async fn echo() -> io::Result<()> {
+    let (a, b) = nic::pipe();
+    let server = AsyncStack::spawn(tcp::with_device(a, SERVER))?;
+    let client = AsyncStack::spawn(tcp::with_device(b, CLIENT))?;
+    let listener = server.bind(80)?;
+
+    // the server reads until the client's FIN and only then echoes, over the half-closed connection
+    let echo = tokio::spawn(async move {
+        let (mut stream, peer) = listener.accept().await?;
+        assert_eq!(peer.ip(), CLIENT);
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await?;
+        assert_eq!(received.len(), LEN);
+        stream.write_all(&received).await?;
+        stream.shutdown().await
+    });
+
+    let payload: Vec<u8> = (0..LEN as u32).map(|i| (i * 7 % 251) as u8).collect();
+    let run = async {
+        let mut stream = client.connect(SocketAddr::new(SERVER.into(), 80)).await?;
+        assert_eq!(stream.peer_addr(), SocketAddr::new(SERVER.into(), 80));
+        stream.write_all(&payload).await?;
+        stream.shutdown().await?;
+        // everything the server wrote after our FIN arrives, then its own FIN ends the stream
+        let mut echoed = Vec::new();
+        stream.read_to_end(&mut echoed).await?;
+        io::Result::Ok(echoed)
+    };
+    let echoed = tokio::time::timeout(Duration::from_secs(10), run)
+        .await
+        .expect("the transfer stalled")?;
+    assert!(echoed == payload);
+    echo.await.unwrap()
+}
+
+#[tokio::test]
+async fn read_after_close() -> io::Result<()> {
+    let (a, b) = nic::pipe();
+    let server = AsyncStack::spawn(tcp::with_device(a, SERVER))?;
+    let client = AsyncStack::spawn(tcp::with_device(b, CLIENT))?;
+    let listener = server.bind(80)?;
+
+    let run = async {
+        let mut stream = client.connect(SocketAddr::new(SERVER.into(), 80)).await?;
+        let (mut accepted, peer) = listener.accept().await?;
+        stream.shutdown().await?;
+        let mut buf = Vec::new();
+        accepted.read_to_end(&mut buf).await?;
+        accepted.shutdown().await?;
+
+        // once LAST-ACK is over the flow is gone, reading still ends the stream
+        let quad = Quad {
+            src: (peer.ip(), peer.port()),
+            dst: (SERVER.into(), 80),
+        };
+        while server.with(|s| s.flow(&quad).is_some()) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut buf = [0u8; 16];
+        accepted.read(&mut buf).await
+    };
+    let n = tokio::time::timeout(Duration::from_secs(10), run)
+        .await
+        .expect("the close stalled")?;
+    assert_eq!(n, 0);
+    Ok(())
+}
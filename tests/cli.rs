@@ -0,0 +1,68 @@
+//! The tcp_proto binary's command line, without opening a tun device
+use std::io::Write;
+use std::os::unix::net::UnixListener;
+use std::process::Command;
+use std::{env, fs, process, thread};
+
+fn tcp_proto(args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_tcp_proto"))
+        .args(args)
+        .output()
+        .expect("tcp_proto runs")
+}
+
+#[test]
+fn help() {
+    let output = tcp_proto(&["--help"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("usage: tcp_proto"));
+}
+
+#[test]
+fn bad_arguments() {
+    for args in [
+        &["listen", "http"][..],
+        &["connect", "192.168.0.1"],
+        &["-a", "192.168.0.2/33"],
+        &["--pcap"],
+        &["--output"],
+        &["serve"],
+        &["ss", "listen", "80"],
+        &["listen", "80", "ss"],
+        &["connect", "192.168.0.1:80", "ss"],
+        &["ss", "extra"],
+        &["ss", "-i", "tun1"],
+    ] {
+        let output = tcp_proto(args);
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("usage:"));
+    }
+}
+
+#[test]
+fn ss_without_an_instance() {
+    let output = tcp_proto(&["-i", "tcp_proto_test_none", "ss"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("no tcp_proto running on tcp_proto_test_none"));
+}
+
+#[test]
+fn ss_in_the_runtime_dir() {
+    let dir = env::temp_dir().join(format!("tcp_proto_test_runtime-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let listener = UnixListener::bind(dir.join("tcp_proto-tcp_proto_test.sock")).unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"State Recv-Q Send-Q\n").unwrap();
+    });
+    let output = Command::new(env!("CARGO_BIN_EXE_tcp_proto"))
+        .args(["-i", "tcp_proto_test", "ss"])
+        .env("XDG_RUNTIME_DIR", &dir)
+        .output()
+        .expect("tcp_proto runs");
+    server.join().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"State Recv-Q Send-Q\n");
+}
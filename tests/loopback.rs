@@ -0,0 +1,1938 @@
+//! Two stacks wired together with an in-memory device pair
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tcp_proto::ethernet::Ethernet;
+use tcp_proto::event::EventLoop;
+use tcp_proto::icmp;
+use tcp_proto::nic::{self, NetDevice};
+use tcp_proto::pcap;
+use tcp_proto::tcp::flow::{Quad, State, Timer, DELAYED_ACK, ECN_CE, ECN_ECT0};
+use tcp_proto::tcp::output::{DropReason, Event};
+use tcp_proto::tcp::{control_message, fastopen, options, tcp};
+
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const SERVER6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+const CLIENT6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+
+/// Feed every queued packet to its stack until both sides are quiet.
+/// A device may consume packets itself (ARP) and still report `WouldBlock`,
+/// so it takes a few rounds without anything for the stacks to be sure.
+fn pump<D: NetDevice>(a: &mut tcp<D>, b: &mut tcp<D>) -> io::Result<()> {
+    let mut buf = [0u8; 1504];
+    let mut quiet = 0;
+    loop {
+        let mut idle = true;
+        for stack in [&mut *a, &mut *b] {
+            match stack.nic.recv(&mut buf) {
+                Ok(n) => {
+                    stack.action(&buf, n)?;
+                    idle = false;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        quiet = if idle { quiet + 1 } else { 0 };
+        if quiet == 3 {
+            return Ok(());
+        }
+    }
+}
+
+/// A server listening on port 80 and a client connected to it from port 4000
+fn connected() -> io::Result<(tcp<nic::Pipe>, tcp<nic::Pipe>)> {
+    let (a, b) = nic::pipe();
+    let mut server = tcp::with_device(a, SERVER);
+    let mut client = tcp::with_device(b, CLIENT);
+    server.control(control_message::Bind(80))?;
+    client.control(control_message::Connect(4000, SERVER.into(), 80))?;
+    pump(&mut server, &mut client)?;
+    Ok((server, client))
+}
+
+// quads name the peer first
+const AT_SERVER: Quad = Quad {
+    src: (IpAddr::V4(CLIENT), 4000),
+    dst: (IpAddr::V4(SERVER), 80),
+};
+const AT_CLIENT: Quad = Quad {
+    src: (IpAddr::V4(SERVER), 80),
+    dst: (IpAddr::V4(CLIENT), 4000),
+};
+
+#[test]
+fn handshake() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    assert_eq!(server.flow(&AT_SERVER).unwrap().state, State::Estab);
+    assert_eq!(client.flow(&AT_CLIENT).unwrap().state, State::Estab);
+
+    // both ends take data and it reaches the other one
+    let mut buf = [0u8; 64];
+    assert_eq!(client.write(&AT_CLIENT, b"syn")?, 3);
+    assert_eq!(server.write(&AT_SERVER, b"syn-ack")?, 7);
+    pump(&mut server, &mut client)?;
+    let n = server.read(&AT_SERVER, &mut buf)?;
+    assert_eq!(&buf[..n], b"syn");
+    let n = client.read(&AT_CLIENT, &mut buf)?;
+    assert_eq!(&buf[..n], b"syn-ack");
+    Ok(())
+}
+
+#[test]
+fn exchange_data() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    let mut buf = [0u8; 64];
+
+    client.write(&AT_CLIENT, b"hello")?;
+    pump(&mut server, &mut client)?;
+    let n = server.read(&AT_SERVER, &mut buf)?;
+    assert_eq!(&buf[..n], b"hello");
+
+    server.write(&AT_SERVER, b"world")?;
+    pump(&mut server, &mut client)?;
+    let n = client.read(&AT_CLIENT, &mut buf)?;
+    assert_eq!(&buf[..n], b"world");
+    Ok(())
+}
+
+#[test]
+fn bulk_transfer() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    let mut received = Vec::new();
+    let mut buf = [0u8; 4096];
+
+    client.write(&AT_CLIENT, &data)?;
+    while received.len() < data.len() {
+        pump(&mut server, &mut client)?;
+        let n = server.read(&AT_SERVER, &mut buf)?;
+        assert!(n > 0, "transfer stalled after {} bytes", received.len());
+        received.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(received, data);
+    Ok(())
+}
+
+#[test]
+fn ethernet_and_arp() -> io::Result<()> {
+    let (mut a, mut b) = nic::pipe();
+    a.medium = nic::Medium::Ethernet;
+    b.medium = nic::Medium::Ethernet;
+    let mut server = tcp::with_device(Ethernet::new(a, [2, 0, 0, 0, 0, 1], SERVER), SERVER);
+    let mut client = tcp::with_device(Ethernet::new(b, [2, 0, 0, 0, 0, 2], CLIENT), CLIENT);
+    server.control(control_message::Bind(80))?;
+    // the SYN waits for the ARP reply
+    client.control(control_message::Connect(4000, SERVER.into(), 80))?;
+    pump(&mut server, &mut client)?;
+
+    let mut buf = [0u8; 64];
+    client.write(&AT_CLIENT, b"over ethernet")?;
+    pump(&mut server, &mut client)?;
+    let n = server.read(&AT_SERVER, &mut buf)?;
+    assert_eq!(&buf[..n], b"over ethernet");
+    Ok(())
+}
+
+#[test]
+fn arp_retry() -> io::Result<()> {
+    let (mut a, mut b) = nic::pipe();
+    b.medium = nic::Medium::Ethernet;
+    let mut client = tcp::with_device(Ethernet::new(b, [2, 0, 0, 0, 0, 2], CLIENT), CLIENT);
+    client.control(control_message::Connect(4000, SERVER.into(), 80))?;
+    let mut frame = [0u8; 1518];
+    let n = a.recv(&mut frame)?;
+    assert_eq!(&frame[12..14], &[0x08, 0x06]);
+    assert_eq!(n, 14 + 28);
+    assert!(a.recv(&mut frame).is_err());
+    // the SYN goes again a second later by the stack's clock, and so does the ARP request
+    client.poll_timers(
+        client
+            .next_timeout()
+            .expect("the SYN's retransmission timer"),
+    )?;
+    a.recv(&mut frame)?;
+    assert_eq!(&frame[12..14], &[0x08, 0x06]);
+    Ok(())
+}
+
+#[test]
+fn arp_retry_without_tcp() -> io::Result<()> {
+    let (mut a, mut b) = nic::pipe();
+    b.medium = nic::Medium::Ethernet;
+    let mut client = tcp::with_device(Ethernet::new(b, [2, 0, 0, 0, 0, 2], CLIENT), CLIENT);
+    client.udp_bind(5353)?;
+    client.send_to(5353, (SERVER.into(), 53), b"query")?;
+    let mut frame = [0u8; 1518];
+    let mut requests = 0;
+    while a.recv(&mut frame).is_ok() {
+        assert_eq!(&frame[12..14], &[0x08, 0x06]);
+        requests += 1;
+    }
+    assert_eq!(requests, 1);
+
+    // no timer of the stack is running, its clock alone repeats the request and gives up
+    let now = Instant::now();
+    for secs in 1..6 {
+        client.poll_timers(now + Duration::from_millis(secs * 1100))?;
+        while a.recv(&mut frame).is_ok() {
+            assert_eq!(&frame[12..14], &[0x08, 0x06]);
+            requests += 1;
+        }
+    }
+    assert_eq!(requests, 3);
+
+    // IPv6 has no neighbour discovery here, its packets are counted and dropped
+    client.set_ip6(CLIENT6);
+    client.send_to(5353, (SERVER6.into(), 53), b"query")?;
+    assert_eq!(client.nic.not_ipv4, 1);
+    assert_eq!(client.counters().dropped(DropReason::SendFailed), 0);
+    assert!(a.recv(&mut frame).is_err());
+    Ok(())
+}
+
+#[test]
+fn ipv6() -> io::Result<()> {
+    let (a, b) = nic::pipe();
+    let mut server = tcp::with_device(a, SERVER);
+    let mut client = tcp::with_device(b, CLIENT);
+    server.set_ip6(SERVER6);
+    client.set_ip6(CLIENT6);
+    server.control(control_message::Bind(80))?;
+    client.control(control_message::Connect(4000, SERVER6.into(), 80))?;
+    pump(&mut server, &mut client)?;
+
+    let at_server = Quad {
+        src: (IpAddr::V6(CLIENT6), 4000),
+        dst: (IpAddr::V6(SERVER6), 80),
+    };
+    let at_client = Quad {
+        src: (IpAddr::V6(SERVER6), 80),
+        dst: (IpAddr::V6(CLIENT6), 4000),
+    };
+    let mut buf = [0u8; 64];
+    client.write(&at_client, b"hello v6")?;
+    pump(&mut server, &mut client)?;
+    let n = server.read(&at_server, &mut buf)?;
+    assert_eq!(&buf[..n], b"hello v6");
+    server.write(&at_server, b"back")?;
+    pump(&mut server, &mut client)?;
+    let n = client.read(&at_client, &mut buf)?;
+    assert_eq!(&buf[..n], b"back");
+    Ok(())
+}
+
+#[test]
+fn udp() -> io::Result<()> {
+    let (a, b) = nic::pipe();
+    let mut server = tcp::with_device(a, SERVER);
+    let mut client = tcp::with_device(b, CLIENT);
+    server.udp_bind(53)?;
+    client.udp_bind(5353)?;
+    assert!(server.udp_bind(53).is_err());
+
+    client.send_to(5353, (SERVER.into(), 53), b"query")?;
+    // nobody listens on 54, the client gets a port unreachable
+    client.send_to(5353, (SERVER.into(), 54), b"lost")?;
+    pump(&mut server, &mut client)?;
+    let mut buf = [0u8; 64];
+    let (n, from) = server.recv_from(53, &mut buf)?;
+    assert_eq!(&buf[..n], b"query");
+    assert_eq!(from, (IpAddr::V4(CLIENT), 5353));
+    assert_eq!(
+        server.recv_from(53, &mut buf).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+
+    server.send_to(53, from, b"answer")?;
+    pump(&mut server, &mut client)?;
+    let (n, from) = client.recv_from(5353, &mut buf)?;
+    assert_eq!(&buf[..n], b"answer");
+    assert_eq!(from, (IpAddr::V4(SERVER), 53));
+    Ok(())
+}
+
+#[test]
+fn corrupt_segments_are_dropped() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    let mut packet = [0u8; 1504];
+
+    client.write(&AT_CLIENT, b"intact")?;
+    let n = server.nic.recv(&mut packet)?;
+    // flip a payload bit, then a bit of the IP header
+    packet[n - 1] ^= 1;
+    server.action(&packet, n)?;
+    packet[n - 1] ^= 1;
+    packet[8] ^= 1;
+    server.action(&packet, n)?;
+    assert_eq!(server.counters().dropped(DropReason::TcpChecksum), 1);
+    assert_eq!(server.counters().dropped(DropReason::IpChecksum), 1);
+    let mut buf = [0u8; 64];
+    assert_eq!(server.read(&AT_SERVER, &mut buf)?, 0);
+
+    packet[8] ^= 1;
+    server.action(&packet, n)?;
+    let n = server.read(&AT_SERVER, &mut buf)?;
+    assert_eq!(&buf[..n], b"intact");
+    Ok(())
+}
+
+#[test]
+fn ephemeral_ports() -> io::Result<()> {
+    let (a, b) = nic::pipe();
+    let mut server = tcp::with_device(a, SERVER);
+    let mut client = tcp::with_device(b, CLIENT);
+    server.control(control_message::Bind(80))?;
+    client.control(control_message::Bind(8080))?;
+    client.set_ephemeral_ports(50000..=50001)?;
+
+    let first = client.connect(SERVER.into(), 80)?;
+    let second = client.connect(SERVER.into(), 80)?;
+    assert_ne!(first.dst.1, second.dst.1);
+    for q in [first, second] {
+        assert!((50000..=50001).contains(&q.dst.1));
+    }
+    let err = client.connect(SERVER.into(), 80).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+
+    // a port that is listening or already talks to this peer can't be picked by hand either
+    let err = client
+        .control(control_message::Connect(8080, SERVER.into(), 80))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    let err = client
+        .control(control_message::Connect(first.dst.1, SERVER.into(), 80))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+    pump(&mut server, &mut client)?;
+    client.write(&first, b"one")?;
+    client.write(&second, b"two")?;
+    pump(&mut server, &mut client)?;
+    // each connection delivers to its own flow at the server
+    let mut buf = [0u8; 64];
+    for (q, data) in [(first, b"one"), (second, b"two")] {
+        let n = server.read(
+            &Quad {
+                src: q.dst,
+                dst: q.src,
+            },
+            &mut buf,
+        )?;
+        assert_eq!(&buf[..n], data);
+    }
+    Ok(())
+}
+
+#[test]
+fn destination_filter() -> io::Result<()> {
+    let other: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 9);
+    let (a, b) = nic::pipe();
+    let mut server = tcp::with_device(a, SERVER);
+    let mut client = tcp::with_device(b, CLIENT);
+    server.control(control_message::Bind(80))?;
+
+    // 10.0.0.9 isn't the server's, so the SYN creates nothing
+    let q = client.connect(other.into(), 80)?;
+    pump(&mut server, &mut client)?;
+    assert_eq!(server.counters().dropped(DropReason::NotForUs), 1);
+    assert!(client.write(&q, b"").is_err());
+
+    server.add_address(other.into());
+    let q = client.connect(other.into(), 80)?;
+    pump(&mut server, &mut client)?;
+    client.write(&q, b"second address")?;
+    pump(&mut server, &mut client)?;
+    let mut buf = [0u8; 64];
+    let at_server = Quad {
+        src: q.dst,
+        dst: q.src,
+    };
+    let n = server.read(&at_server, &mut buf)?;
+    assert_eq!(&buf[..n], b"second address");
+
+    server.remove_address(other.into());
+    server.set_promiscuous(true);
+    let q = client.connect(Ipv4Addr::new(10, 0, 0, 10).into(), 80)?;
+    pump(&mut server, &mut client)?;
+    client.write(&q, b"")?;
+    Ok(())
+}
+
+#[test]
+fn pcap_capture() -> io::Result<()> {
+    let (a, b) = nic::pipe();
+    let mut server = tcp::with_device(pcap::Capture::new(a, Vec::new())?, SERVER);
+    let mut client = tcp::with_device(pcap::Capture::new(b, Vec::new())?, CLIENT);
+    server.control(control_message::Bind(80))?;
+    client.control(control_message::Connect(4000, SERVER.into(), 80))?;
+    pump(&mut server, &mut client)?;
+
+    // SYN, SYN-ACK and ACK, each sent by one side and received by the other
+    for stack in [&server, &client] {
+        let file = stack.nic.get_ref();
+        assert_eq!(file[..4], pcap::MAGIC_NANOS.to_le_bytes());
+        assert_eq!(file[20..24], pcap::LINKTYPE_RAW.to_le_bytes());
+        let mut at = 24;
+        let mut packets = 0;
+        while at < file.len() {
+            let len =
+                u32::from_le_bytes([file[at + 8], file[at + 9], file[at + 10], file[at + 11]]);
+            at += 16 + len as usize;
+            packets += 1;
+        }
+        assert_eq!(at, file.len());
+        assert_eq!(packets, 3);
+    }
+    Ok(())
+}
+
+/// A capture file with room for `left` bytes
+struct Full {
+    left: usize,
+}
+
+impl std::io::Write for Full {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.left {
+            return Err(io::Error::from(io::ErrorKind::WriteZero));
+        }
+        self.left -= buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn pcap_capture_full() -> io::Result<()> {
+    let (a, b) = nic::pipe();
+    // the file header and nothing else fits
+    let mut server = tcp::with_device(pcap::Capture::new(a, Full { left: 24 })?, SERVER);
+    let mut client = tcp::with_device(pcap::Capture::new(b, Full { left: 24 })?, CLIENT);
+    server.control(control_message::Bind(80))?;
+    client.control(control_message::Connect(4000, SERVER.into(), 80))?;
+    pump(&mut server, &mut client)?;
+
+    // the handshake goes through once, the capture misses its packets
+    assert_eq!(client.flow(&AT_CLIENT).unwrap().state, State::Estab);
+    assert_eq!(client.counters().out_segs, 2);
+    assert_eq!(client.counters().dropped(DropReason::SendFailed), 0);
+    assert!(client.nic.take_error().is_some());
+    assert!(client.nic.take_error().is_none());
+    Ok(())
+}
+
+#[test]
+fn pcap_record_too_long() -> io::Result<()> {
+    let mut file = Vec::new();
+    pcap::Writer::new(&mut file, pcap::LINKTYPE_RAW)?;
+    // a record that claims 4 GiB
+    file.extend_from_slice(&[0; 8]);
+    file.extend_from_slice(&u32::MAX.to_le_bytes());
+    file.extend_from_slice(&u32::MAX.to_le_bytes());
+    let mut reader = pcap::Reader::new(&file[..])?;
+    let err = reader.next_packet().err().expect("an error");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    Ok(())
+}
+
+/// Lose the next packet `stack` would receive
+fn drop_next<D: NetDevice>(stack: &mut tcp<D>) {
+    let mut buf = [0u8; 1504];
+    stack.nic.recv(&mut buf).expect("a packet to drop");
+}
+
+#[test]
+fn retransmission() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    client.write(&AT_CLIENT, b"lost once")?;
+    drop_next(&mut server);
+    pump(&mut server, &mut client)?;
+    let mut buf = [0u8; 64];
+    assert_eq!(server.read(&AT_SERVER, &mut buf)?, 0);
+
+    // nothing is due before the RTO, after it the segment goes out again
+    client.poll_timers(Instant::now())?;
+    pump(&mut server, &mut client)?;
+    assert_eq!(server.read(&AT_SERVER, &mut buf)?, 0);
+    client.poll_timers(Instant::now() + Duration::from_secs(2))?;
+    pump(&mut server, &mut client)?;
+    let n = server.read(&AT_SERVER, &mut buf)?;
+    assert_eq!(&buf[..n], b"lost once");
+    Ok(())
+}
+
+/// Hand the next segment queued for `stack` to it, returns its TCP header
+fn next_segment<D: NetDevice>(stack: &mut tcp<D>) -> io::Result<etherparse::TcpHeader> {
+    let mut buf = [0u8; 1504];
+    let n = stack.nic.recv(&mut buf)?;
+    let tcph = tcp_header(&buf[..n]);
+    stack.action(&buf, n)?;
+    Ok(tcph)
+}
+
+#[test]
+fn out_of_order() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    let data: Vec<u8> = (0..4000u32).map(|i| i as u8).collect();
+    client.write(&AT_CLIENT, &data)?;
+
+    // the second of three segments is lost, the third arrives ahead of RCV.NXT
+    deliver(&mut server)?;
+    drop_next(&mut server);
+    deliver(&mut server)?;
+    // and is acknowledged right away, for the first segment only
+    let before = next_segment(&mut client)?.acknowledgment_number;
+    let mut buf = [0u8; 8192];
+    let n = server.read(&AT_SERVER, &mut buf)?;
+    assert!(n > 0 && n < data.len());
+    assert_eq!(&buf[..n], &data[..n]);
+
+    // the retransmission fills the gap and the queued segment follows it
+    client.poll_timers(Instant::now() + Duration::from_secs(2))?;
+    deliver(&mut server)?;
+    assert_eq!(
+        next_segment(&mut client)?.acknowledgment_number,
+        before + (data.len() - n) as u32
+    );
+    let m = server.read(&AT_SERVER, &mut buf)?;
+    assert_eq!(&buf[..m], &data[n..]);
+    assert_eq!(server.info(&AT_SERVER).unwrap().out_of_order, 1);
+    Ok(())
+}
+
+#[test]
+fn partly_old_segment() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    client.write(&AT_CLIENT, b"abc")?;
+    deliver(&mut server)?;
+    client.write(&AT_CLIENT, b"defgh")?;
+    drop_next(&mut server);
+    // the retransmission starts with data the server has, which isn't out of order
+    client.poll_timers(Instant::now() + Duration::from_secs(2))?;
+    deliver(&mut server)?;
+    let mut buf = [0u8; 64];
+    let n = server.read(&AT_SERVER, &mut buf)?;
+    assert_eq!(&buf[..n], b"abcdefgh");
+    assert_eq!(server.info(&AT_SERVER).unwrap().out_of_order, 0);
+    Ok(())
+}
+
+#[test]
+fn syn_cookies() -> io::Result<()> {
+    let (a, b) = nic::pipe();
+    let mut server = tcp::with_device(a, SERVER);
+    let mut client = tcp::with_device(b, CLIENT);
+    server.control(control_message::Bind(80))?;
+    server.set_syn_backlog(1);
+
+    // the first SYN takes the only place in the SYN queue, the second is answered with a cookie
+    client.control(control_message::Connect(4000, SERVER.into(), 80))?;
+    client.control(control_message::Connect(4001, SERVER.into(), 80))?;
+    deliver(&mut server)?;
+    deliver(&mut server)?;
+    assert_eq!(server.snapshot().flows.len(), 1);
+    // the flow is rebuilt from the final ACK
+    pump(&mut server, &mut client)?;
+    assert_eq!(server.counters().passive_opens, 2);
+    let at_server = Quad {
+        src: (CLIENT.into(), 4001),
+        dst: (SERVER.into(), 80),
+    };
+    assert_eq!(server.flow(&at_server).unwrap().state, State::Estab);
+    let at_client = Quad {
+        src: at_server.dst,
+        dst: at_server.src,
+    };
+    client.write(&at_client, b"from a cookie")?;
+    pump(&mut server, &mut client)?;
+    let mut buf = [0u8; 64];
+    let n = server.read(&at_server, &mut buf)?;
+    assert_eq!(&buf[..n], b"from a cookie");
+
+    // the first handshake completed and left the SYN queue, the next SYN gets the place
+    client.control(control_message::Connect(4002, SERVER.into(), 80))?;
+    deliver(&mut server)?;
+    assert_eq!(server.snapshot().flows.len(), 3);
+    Ok(())
+}
+
+#[test]
+fn syn_cookie_rejected() -> io::Result<()> {
+    let (a, b) = nic::pipe();
+    let mut server = tcp::with_device(a, SERVER);
+    let mut client = tcp::with_device(b, CLIENT);
+    server.control(control_message::Bind(80))?;
+    server.set_syn_backlog(0);
+    client.control(control_message::Connect(4000, SERVER.into(), 80))?;
+    deliver(&mut server)?;
+    assert!(server.snapshot().flows.is_empty());
+    deliver(&mut client)?;
+    let mut ack = [0u8; 1504];
+    let n = server.nic.recv(&mut ack)?;
+
+    // too late: the cookie is a few counter slots old
+    server.input(Instant::now() + Duration::from_secs(300), &ack[..n])?;
+    assert_eq!(server.counters().dropped(DropReason::NoSocket), 1);
+    // forged: another server made the cookie
+    let (c, _d) = nic::pipe();
+    let mut other = tcp::with_device(c, SERVER);
+    other.control(control_message::Bind(80))?;
+    other.set_syn_backlog(0);
+    other.input(Instant::now(), &ack[..n])?;
+    assert_eq!(other.counters().dropped(DropReason::NoSocket), 1);
+    assert!(other.flow(&AT_SERVER).is_none());
+
+    // in time the same ACK opens the connection
+    server.input(Instant::now(), &ack[..n])?;
+    assert_eq!(server.flow(&AT_SERVER).unwrap().state, State::Estab);
+    Ok(())
+}
+
+#[test]
+fn urgent_data() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    client.write(&AT_CLIENT, b"normal ")?;
+    deliver(&mut server)?;
+    assert_eq!(server.urgent_offset(&AT_SERVER), None);
+
+    // the pointer points past the last urgent byte, not at it (RFC 6093)
+    client.write_urgent(&AT_CLIENT, b"urgent")?;
+    let tcph = next_segment(&mut server)?;
+    assert!(tcph.urg);
+    assert_eq!(tcph.urgent_pointer, 6);
+    assert_eq!(server.urgent_offset(&AT_SERVER), Some(13));
+
+    // the urgent data stays inline, the offset shrinks as it is read
+    let mut buf = [0u8; 10];
+    assert_eq!(server.read(&AT_SERVER, &mut buf)?, 10);
+    assert_eq!(&buf, b"normal urg");
+    assert_eq!(server.urgent_offset(&AT_SERVER), Some(3));
+    let n = server.read(&AT_SERVER, &mut buf)?;
+    assert_eq!(&buf[..n], b"ent");
+    assert_eq!(server.urgent_offset(&AT_SERVER), None);
+    Ok(())
+}
+
+#[test]
+fn urgent_pointer_beyond_segment() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    let data: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+    client.write_urgent(&AT_CLIENT, &data)?;
+
+    // the first segment points past its own end, to where the urgent data ends
+    let tcph = next_segment(&mut server)?;
+    assert!(tcph.urg);
+    assert_eq!(tcph.urgent_pointer, 3000);
+    let mut buf = [0u8; 4096];
+    let n = server.read(&AT_SERVER, &mut buf)?;
+    assert!(n < data.len());
+    assert_eq!(server.urgent_offset(&AT_SERVER), Some(3000 - n));
+
+    // later segments carry a pointer relative to their own sequence number
+    let tcph = next_segment(&mut server)?;
+    assert!(tcph.urg);
+    assert_eq!(tcph.urgent_pointer as usize, 3000 - n);
+    pump(&mut server, &mut client)?;
+    let m = server.read(&AT_SERVER, &mut buf)?;
+    assert_eq!(n + m, data.len());
+    assert_eq!(server.urgent_offset(&AT_SERVER), None);
+
+    // data after the urgent data isn't urgent
+    client.write(&AT_CLIENT, b"normal")?;
+    let tcph = next_segment(&mut server)?;
+    assert!(!tcph.urg);
+    Ok(())
+}
+
+/// The Fast Open cookie option of the TCP segment in `packet`, if it has one
+fn fastopen_cookie(packet: &[u8]) -> Option<Vec<u8>> {
+    let tcph = tcp_header(packet);
+    options::find(tcph.options(), options::KIND_FASTOPEN).map(|c| c.to_vec())
+}
+
+/// A client that learned a Fast Open cookie from the server, connected from port 4000
+fn fastopen_cookie_learned() -> io::Result<(tcp<nic::Pipe>, tcp<nic::Pipe>)> {
+    let (a, b) = nic::pipe();
+    let mut server = tcp::with_device(a, SERVER);
+    let mut client = tcp::with_device(b, CLIENT);
+    server.control(control_message::Bind(80))?;
+    server.set_fastopen(true);
+
+    // the first SYN asks for a cookie and carries no data, the data follows the handshake
+    client.control(control_message::FastOpen(
+        4000,
+        SERVER.into(),
+        80,
+        b"first".to_vec(),
+    ))?;
+    let syn = take(&mut server)?;
+    assert_eq!(fastopen_cookie(&syn), Some(Vec::new()));
+    assert!(tcp_payload(&syn).is_empty());
+    server.input(Instant::now(), &syn)?;
+    let syn_ack = take(&mut client)?;
+    assert_eq!(
+        fastopen_cookie(&syn_ack).map(|c| c.len()),
+        Some(fastopen::COOKIE_LEN)
+    );
+    client.input(Instant::now(), &syn_ack)?;
+    pump(&mut server, &mut client)?;
+    let mut buf = [0u8; 64];
+    let n = server.read(&AT_SERVER, &mut buf)?;
+    assert_eq!(&buf[..n], b"first");
+    Ok((server, client))
+}
+
+const SECOND: Quad = Quad {
+    src: (IpAddr::V4(CLIENT), 4001),
+    dst: (IpAddr::V4(SERVER), 80),
+};
+
+#[test]
+fn fastopen_with_cookie() -> io::Result<()> {
+    let (mut server, mut client) = fastopen_cookie_learned()?;
+
+    // with the cookie the data rides on the SYN and is readable before the handshake completes
+    client.control(control_message::FastOpen(
+        4001,
+        SERVER.into(),
+        80,
+        b"in the SYN".to_vec(),
+    ))?;
+    let syn = take(&mut server)?;
+    assert_eq!(tcp_payload(&syn), b"in the SYN");
+    server.input(Instant::now(), &syn)?;
+    assert_eq!(server.flow(&SECOND).unwrap().state, State::SynRcvd);
+    let mut buf = [0u8; 64];
+    let n = server.read(&SECOND, &mut buf)?;
+    assert_eq!(&buf[..n], b"in the SYN");
+
+    // and isn't sent again after the handshake
+    pump(&mut server, &mut client)?;
+    assert_eq!(server.flow(&SECOND).unwrap().state, State::Estab);
+    assert_eq!(server.read(&SECOND, &mut buf)?, 0);
+    Ok(())
+}
+
+#[test]
+fn fastopen_invalid_cookie() -> io::Result<()> {
+    let (mut server, mut client) = fastopen_cookie_learned()?;
+    // another server at the same address doesn't know the cookie
+    let (c, mut d) = nic::pipe();
+    let mut other = tcp::with_device(c, SERVER);
+    other.control(control_message::Bind(80))?;
+    other.set_fastopen(true);
+
+    client.control(control_message::FastOpen(
+        4001,
+        SERVER.into(),
+        80,
+        b"in the SYN".to_vec(),
+    ))?;
+    let syn = take(&mut server)?;
+    assert_eq!(tcp_payload(&syn), b"in the SYN");
+    other.input(Instant::now(), &syn)?;
+    let mut buf = [0u8; 1504];
+    assert_eq!(other.read(&SECOND, &mut buf)?, 0);
+    // the SYN-ACK hands out a fresh cookie and acknowledges the SYN only
+    let n = d.recv(&mut buf)?;
+    assert!(fastopen_cookie(&buf[..n]).is_some());
+    client.input(Instant::now(), &buf[..n])?;
+
+    // the client sends the data again after the handshake
+    while let Ok(packet) = take(&mut server) {
+        other.input(Instant::now(), &packet)?;
+    }
+    let n = other.read(&SECOND, &mut buf)?;
+    assert_eq!(&buf[..n], b"in the SYN");
+    Ok(())
+}
+
+#[test]
+fn fastopen_cookie_not_given() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    // the server has Fast Open off and doesn't answer the cookie request
+    client.control(control_message::FastOpen(
+        4001,
+        SERVER.into(),
+        80,
+        b"later".to_vec(),
+    ))?;
+    deliver(&mut server)?;
+    let syn_ack = take(&mut client)?;
+    assert_eq!(fastopen_cookie(&syn_ack), None);
+    client.input(Instant::now(), &syn_ack)?;
+    pump(&mut server, &mut client)?;
+    let mut buf = [0u8; 64];
+    let n = server.read(&SECOND, &mut buf)?;
+    assert_eq!(&buf[..n], b"later");
+    Ok(())
+}
+
+#[test]
+fn fastopen_server_without_it() -> io::Result<()> {
+    let (mut server, mut client) = fastopen_cookie_learned()?;
+    server.set_fastopen(false);
+
+    // the SYN data is ignored and sent again once the connection is established
+    client.control(control_message::FastOpen(
+        4001,
+        SERVER.into(),
+        80,
+        b"in the SYN".to_vec(),
+    ))?;
+    let syn = take(&mut server)?;
+    assert_eq!(tcp_payload(&syn), b"in the SYN");
+    server.input(Instant::now(), &syn)?;
+    let mut buf = [0u8; 64];
+    assert_eq!(server.read(&SECOND, &mut buf)?, 0);
+    let syn_ack = take(&mut client)?;
+    assert_eq!(fastopen_cookie(&syn_ack), None);
+    client.input(Instant::now(), &syn_ack)?;
+    pump(&mut server, &mut client)?;
+    let n = server.read(&SECOND, &mut buf)?;
+    assert_eq!(&buf[..n], b"in the SYN");
+    Ok(())
+}
+
+/// A server and a client that both ask for ECN, connected from port 4000
+fn ecn_connected() -> io::Result<(tcp<nic::Pipe>, tcp<nic::Pipe>)> {
+    let (a, b) = nic::pipe();
+    let mut server = tcp::with_device(a, SERVER);
+    let mut client = tcp::with_device(b, CLIENT);
+    server.set_ecn(true);
+    client.set_ecn(true);
+    server.control(control_message::Bind(80))?;
+    client.control(control_message::Connect(4000, SERVER.into(), 80))?;
+    pump(&mut server, &mut client)?;
+    Ok((server, client))
+}
+
+/// The ECN codepoint of the IP packet `packet`
+fn ecn(packet: &[u8]) -> u8 {
+    tcp_proto::ip::parse(packet).expect("an IP packet").ecn
+}
+
+/// Mark the IPv4 packet `packet` Congestion Experienced, like a router would
+fn mark_ce(packet: &mut [u8]) {
+    let iph = etherparse::Ipv4HeaderSlice::from_slice(packet).unwrap();
+    let len = iph.slice().len();
+    let mut iph = iph.to_header();
+    iph.explicit_congestion_notification = ECN_CE;
+    iph.write(&mut &mut packet[..len]).unwrap();
+}
+
+#[test]
+fn ecn_negotiation() -> io::Result<()> {
+    // only the client asks, the server doesn't agree
+    let (a, b) = nic::pipe();
+    let mut server = tcp::with_device(a, SERVER);
+    let mut client = tcp::with_device(b, CLIENT);
+    client.set_ecn(true);
+    server.control(control_message::Bind(80))?;
+    client.control(control_message::Connect(4000, SERVER.into(), 80))?;
+    let syn = take(&mut server)?;
+    assert!(tcp_header(&syn).ece && tcp_header(&syn).cwr);
+    server.input(Instant::now(), &syn)?;
+    let syn_ack = take(&mut client)?;
+    assert!(!tcp_header(&syn_ack).ece);
+    client.input(Instant::now(), &syn_ack)?;
+    pump(&mut server, &mut client)?;
+    assert!(!client.info(&AT_CLIENT).unwrap().ecn);
+    assert!(!server.info(&AT_SERVER).unwrap().ecn);
+    client.write(&AT_CLIENT, b"not ECT")?;
+    assert_eq!(ecn(&take(&mut server)?), 0);
+
+    // both ask: new data is ECT(0), pure ACKs are not
+    let (mut server, mut client) = ecn_connected()?;
+    assert!(client.info(&AT_CLIENT).unwrap().ecn);
+    assert!(server.info(&AT_SERVER).unwrap().ecn);
+    client.write(&AT_CLIENT, b"ECT")?;
+    let data = take(&mut server)?;
+    assert_eq!(ecn(&data), ECN_ECT0);
+    server.input(Instant::now(), &data)?;
+    server.poll_timers(server.next_timeout().expect("the delayed ACK timer"))?;
+    assert_eq!(ecn(&take(&mut client)?), 0);
+    Ok(())
+}
+
+#[test]
+fn ecn_congestion_experienced() -> io::Result<()> {
+    let (mut server, mut client) = ecn_connected()?;
+    let data: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+    client.write(&AT_CLIENT, &data)?;
+    let mut segments = [take(&mut server)?, take(&mut server)?, take(&mut server)?];
+
+    // a router marks the first segment, the server echoes it with ECE
+    mark_ce(&mut segments[0]);
+    server.input(Instant::now(), &segments[0])?;
+    server.input(Instant::now(), &segments[1])?;
+    let ack = take(&mut client)?;
+    assert!(tcp_header(&ack).ece);
+    server.input(Instant::now(), &segments[2])?;
+
+    // the client reduces its window and says so with CWR on its next new data
+    let before = client.info(&AT_CLIENT).unwrap();
+    client.input(Instant::now(), &ack)?;
+    let after = client.info(&AT_CLIENT).unwrap();
+    assert!(after.cwnd < before.cwnd);
+    assert!(after.ssthresh < before.ssthresh);
+    client.write(&AT_CLIENT, b"with CWR")?;
+    let cwr = take(&mut server)?;
+    assert!(tcp_header(&cwr).cwr);
+
+    // the server keeps echoing until it sees the CWR, but that is the same window of data
+    server.poll_timers(server.next_timeout().expect("the delayed ACK timer"))?;
+    let ack = take(&mut client)?;
+    assert!(tcp_header(&ack).ece);
+    client.input(Instant::now(), &ack)?;
+    client.write(&AT_CLIENT, b"without CWR")?;
+    let no_cwr = take(&mut server)?;
+    assert!(!tcp_header(&no_cwr).cwr);
+
+    // CWR ends the echo
+    server.input(Instant::now(), &cwr)?;
+    server.input(Instant::now(), &no_cwr)?;
+    assert!(!tcp_header(&take(&mut client)?).ece);
+    Ok(())
+}
+
+#[test]
+fn ecn_retransmission() -> io::Result<()> {
+    let (mut server, mut client) = ecn_connected()?;
+    client.write(&AT_CLIENT, b"lost once")?;
+    assert_eq!(ecn(&take(&mut server)?), ECN_ECT0);
+    // a retransmission is not ECN-capable (RFC 3168 S6.1.5)
+    client.poll_timers(Instant::now() + Duration::from_secs(2))?;
+    let again = take(&mut server)?;
+    assert_eq!(tcp_payload(&again), b"lost once");
+    assert_eq!(ecn(&again), 0);
+    assert!(!tcp_header(&again).cwr);
+    Ok(())
+}
+
+#[test]
+fn flow_info() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    client.write(&AT_CLIENT, b"lost once")?;
+    drop_next(&mut server);
+    client.poll_timers(Instant::now() + Duration::from_secs(2))?;
+    pump(&mut server, &mut client)?;
+    let next = server.next_timeout().expect("the delayed ACK timer");
+    server.poll_timers(next)?;
+    pump(&mut server, &mut client)?;
+
+    let info = client.info(&AT_CLIENT).unwrap();
+    assert!(matches!(info.state, State::Estab));
+    assert!(info.rtt.is_some(), "the handshake is timed");
+    assert_eq!(info.bytes_sent, 18);
+    assert_eq!(info.bytes_retrans, 9);
+    assert_eq!(info.retrans_segs, 1);
+    assert_eq!(info.bytes_acked, 9);
+    assert_eq!(info.unacked, 0);
+    let info = server.info(&AT_SERVER).unwrap();
+    assert_eq!(info.bytes_received, 9);
+    assert_eq!(info.recv_queue, 9);
+    assert_eq!(info.retrans_segs, 0);
+    assert_eq!(info.segs_in, 3); // SYN, ACK and the data
+    assert!(server.info(&AT_CLIENT).is_none());
+    Ok(())
+}
+
+#[test]
+fn mib_counters() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    client.write(&AT_CLIENT, b"lost once")?;
+    drop_next(&mut server);
+    client.poll_timers(Instant::now() + Duration::from_secs(2))?;
+    pump(&mut server, &mut client)?;
+    // nobody listens on port 81, the server refuses it with a RST
+    let q = client.connect(SERVER.into(), 81)?;
+    pump(&mut server, &mut client)?;
+    assert!(client.flow(&q).is_none());
+    let e = client.take_error(&q).expect("an error");
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+
+    let counters = client.counters();
+    assert_eq!(counters.active_opens, 2);
+    assert_eq!(counters.attempt_fails, 1);
+    assert_eq!(counters.retrans_segs, 1);
+    let sent = counters.out_segs;
+    let counters = server.counters();
+    assert_eq!(counters.passive_opens, 1);
+    assert_eq!(counters.dropped(DropReason::NoSocket), 1);
+    assert_eq!(counters.out_rsts, 1);
+    // all but the lost segment arrived
+    assert_eq!(counters.in_segs, sent - 1);
+    assert_eq!(counters.in_errs, 0);
+
+    let text = server.prometheus();
+    assert!(text.contains("# TYPE tcp_passive_opens_total counter\ntcp_passive_opens_total 1\n"));
+    assert!(text.contains("tcp_curr_estab 1\n"));
+    assert!(text.contains("packets_dropped_total{reason=\"no_socket\"} 1\n"));
+    assert!(text.contains("tcp_out_rsts_total 1\n"));
+    Ok(())
+}
+
+#[test]
+fn drop_reasons() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    let (tx, rx) = mpsc::channel();
+    server.set_drop_hook(move |reason, packet| tx.send((reason, packet.len())).unwrap());
+    let mut packet = [0u8; 1504];
+
+    client.connect(SERVER.into(), 81)?;
+    let n = server.nic.recv(&mut packet)?;
+    server.action(&packet, n)?;
+    assert_eq!(rx.try_recv(), Ok((DropReason::NoSocket, n)));
+
+    client.write(&AT_CLIENT, b"twice")?;
+    let n = server.nic.recv(&mut packet)?;
+    packet[n - 1] ^= 1;
+    server.action(&packet, n)?;
+    assert_eq!(rx.try_recv(), Ok((DropReason::TcpChecksum, n)));
+    packet[n - 1] ^= 1;
+    server.action(&packet, n)?;
+    assert!(rx.try_recv().is_err());
+    // the data is old the second time around, the client hears where the server is
+    server.action(&packet, n)?;
+    assert_eq!(rx.try_recv(), Ok((DropReason::OutOfWindow, n)));
+    assert!(client.nic.recv(&mut packet).is_ok());
+
+    let counters = server.counters();
+    assert_eq!(counters.dropped(DropReason::NoSocket), 1);
+    assert_eq!(counters.dropped(DropReason::TcpChecksum), 1);
+    assert_eq!(counters.dropped(DropReason::OutOfWindow), 1);
+    assert_eq!(counters.dropped(DropReason::BadAck), 0);
+    Ok(())
+}
+
+/// An IPv4 fragment of a UDP datagram from the client to the server
+fn fragment(id: u16, offset: usize, more: bool, payload: &[u8]) -> Vec<u8> {
+    let mut ip = etherparse::Ipv4Header::new(
+        payload.len() as u16,
+        64,
+        etherparse::IpTrafficClass::Udp,
+        CLIENT.octets(),
+        SERVER.octets(),
+    );
+    ip.identification = id;
+    ip.dont_fragment = false;
+    ip.more_fragments = more;
+    ip.fragments_offset = (offset / 8) as u16;
+    let mut packet = Vec::new();
+    ip.write(&mut packet).unwrap();
+    packet.extend_from_slice(payload);
+    packet
+}
+
+#[test]
+fn fragment_drop_reasons() -> io::Result<()> {
+    let (a, _b) = nic::pipe();
+    let mut server = tcp::with_device(a, SERVER);
+    let (tx, rx) = mpsc::channel();
+    server.set_drop_hook(move |reason, packet| tx.send((reason, packet.len())).unwrap());
+    let now = Instant::now();
+
+    // more follow but it isn't cut at a multiple of 8 bytes
+    let bad = fragment(1, 0, true, &[0; 30]);
+    server.input(now, &bad)?;
+    assert_eq!(
+        rx.try_recv(),
+        Ok((DropReason::FragmentMalformed, bad.len()))
+    );
+
+    // the same bytes twice with different data
+    server.input(now, &fragment(2, 0, true, &[0; 32]))?;
+    let conflict = fragment(2, 16, false, &[1; 32]);
+    server.input(now, &conflict)?;
+    assert_eq!(
+        rx.try_recv(),
+        Ok((DropReason::FragmentOverlap, conflict.len()))
+    );
+
+    // no room for two datagrams, the older one goes with the 64 bytes we had of it
+    server.set_reassembly_limits(100, Duration::from_secs(30));
+    server.input(now, &fragment(3, 0, true, &[0; 64]))?;
+    server.input(now, &fragment(4, 0, true, &[0; 64]))?;
+    assert_eq!(rx.try_recv(), Ok((DropReason::FragmentMemory, 64)));
+
+    // the rest never came
+    server.set_reassembly_limits(4096, Duration::from_secs(30));
+    server.input(
+        now + Duration::from_secs(30),
+        &fragment(5, 0, true, &[0; 64]),
+    )?;
+    assert_eq!(rx.try_recv(), Ok((DropReason::FragmentTimeout, 64)));
+    assert!(rx.try_recv().is_err());
+
+    // nor did the rest of this one, its timer goes off without another fragment
+    let later = now + Duration::from_secs(60);
+    assert_eq!(server.next_timeout(), Some(later));
+    server.poll_timers(later)?;
+    assert_eq!(rx.try_recv(), Ok((DropReason::FragmentTimeout, 64)));
+    assert!(rx.try_recv().is_err());
+    assert_eq!(server.next_timeout(), None);
+
+    let counters = server.counters();
+    assert_eq!(counters.dropped(DropReason::FragmentMalformed), 1);
+    assert_eq!(counters.dropped(DropReason::FragmentOverlap), 1);
+    assert_eq!(counters.dropped(DropReason::FragmentMemory), 1);
+    assert_eq!(counters.dropped(DropReason::FragmentTimeout), 2);
+    Ok(())
+}
+
+#[test]
+fn icmp_drop_reasons() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    let (tx, rx) = mpsc::channel();
+    server.set_drop_hook(move |reason, packet| tx.send((reason, packet.len())).unwrap());
+    let now = Instant::now();
+
+    // an echo reply to a request we never sent, and a message cut short
+    let reply = icmp_packet(CLIENT, SERVER, vec![0, 0, 0, 0, 0, 1, 0, 1]);
+    server.input(now, &reply)?;
+    assert_eq!(rx.try_recv(), Ok((DropReason::IcmpUnhandled, reply.len())));
+    let short = icmp_packet(CLIENT, SERVER, vec![8, 0, 0, 0]);
+    server.input(now, &short)?;
+    assert_eq!(rx.try_recv(), Ok((DropReason::IcmpUnhandled, short.len())));
+
+    // port unreachable about a segment of a connection we don't have
+    let segment = {
+        let mut packet = Vec::new();
+        etherparse::PacketBuilder::ipv4(SERVER.octets(), CLIENT.octets(), 64)
+            .tcp(80, 4001, 1000, 64240)
+            .write(&mut packet, &[])
+            .unwrap();
+        packet
+    };
+    let mut message = vec![3, 3, 0, 0, 0, 0, 0, 0];
+    message.extend_from_slice(&segment);
+    let error = icmp_packet(CLIENT, SERVER, message);
+    server.input(now, &error)?;
+    assert_eq!(rx.try_recv(), Ok((DropReason::IcmpNoSocket, error.len())));
+    assert!(rx.try_recv().is_err());
+
+    // a corrupted echo request isn't answered, a corrupted error doesn't touch the connection
+    let mut request = icmp_packet(CLIENT, SERVER, vec![8, 0, 0, 0, 0, 1, 0, 1]);
+    *request.last_mut().unwrap() ^= 1;
+    server.input(now, &request)?;
+    assert_eq!(rx.try_recv(), Ok((DropReason::IcmpChecksum, request.len())));
+    assert!(take(&mut client).is_err());
+    let mut ours = Vec::new();
+    etherparse::PacketBuilder::ipv4(SERVER.octets(), CLIENT.octets(), 64)
+        .tcp(80, 4000, 1000, 64240)
+        .write(&mut ours, &[])
+        .unwrap();
+    let mut message = vec![3, 3, 0, 0, 0, 0, 0, 0];
+    message.extend_from_slice(&ours);
+    let mut error = icmp_packet(CLIENT, SERVER, message);
+    *error.last_mut().unwrap() ^= 1;
+    server.input(now, &error)?;
+    assert_eq!(rx.try_recv(), Ok((DropReason::IcmpChecksum, error.len())));
+    assert_eq!(server.flow(&AT_SERVER).unwrap().state, State::Estab);
+    assert!(rx.try_recv().is_err());
+
+    let counters = server.counters();
+    assert_eq!(counters.dropped(DropReason::IcmpChecksum), 2);
+    assert_eq!(counters.dropped(DropReason::IcmpUnhandled), 2);
+    assert_eq!(counters.dropped(DropReason::IcmpNoSocket), 1);
+    assert!(server
+        .prometheus()
+        .contains("packets_dropped_total{reason=\"icmp_no_socket\"} 1\n"));
+    Ok(())
+}
+
+#[test]
+fn snapshot() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    client.write(&AT_CLIENT, b"unacknowledged")?;
+
+    let snapshot = server.snapshot();
+    assert_eq!(snapshot.listeners.len(), 1);
+    assert_eq!(snapshot.listeners[0].port, 80);
+    assert_eq!(snapshot.listeners[0].accept_queue, 1);
+    assert_eq!(snapshot.flows.len(), 1);
+    assert_eq!(snapshot.flows[0].quad, AT_SERVER);
+    server.accept(80);
+    assert_eq!(server.snapshot().listeners[0].accept_queue, 0);
+
+    let snapshot = client.snapshot();
+    assert!(snapshot.listeners.is_empty());
+    let info = &snapshot.flows[0];
+    assert_eq!(info.send_queue, 14);
+    assert!(matches!(info.timer, Some((Timer::Retransmit, _))));
+    let text = snapshot.to_string();
+    let lines: Vec<_> = text.lines().collect();
+    assert!(lines[0].starts_with("State"));
+    assert!(lines[1].starts_with("ESTAB"));
+    assert!(lines[1].contains("10.0.0.2:4000"));
+    assert!(lines[1].contains("10.0.0.1:80"));
+    assert!(lines[2].contains(" bytes_sent:14 "));
+    assert!(lines[2].contains(" timer:(on,"));
+    assert!(server.snapshot().to_string().contains("LISTEN"));
+    Ok(())
+}
+
+#[test]
+fn syn_retransmission() -> io::Result<()> {
+    let (a, b) = nic::pipe();
+    let mut server = tcp::with_device(a, SERVER);
+    let mut client = tcp::with_device(b, CLIENT);
+    server.control(control_message::Bind(80))?;
+    client.control(control_message::Connect(4000, SERVER.into(), 80))?;
+    drop_next(&mut server);
+    client.poll_timers(Instant::now() + Duration::from_secs(2))?;
+    pump(&mut server, &mut client)?;
+    assert_eq!(client.write(&AT_CLIENT, b"")?, 0);
+    Ok(())
+}
+
+/// The next packet queued for `stack`, without handing it over
+fn take<D: NetDevice>(stack: &mut tcp<D>) -> io::Result<Vec<u8>> {
+    let mut buf = [0u8; 1504];
+    let n = stack.nic.recv(&mut buf)?;
+    Ok(buf[..n].to_vec())
+}
+
+/// The payload of the TCP segment in `packet`
+fn tcp_payload(packet: &[u8]) -> Vec<u8> {
+    let iph = tcp_proto::ip::parse(packet).expect("an IP packet");
+    let tcph = etherparse::TcpHeaderSlice::from_slice(iph.payload).expect("a TCP segment");
+    iph.payload[tcph.slice().len()..].to_vec()
+}
+
+/// The TCP header of the segment in `packet`
+fn tcp_header(packet: &[u8]) -> etherparse::TcpHeader {
+    let iph = tcp_proto::ip::parse(packet).expect("an IP packet");
+    etherparse::TcpHeaderSlice::from_slice(iph.payload)
+        .expect("a TCP segment")
+        .to_header()
+}
+
+/// A segment from the server to the client made by hand, `flags` picks from "SAR" (SYN, ACK, RST)
+fn forged(seq: u32, ack: u32, flags: &str) -> Vec<u8> {
+    let mut segment = etherparse::PacketBuilder::ipv4(SERVER.octets(), CLIENT.octets(), 64)
+        .tcp(80, 4000, seq, 64240);
+    if flags.contains('S') {
+        segment = segment.syn();
+    }
+    if flags.contains('A') {
+        segment = segment.ack(ack);
+    }
+    if flags.contains('R') {
+        segment = segment.rst();
+    }
+    let mut packet = Vec::new();
+    segment.write(&mut packet, &[]).unwrap();
+    packet
+}
+
+#[test]
+fn syn_sent() -> io::Result<()> {
+    let (a, b) = nic::pipe();
+    let mut server = tcp::with_device(a, SERVER);
+    let mut client = tcp::with_device(b, CLIENT);
+    client.control(control_message::Connect(4000, SERVER.into(), 80))?;
+    let mut syn = [0u8; 1504];
+    let n = server.nic.recv(&mut syn)?;
+    let iss = tcp_header(&syn[..n]).sequence_number;
+    let now = Instant::now();
+
+    // an ACK of something we never sent is answered with a RST from where it points
+    client.input(now, &forged(1000, iss.wrapping_add(2), "SA"))?;
+    assert_eq!(client.counters().dropped(DropReason::BadAck), 1);
+    let rst = next_segment(&mut server)?;
+    assert!(rst.rst && !rst.ack);
+    assert_eq!(rst.sequence_number, iss.wrapping_add(2));
+    // no SYN, a RST that doesn't acknowledge the SYN: dropped without a word
+    client.input(now, &forged(1000, iss.wrapping_add(1), "A"))?;
+    client.input(now, &forged(1000, 0, "R"))?;
+    assert_eq!(client.counters().dropped(DropReason::BadAck), 3);
+    assert_eq!(client.flow(&AT_CLIENT).unwrap().state, State::SynSent);
+    assert!(take(&mut server).is_err());
+
+    // a SYN-ACK at the very end of the sequence space, RCV.NXT wraps around
+    client.input(now, &forged(u32::MAX, iss.wrapping_add(1), "SA"))?;
+    assert_eq!(client.flow(&AT_CLIENT).unwrap().state, State::Estab);
+    assert_eq!(next_segment(&mut server)?.acknowledgment_number, 0);
+    Ok(())
+}
+
+#[test]
+fn connection_refused() -> io::Result<()> {
+    let (mut a, b) = nic::pipe();
+    let mut client = tcp::with_device(b, CLIENT);
+    client.control(control_message::Connect(4000, SERVER.into(), 80))?;
+    let mut syn = [0u8; 1504];
+    let n = a.recv(&mut syn)?;
+    let iss = tcp_header(&syn[..n]).sequence_number;
+    client.input(Instant::now(), &forged(0, iss.wrapping_add(1), "RA"))?;
+    assert!(client.flow(&AT_CLIENT).is_none());
+    let e = client.take_error(&AT_CLIENT).expect("an error");
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+    assert_eq!(client.counters().attempt_fails, 1);
+    Ok(())
+}
+
+#[test]
+fn reset_for_closed_port() -> io::Result<()> {
+    let (a, b) = nic::pipe();
+    let mut server = tcp::with_device(a, SERVER);
+    let mut client = tcp::with_device(b, CLIENT);
+    server.control(control_message::Bind(80))?;
+    let now = Instant::now();
+    let segment = |dport, seq, ack: Option<u32>, rst| {
+        let mut builder = etherparse::PacketBuilder::ipv4(CLIENT.octets(), SERVER.octets(), 64)
+            .tcp(4000, dport, seq, 64240);
+        if let Some(ack) = ack {
+            builder = builder.ack(ack);
+        }
+        if rst {
+            builder = builder.rst();
+        }
+        let mut packet = Vec::new();
+        builder.write(&mut packet, b"data").unwrap();
+        packet
+    };
+
+    // without an ACK the RST acknowledges the segment, it has no sequence number of ours to use
+    server.input(now, &segment(81, 1000, None, false))?;
+    let rst = next_segment(&mut client)?;
+    assert!(rst.rst && rst.ack);
+    assert_eq!((rst.sequence_number, rst.acknowledgment_number), (0, 1004));
+    assert_eq!((rst.source_port, rst.destination_port), (81, 4000));
+
+    // with one the RST comes from where it points, a listener refuses ACKs too
+    for port in [81, 80] {
+        server.input(now, &segment(port, 1000, Some(5000), false))?;
+        let rst = next_segment(&mut client)?;
+        assert!(rst.rst && !rst.ack);
+        assert_eq!(rst.sequence_number, 5000);
+    }
+
+    // a RST is never answered
+    server.input(now, &segment(81, 1000, Some(5000), true))?;
+    server.input(now, &segment(80, 1000, Some(5000), true))?;
+    assert!(take(&mut client).is_err());
+    assert_eq!(server.counters().out_rsts, 3);
+    assert_eq!(server.counters().dropped(DropReason::NoSocket), 5);
+    Ok(())
+}
+
+#[test]
+fn reset_in_syn_received() -> io::Result<()> {
+    let (a, b) = nic::pipe();
+    let mut server = tcp::with_device(a, SERVER);
+    let mut client = tcp::with_device(b, CLIENT);
+    server.control(control_message::Bind(80))?;
+    client.control(control_message::Connect(4000, SERVER.into(), 80))?;
+    deliver(&mut server)?;
+    let syn_ack = take(&mut client)?;
+    let syn_ack = tcp_header(&syn_ack);
+    let now = Instant::now();
+    let to_server = |ack: u32, rst: bool| {
+        let mut builder = etherparse::PacketBuilder::ipv4(CLIENT.octets(), SERVER.octets(), 64)
+            .tcp(4000, 80, syn_ack.acknowledgment_number, 64240)
+            .ack(ack);
+        if rst {
+            builder = builder.rst();
+        }
+        let mut packet = Vec::new();
+        builder.write(&mut packet, &[]).unwrap();
+        packet
+    };
+
+    // an ACK that isn't of our SYN-ACK gets a RST, the handshake goes on
+    server.input(
+        now,
+        &to_server(syn_ack.sequence_number.wrapping_add(5), false),
+    )?;
+    let rst = next_segment(&mut client)?;
+    assert!(rst.rst);
+    assert_eq!(rst.sequence_number, syn_ack.sequence_number.wrapping_add(5));
+    assert_eq!(server.flow(&AT_SERVER).unwrap().state, State::SynRcvd);
+
+    // a RST takes the flow away, nobody has accepted it so there is no error to keep
+    server.input(now, &to_server(0, true))?;
+    assert!(server.flow(&AT_SERVER).is_none());
+    assert!(server.take_error(&AT_SERVER).is_none());
+    assert!(server.accept(80).is_none());
+    assert!(take(&mut client).is_err());
+    Ok(())
+}
+
+#[test]
+fn fin_completes_handshake() -> io::Result<()> {
+    let (a, b) = nic::pipe();
+    let mut server = tcp::with_device(a, SERVER);
+    let mut client = tcp::with_device(b, CLIENT);
+    server.control(control_message::Bind(80))?;
+    client.control(control_message::Connect(4000, SERVER.into(), 80))?;
+    deliver(&mut server)?;
+    let syn_ack = tcp_header(&take(&mut client)?);
+
+    // the ACK of the SYN-ACK carries data and the FIN
+    let mut packet = Vec::new();
+    etherparse::PacketBuilder::ipv4(CLIENT.octets(), SERVER.octets(), 64)
+        .tcp(4000, 80, syn_ack.acknowledgment_number, 64240)
+        .ack(syn_ack.sequence_number.wrapping_add(1))
+        .fin()
+        .write(&mut packet, b"bye")
+        .unwrap();
+    server.input(Instant::now(), &packet)?;
+    assert_eq!(server.flow(&AT_SERVER).unwrap().state, State::CloseWait);
+    let ack = next_segment(&mut client)?;
+    assert_eq!(
+        ack.acknowledgment_number,
+        syn_ack.acknowledgment_number.wrapping_add(4)
+    );
+    let mut events = Vec::new();
+    while let Some(event) = server.poll_event() {
+        events.push(event);
+    }
+    assert!(events.contains(&Event::PeerClosed(AT_SERVER)));
+    let mut buf = [0u8; 16];
+    let n = server.read(&AT_SERVER, &mut buf)?;
+    assert_eq!(&buf[..n], b"bye");
+    Ok(())
+}
+
+#[test]
+fn connection_reset() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    server.write(&AT_SERVER, b"before")?;
+    let data = take(&mut client)?;
+    let nxt = tcp_header(&data).sequence_number.wrapping_add(6);
+    let now = Instant::now();
+    client.input(now, &data)?;
+    take_all(&mut server);
+
+    // a RST outside the window is dropped, one inside but not at RCV.NXT gets a challenge ACK
+    client.input(now, &forged(nxt.wrapping_add(1 << 20), 0, "R"))?;
+    assert!(take(&mut server).is_err());
+    client.input(now, &forged(nxt.wrapping_add(6), 0, "R"))?;
+    let challenge = next_segment(&mut server)?;
+    assert!(challenge.ack && !challenge.rst);
+    assert_eq!(challenge.acknowledgment_number, nxt);
+    assert_eq!(client.counters().dropped(DropReason::OutOfWindow), 2);
+    assert_eq!(client.flow(&AT_CLIENT).unwrap().state, State::Estab);
+
+    // at RCV.NXT it resets the connection, and isn't answered
+    client.input(now, &forged(nxt, 0, "R"))?;
+    assert!(take(&mut server).is_err());
+    assert!(client.flow(&AT_CLIENT).is_none());
+    let e = client.take_error(&AT_CLIENT).expect("an error");
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+    assert_eq!(client.counters().estab_resets, 1);
+    assert!(client.poll_event().is_some());
+    Ok(())
+}
+
+#[test]
+fn abort() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    server.abort(&AT_SERVER)?;
+    assert!(server.flow(&AT_SERVER).is_none());
+    assert!(server.take_error(&AT_SERVER).is_none());
+    assert_eq!(server.counters().out_rsts, 1);
+    assert_eq!(server.counters().estab_resets, 1);
+    pump(&mut server, &mut client)?;
+    assert_eq!(
+        client.take_error(&AT_CLIENT).unwrap().kind(),
+        io::ErrorKind::ConnectionReset
+    );
+    assert!(server.abort(&AT_SERVER).is_err());
+
+    // a SYN-RCVD flow is aborted on close
+    let (a, b) = nic::pipe();
+    let mut server = tcp::with_device(a, SERVER);
+    let mut client = tcp::with_device(b, CLIENT);
+    server.control(control_message::Bind(80))?;
+    client.control(control_message::Connect(4000, SERVER.into(), 80))?;
+    deliver(&mut server)?;
+    take(&mut client)?;
+    server.close(&AT_SERVER)?;
+    assert!(next_segment(&mut client)?.rst);
+    assert!(client.flow(&AT_CLIENT).is_none());
+    Ok(())
+}
+
+#[test]
+fn last_ack_bad_ack() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    client.close(&AT_CLIENT)?;
+    pump(&mut server, &mut client)?;
+    server.close(&AT_SERVER)?;
+    let fin = next_segment(&mut client)?;
+    assert_eq!(server.flow(&AT_SERVER).unwrap().state, State::LastAck);
+    // an ACK of something the server never sent gets an ACK of what it did send
+    let mut packet = Vec::new();
+    etherparse::PacketBuilder::ipv4(CLIENT.octets(), SERVER.octets(), 64)
+        .tcp(4000, 80, fin.acknowledgment_number, 64240)
+        .ack(fin.sequence_number.wrapping_add(100))
+        .write(&mut packet, &[])
+        .unwrap();
+    server.input(Instant::now(), &packet)?;
+    let ack = next_segment(&mut client)?;
+    assert!(ack.ack && !ack.rst);
+    assert_eq!(ack.sequence_number, fin.sequence_number.wrapping_add(1));
+    assert_eq!(server.counters().dropped(DropReason::BadAck), 1);
+    assert_eq!(server.flow(&AT_SERVER).unwrap().state, State::LastAck);
+    Ok(())
+}
+
+#[test]
+fn connect_timeout() -> io::Result<()> {
+    let (_a, b) = nic::pipe();
+    let mut client = tcp::with_device(b, CLIENT);
+    client.control(control_message::Connect(4000, SERVER.into(), 80))?;
+    // nobody answers, the RTO backs off up to a minute per try
+    let mut now = Instant::now();
+    for _ in 0..8 {
+        now += Duration::from_secs(120);
+        client.poll_timers(now)?;
+    }
+    // the flow is gone, its error stays until it is taken
+    assert!(client.flow(&AT_CLIENT).is_none());
+    let e = client.take_error(&AT_CLIENT).expect("an error");
+    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    assert!(client.take_error(&AT_CLIENT).is_none());
+    assert!(client.write(&AT_CLIENT, b"").is_err());
+    Ok(())
+}
+
+/// An ICMP message from `src` to `dst` with its checksum filled in
+fn icmp_packet(src: Ipv4Addr, dst: Ipv4Addr, mut message: Vec<u8>) -> Vec<u8> {
+    let sum = icmp::checksum(&message);
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+    let mut ip = tcp_proto::ip::Header::new(src.into(), dst.into(), icmp::PROTOCOL);
+    ip.set_payload_len(message.len()).unwrap();
+    let mut packet = Vec::new();
+    ip.write(&mut packet).unwrap();
+    packet.extend_from_slice(&message);
+    packet
+}
+
+/// A destination unreachable with `code` about the packet the client sent in `original`, from a router
+fn unreachable(code: u8, original: &[u8]) -> Vec<u8> {
+    let mut message = vec![3, code, 0, 0, 0, 0, 0, 0];
+    message.extend_from_slice(original);
+    icmp_packet(Ipv4Addr::new(10, 0, 0, 254), CLIENT, message)
+}
+
+#[test]
+fn icmp_echo() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    let request = vec![8, 0, 0, 0, 0x12, 0x34, 0, 1, b'p', b'i', b'n', b'g'];
+    server.input(Instant::now(), &icmp_packet(CLIENT, SERVER, request))?;
+    let reply = take(&mut client)?;
+    let iph = tcp_proto::ip::parse(&reply).expect("an IP packet");
+    assert_eq!(iph.protocol, icmp::PROTOCOL);
+    assert_eq!((iph.src, iph.dst), (IpAddr::V4(SERVER), IpAddr::V4(CLIENT)));
+    // an echo reply with the identifier, sequence number and data of the request
+    assert_eq!(&iph.payload[..2], &[0, 0]);
+    assert_eq!(
+        &iph.payload[4..],
+        &[0x12, 0x34, 0, 1, b'p', b'i', b'n', b'g']
+    );
+    assert_eq!(icmp::checksum(iph.payload), 0);
+    Ok(())
+}
+
+#[test]
+fn send_failure() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    let request = vec![8, 0, 0, 0, 0x12, 0x34, 0, 1, b'p', b'i', b'n', b'g'];
+    let mtu = server.nic.mtu;
+
+    // the device won't take the reply: it is counted and dropped, the input still succeeds
+    server.nic.mtu = 20;
+    server.input(
+        Instant::now(),
+        &icmp_packet(CLIENT, SERVER, request.clone()),
+    )?;
+    assert_eq!(server.counters().dropped(DropReason::SendFailed), 1);
+    assert!(take(&mut client).is_err());
+
+    // nothing is stuck behind it
+    server.nic.mtu = mtu;
+    server.input(Instant::now(), &icmp_packet(CLIENT, SERVER, request))?;
+    take(&mut client)?;
+    server.write(&AT_SERVER, b"still here")?;
+    pump(&mut server, &mut client)?;
+    let mut buf = [0u8; 64];
+    let n = client.read(&AT_CLIENT, &mut buf)?;
+    assert_eq!(&buf[..n], b"still here");
+    assert_eq!(server.counters().dropped(DropReason::SendFailed), 1);
+    Ok(())
+}
+
+#[test]
+fn icmp_port_unreachable() -> io::Result<()> {
+    let (a, b) = nic::pipe();
+    let mut server = tcp::with_device(a, SERVER);
+    let mut client = tcp::with_device(b, CLIENT);
+    client.udp_bind(5353)?;
+    client.send_to(5353, (SERVER.into(), 54), b"lost")?;
+    let datagram = take(&mut server)?;
+    server.input(Instant::now(), &datagram)?;
+    assert_eq!(server.counters().dropped(DropReason::UdpNoPort), 1);
+    let message = take(&mut client)?;
+    let iph = tcp_proto::ip::parse(&message).expect("an IP packet");
+    assert_eq!(iph.protocol, icmp::PROTOCOL);
+    assert_eq!(&iph.payload[..2], &[3, 3]);
+    assert_eq!(icmp::checksum(iph.payload), 0);
+    // the datagram that was refused follows
+    assert_eq!(&iph.payload[8..], &datagram[..]);
+
+    // nothing for a broadcast
+    let mut broadcast = Vec::new();
+    etherparse::PacketBuilder::ipv4(CLIENT.octets(), [255, 255, 255, 255], 64)
+        .udp(5353, 54)
+        .write(&mut broadcast, b"lost")
+        .unwrap();
+    server.set_promiscuous(true);
+    server.input(Instant::now(), &broadcast)?;
+    assert!(take(&mut client).is_err());
+    Ok(())
+}
+
+#[test]
+fn icmp_hard_error() -> io::Result<()> {
+    let (mut a, b) = nic::pipe();
+    let mut client = tcp::with_device(b, CLIENT);
+    client.control(control_message::Connect(4000, SERVER.into(), 80))?;
+    let mut syn = [0u8; 1504];
+    let n = a.recv(&mut syn)?;
+    // port unreachable while connecting aborts the connection
+    client.input(Instant::now(), &unreachable(3, &syn[..n]))?;
+    assert!(client.flow(&AT_CLIENT).is_none());
+    let e = client.take_error(&AT_CLIENT).expect("an error");
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+    Ok(())
+}
+
+#[test]
+fn icmp_soft_error() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    client.write(&AT_CLIENT, b"in flight")?;
+    let data = take(&mut server)?;
+
+    // about a segment that isn't in flight, ignored
+    let mut stale = data.clone();
+    let iph_len = tcp_proto::ip::parse(&data).unwrap().header.len();
+    let old = tcp_header(&data).sequence_number.wrapping_sub(100_000);
+    stale[iph_len + 4..iph_len + 8].copy_from_slice(&old.to_be_bytes());
+    client.input(Instant::now(), &unreachable(1, &stale))?;
+    assert!(client.take_error(&AT_CLIENT).is_none());
+    assert_eq!(client.counters().dropped(DropReason::OutOfWindow), 1);
+
+    // host unreachable doesn't end an established connection, it is only reported
+    client.input(Instant::now(), &unreachable(1, &data))?;
+    assert_eq!(client.flow(&AT_CLIENT).unwrap().state, State::Estab);
+    let e = client.take_error(&AT_CLIENT).expect("an error");
+    assert_eq!(e.kind(), io::ErrorKind::HostUnreachable);
+    // a hard error too, once the connection is established (RFC 5461 S4)
+    client.input(Instant::now(), &unreachable(3, &data))?;
+    assert_eq!(client.flow(&AT_CLIENT).unwrap().state, State::Estab);
+    assert_eq!(
+        client.take_error(&AT_CLIENT).unwrap().kind(),
+        io::ErrorKind::ConnectionRefused
+    );
+
+    // and the data still gets through
+    server.input(Instant::now(), &data)?;
+    pump(&mut server, &mut client)?;
+    let mut buf = [0u8; 64];
+    let n = server.read(&AT_SERVER, &mut buf)?;
+    assert_eq!(&buf[..n], b"in flight");
+    Ok(())
+}
+
+/// Every packet queued for `stack`, without handing them over
+fn take_all<D: NetDevice>(stack: &mut tcp<D>) -> Vec<Vec<u8>> {
+    std::iter::from_fn(|| take(stack).ok()).collect()
+}
+
+/// Like `pump`, with the stacks' clocks at `now`
+fn pump_at<D: NetDevice>(a: &mut tcp<D>, b: &mut tcp<D>, now: Instant) -> io::Result<()> {
+    loop {
+        let mut idle = true;
+        for stack in [&mut *a, &mut *b] {
+            for packet in take_all(stack) {
+                stack.input(now, &packet)?;
+                idle = false;
+            }
+        }
+        if idle {
+            return Ok(());
+        }
+    }
+}
+
+/// A router on the way to the server only forwards packets of up to 1000 bytes: the client
+/// learns it from a fragmentation needed about its first data segment, sent with `data` queued.
+/// Gives the sequence number of that segment too.
+fn behind_small_link(data: usize) -> io::Result<(tcp<nic::Pipe>, tcp<nic::Pipe>, u32)> {
+    let (mut server, mut client) = connected()?;
+    let data: Vec<u8> = (0..data).map(|i| i as u8).collect();
+    client.write(&AT_CLIENT, &data)?;
+    let too_big = take_all(&mut server);
+    assert!(too_big.iter().all(|p| p.len() == 1500));
+    let mut message = vec![3, 4, 0, 0, 0, 0];
+    message.extend_from_slice(&1000u16.to_be_bytes());
+    message.extend_from_slice(&too_big[0]);
+    let message = icmp_packet(Ipv4Addr::new(10, 0, 0, 254), CLIENT, message);
+    client.input(Instant::now(), &message)?;
+    Ok((server, client, tcp_header(&too_big[0]).sequence_number))
+}
+
+#[test]
+fn pmtu_fragmentation_needed() -> io::Result<()> {
+    let (mut server, mut client, first) = behind_small_link(20000)?;
+    let info = client.info(&AT_CLIENT).unwrap();
+    assert_eq!(info.pmtu, 1000);
+    assert_eq!(info.snd_mss, 960);
+    // what was in flight goes again in segments that fit
+    let again = take_all(&mut server);
+    assert!(!again.is_empty());
+    assert!(again.iter().all(|p| p.len() <= 1000));
+    assert_eq!(tcp_header(&again[0]).sequence_number, first);
+
+    // a next-hop MTU below the minimum or above the PMTU is ignored
+    for mtu in [40u16, 1400] {
+        let mut message = vec![3, 4, 0, 0, 0, 0];
+        message.extend_from_slice(&mtu.to_be_bytes());
+        message.extend_from_slice(&again[0]);
+        let message = icmp_packet(Ipv4Addr::new(10, 0, 0, 254), CLIENT, message);
+        client.input(Instant::now(), &message)?;
+        assert_eq!(client.info(&AT_CLIENT).unwrap().pmtu, 1000);
+    }
+    assert!(take_all(&mut server).is_empty());
+    Ok(())
+}
+
+/// Once the raise timer expired, the client's next full segment is a probe of 1250 bytes
+fn pmtu_probe(later: Instant) -> io::Result<(tcp<nic::Pipe>, tcp<nic::Pipe>, Vec<u8>)> {
+    let (mut server, mut client, _) = behind_small_link(40000)?;
+    // one round trip now starts the raise timer
+    for packet in take_all(&mut server) {
+        server.input(Instant::now(), &packet)?;
+    }
+    for packet in take_all(&mut client) {
+        client.input(Instant::now(), &packet)?;
+    }
+    for _ in 0..10 {
+        for packet in take_all(&mut server) {
+            server.input(later, &packet)?;
+        }
+        for packet in take_all(&mut client) {
+            client.input(later, &packet)?;
+        }
+        let sent = take_all(&mut server);
+        if let Some(i) = sent.iter().position(|p| p.len() > 1000) {
+            assert_eq!(sent[i].len(), 1250);
+            // hand back what went before the probe, keep the probe and what follows
+            for packet in &sent[..i] {
+                server.input(later, packet)?;
+            }
+            let mut rest = sent[i..].to_vec();
+            let probe = rest.remove(0);
+            for packet in rest {
+                client.nic.send(&packet)?;
+            }
+            return Ok((server, client, probe));
+        }
+        for packet in sent {
+            server.input(later, &packet)?;
+        }
+    }
+    panic!("no probe");
+}
+
+#[test]
+fn pmtu_probe_acked() -> io::Result<()> {
+    let later = Instant::now() + Duration::from_secs(601);
+    let (mut server, mut client, probe) = pmtu_probe(later)?;
+    server.input(later, &probe)?;
+    pump_at(&mut server, &mut client, later)?;
+    // and the search goes on from there
+    assert!(client.info(&AT_CLIENT).unwrap().pmtu >= 1250);
+    Ok(())
+}
+
+#[test]
+fn pmtu_probe_lost() -> io::Result<()> {
+    let later = Instant::now() + Duration::from_secs(601);
+    let (mut server, mut client, probe) = pmtu_probe(later)?;
+    // the segments after the probe bring duplicate ACKs
+    for packet in take_all(&mut server) {
+        server.input(later, &packet)?;
+    }
+    for packet in take_all(&mut client) {
+        client.input(later, &packet)?;
+    }
+    // the probe's data goes again in segments that fit
+    assert_eq!(client.info(&AT_CLIENT).unwrap().pmtu, 1000);
+    let seq = tcp_header(&probe).sequence_number;
+    let again = take_all(&mut server);
+    let again = again
+        .iter()
+        .find(|p| tcp_header(p).sequence_number == seq)
+        .expect("the probe's data again");
+    assert!(again.len() <= 1000);
+    Ok(())
+}
+
+#[test]
+fn pmtu_probe_and_timeout() -> io::Result<()> {
+    let later = Instant::now() + Duration::from_secs(601);
+    let (mut server, mut client, probe) = pmtu_probe(later)?;
+    let end = tcp_header(&probe).sequence_number + tcp_payload(&probe).len() as u32;
+    // everything after the probe is lost too, the retransmission fits the old PMTU
+    take_all(&mut server);
+    let mut now = later + Duration::from_secs(120);
+    client.poll_timers(now)?;
+    let mut probing = false;
+    let mut acked = false;
+    // acknowledging the probe's data doesn't count as the probe getting through,
+    // only a new probe may raise the PMTU
+    for _ in 0..50 {
+        for packet in take_all(&mut server) {
+            probing |= packet.len() > 1000;
+            server.input(now, &packet)?;
+        }
+        now += Duration::from_secs(1);
+        server.poll_timers(now)?;
+        for packet in take_all(&mut client) {
+            acked |= tcp_header(&packet).acknowledgment_number >= end;
+            client.input(now, &packet)?;
+            if !probing {
+                assert_eq!(client.info(&AT_CLIENT).unwrap().pmtu, 1000);
+            }
+        }
+        if acked {
+            break;
+        }
+    }
+    assert!(acked);
+    Ok(())
+}
+
+#[test]
+fn delayed_ack() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    client.write(&AT_CLIENT, b"one segment")?;
+    let mut buf = [0u8; 1504];
+    let n = server.nic.recv(&mut buf)?;
+    server.action(&buf, n)?;
+    // a single segment is not acknowledged right away
+    assert!(client.nic.recv(&mut buf).is_err());
+    let next = server.next_timeout().expect("the delayed ACK timer");
+    assert!(next <= Instant::now() + DELAYED_ACK + Duration::from_millis(20));
+    server.poll_timers(next)?;
+    assert!(client.nic.recv(&mut buf).is_ok());
+    Ok(())
+}
+
+#[test]
+fn event_loop() -> io::Result<()> {
+    let (a, b) = nic::pipe();
+    let mut event_loop = EventLoop::new(tcp::with_device(a, SERVER))?;
+    let handle = event_loop.handle();
+    thread::spawn(move || event_loop.run());
+    let mut client = tcp::with_device(b, CLIENT);
+
+    // commands run on the loop's thread, which answers through a channel
+    let (tx, rx) = mpsc::channel();
+    let bound = tx.clone();
+    handle.run(move |server| {
+        server.control(control_message::Bind(80)).unwrap();
+        bound.send(Vec::new()).unwrap();
+    })?;
+    rx.recv().unwrap();
+
+    client.control(control_message::Connect(4000, SERVER.into(), 80))?;
+    let mut buf = [0u8; 1504];
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut sent = false;
+    loop {
+        assert!(Instant::now() < deadline, "no answer from the event loop");
+        if let Ok(n) = client.nic.recv(&mut buf) {
+            client.action(&buf, n)?;
+        }
+        if !sent && client.write(&AT_CLIENT, b"to the loop").is_ok() {
+            sent = true;
+        }
+        let tx = tx.clone();
+        handle.run(move |server| {
+            let mut buf = [0u8; 64];
+            let n = server.read(&AT_SERVER, &mut buf).unwrap_or(0);
+            tx.send(buf[..n].to_vec()).unwrap();
+        })?;
+        let data = rx.recv().unwrap();
+        if !data.is_empty() {
+            assert_eq!(data, b"to the loop");
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Hand the next packet queued for `stack` to it
+fn deliver<D: NetDevice>(stack: &mut tcp<D>) -> io::Result<()> {
+    let mut buf = [0u8; 1504];
+    let n = stack.nic.recv(&mut buf)?;
+    stack.action(&buf, n)
+}
+
+#[test]
+fn events() -> io::Result<()> {
+    let (mut server, mut client) = connected()?;
+    assert_eq!(server.poll_event(), Some(Event::Established(AT_SERVER)));
+    assert_eq!(client.poll_event(), Some(Event::Established(AT_CLIENT)));
+
+    client.write(&AT_CLIENT, b"hello")?;
+    client.close(&AT_CLIENT)?;
+    deliver(&mut server)?;
+    assert_eq!(server.poll_event(), Some(Event::Readable(AT_SERVER)));
+    deliver(&mut server)?;
+    assert_eq!(server.poll_event(), Some(Event::PeerClosed(AT_SERVER)));
+    assert_eq!(server.flow(&AT_SERVER).unwrap().state, State::CloseWait);
+    pump(&mut server, &mut client)?;
+    assert_eq!(client.flow(&AT_CLIENT).unwrap().state, State::FinWait2);
+
+    // the server still sends after the client's FIN, and its own FIN follows the data
+    server.write(&AT_SERVER, b"after the FIN")?;
+    server.close(&AT_SERVER)?;
+    assert_eq!(server.flow(&AT_SERVER).unwrap().state, State::LastAck);
+    deliver(&mut client)?;
+    assert_eq!(client.poll_event(), Some(Event::Readable(AT_CLIENT)));
+    let mut buf = [0u8; 64];
+    let n = client.read(&AT_CLIENT, &mut buf)?;
+    assert_eq!(&buf[..n], b"after the FIN");
+    deliver(&mut client)?;
+    assert_eq!(client.poll_event(), Some(Event::PeerClosed(AT_CLIENT)));
+    assert_eq!(client.flow(&AT_CLIENT).unwrap().state, State::TimeWait);
+    pump(&mut server, &mut client)?;
+    assert_eq!(server.poll_event(), Some(Event::Closed(AT_SERVER)));
+    assert_eq!(server.poll_event(), None);
+    assert_eq!(client.poll_event(), None);
+    // the closed flow is forgotten and the quad may connect again
+    assert!(server.flow(&AT_SERVER).is_none());
+    assert!(server.snapshot().flows.is_empty());
+    client.poll_timers(Instant::now() + Duration::from_secs(120))?;
+    assert!(client.flow(&AT_CLIENT).is_none());
+    client.control(control_message::Connect(4000, SERVER.into(), 80))?;
+    pump(&mut server, &mut client)?;
+    assert_eq!(server.flow(&AT_SERVER).unwrap().state, State::Estab);
+    Ok(())
+}
@@ -0,0 +1,71 @@
+//! The PMTU search on its own, with time moved by hand
+use std::time::{Duration, Instant};
+
+use tcp_proto::tcp::pmtu::{Pmtu, BASE_PMTU, MIN_PMTU_V6};
+
+#[test]
+fn fragmentation_needed() {
+    let mut pmtu = Pmtu::new(1500, false);
+    assert!(pmtu.on_fragmentation_needed(1000));
+    assert_eq!(pmtu.pmtu, 1000);
+    // ICMP never raises it and nonsense below the minimum link MTU is ignored
+    assert!(!pmtu.on_fragmentation_needed(1400));
+    assert!(!pmtu.on_fragmentation_needed(1000));
+    assert!(!pmtu.on_fragmentation_needed(40));
+    assert_eq!(pmtu.pmtu, 1000);
+    // without a next-hop MTU it steps down to the next plateau
+    assert!(pmtu.on_fragmentation_needed(0));
+    assert_eq!(pmtu.pmtu, 508);
+
+    // IPv6 links carry at least 1280 bytes
+    let mut pmtu = Pmtu::new(1500, true);
+    assert!(!pmtu.on_fragmentation_needed(1000));
+    assert!(pmtu.on_fragmentation_needed(MIN_PMTU_V6));
+    assert_eq!(pmtu.pmtu, MIN_PMTU_V6);
+}
+
+#[test]
+fn probe_acked() {
+    let now = Instant::now();
+    let mut pmtu = Pmtu::new(1500, false);
+    assert!(pmtu.on_black_hole());
+    assert_eq!(pmtu.pmtu, BASE_PMTU);
+    // halfway to the interface MTU, one probe at a time
+    assert_eq!(pmtu.probe_size(now), Some(1350));
+    pmtu.probe_sent(1350, 5000);
+    assert_eq!(pmtu.probe_size(now), None);
+    assert_eq!(pmtu.probe_in_flight(), Some((1350, 5000)));
+    pmtu.probe_acked();
+    assert_eq!(pmtu.pmtu, 1350);
+    assert_eq!(pmtu.probe_in_flight(), None);
+    assert_eq!(pmtu.probe_size(now), Some(1425));
+}
+
+#[test]
+fn probe_lost() {
+    let now = Instant::now();
+    let mut pmtu = Pmtu::new(1500, false);
+    pmtu.on_black_hole();
+    // a size is only given up after three lost probes
+    for _ in 0..2 {
+        assert_eq!(pmtu.probe_size(now), Some(1350));
+        pmtu.probe_sent(1350, 5000);
+        pmtu.probe_lost();
+    }
+    assert_eq!(pmtu.probe_size(now), Some(1350));
+    pmtu.probe_sent(1350, 5000);
+    pmtu.probe_lost();
+    assert_eq!(pmtu.pmtu, BASE_PMTU);
+    assert_eq!(pmtu.probe_size(now), Some(1275));
+}
+
+#[test]
+fn search_again_later() {
+    let now = Instant::now();
+    let mut pmtu = Pmtu::new(1500, false);
+    pmtu.on_fragmentation_needed(1000);
+    // the router told us, nothing to search until the raise timer
+    assert_eq!(pmtu.probe_size(now), None);
+    assert_eq!(pmtu.probe_size(now + Duration::from_secs(599)), None);
+    assert_eq!(pmtu.probe_size(now + Duration::from_secs(600)), Some(1250));
+}
@@ -0,0 +1,140 @@
+//! IPv4 fragment reassembly
+use std::time::Instant;
+
+use tcp_proto::reassembly::{self, Dropped, Input, Reassembler};
+
+/// A datagram with `len` bytes of payload, identification `id`
+fn datagram(id: u16, len: usize) -> (etherparse::Ipv4Header, Vec<u8>) {
+    let mut ip = etherparse::Ipv4Header::new(
+        len as u16,
+        64,
+        etherparse::IpTrafficClass::Udp,
+        [10, 0, 0, 2],
+        [10, 0, 0, 1],
+    );
+    ip.identification = id;
+    ip.dont_fragment = false;
+    let payload = (0..len).map(|i| i as u8).collect();
+    (ip, payload)
+}
+
+/// The fragment of `payload` from `start` to `end`
+fn fragment(ip: &etherparse::Ipv4Header, payload: &[u8], start: usize, end: usize) -> Vec<u8> {
+    let mut ip = ip.clone();
+    ip.fragments_offset = (start / 8) as u16;
+    ip.more_fragments = end < payload.len();
+    ip.set_payload_len(end - start).unwrap();
+    let mut packet = Vec::new();
+    ip.write(&mut packet).unwrap();
+    packet.extend_from_slice(&payload[start..end]);
+    packet
+}
+
+#[test]
+fn out_of_order() {
+    let (ip, payload) = datagram(1, 100);
+    let mut r = Reassembler::new();
+    let now = Instant::now();
+    let last = fragment(&ip, &payload, 48, 100);
+    assert!(reassembly::is_fragment(&last));
+    assert_eq!(r.input(now, &last), Input::Pending);
+    assert_eq!(
+        r.input(now, &fragment(&ip, &payload, 0, 24)),
+        Input::Pending
+    );
+    // a duplicate changes nothing
+    assert_eq!(
+        r.input(now, &fragment(&ip, &payload, 0, 24)),
+        Input::Pending
+    );
+    let packet = match r.input(now, &fragment(&ip, &payload, 24, 48)) {
+        Input::Complete(packet) => packet,
+        other => panic!("not complete: {:?}", other),
+    };
+
+    let iph = etherparse::Ipv4HeaderSlice::from_slice(&packet).unwrap();
+    assert!(!reassembly::is_fragment(&packet));
+    assert_eq!(iph.total_len() as usize, packet.len());
+    assert_eq!(&packet[iph.slice().len()..], &payload[..]);
+    assert_eq!(r.pending(), 0);
+}
+
+#[test]
+fn conflicting_overlap_drops_datagram() {
+    let (ip, payload) = datagram(2, 64);
+    let mut r = Reassembler::new();
+    let now = Instant::now();
+    assert_eq!(
+        r.input(now, &fragment(&ip, &payload, 0, 32)),
+        Input::Pending
+    );
+    let mut forged = payload.clone();
+    forged[20] ^= 0xff;
+    assert_eq!(
+        r.input(now, &fragment(&ip, &forged, 16, 48)),
+        Input::Overlap
+    );
+    assert_eq!(r.pending(), 0);
+    // the rest doesn't bring it back
+    assert_eq!(
+        r.input(now, &fragment(&ip, &payload, 32, 64)),
+        Input::Pending
+    );
+}
+
+#[test]
+fn malformed_fragments() {
+    let (ip, payload) = datagram(3, 64);
+    let mut r = Reassembler::new();
+    let now = Instant::now();
+    // not a multiple of 8 bytes, but more follow
+    assert_eq!(
+        r.input(now, &fragment(&ip, &payload, 0, 30)),
+        Input::Malformed
+    );
+    // beyond the largest datagram
+    let (ip, payload) = datagram(4, 65000);
+    let mut last = fragment(&ip, &payload, 64800, 65000);
+    let mut iph = etherparse::Ipv4HeaderSlice::from_slice(&last)
+        .unwrap()
+        .to_header();
+    iph.fragments_offset = 8190;
+    iph.write(&mut &mut last[..20]).unwrap();
+    assert_eq!(r.input(now, &last), Input::Malformed);
+    assert_eq!(r.pending(), 0);
+}
+
+#[test]
+fn limits() {
+    let mut r = Reassembler::new();
+    let now = Instant::now();
+    r.max_memory = 100;
+    for id in 0..4 {
+        let (ip, payload) = datagram(id, 128);
+        r.input(now, &fragment(&ip, &payload, 0, 64));
+    }
+    // only the newest fits, the others are dropped oldest first
+    assert_eq!(r.pending(), 1);
+    let dropped = r.take_dropped();
+    assert_eq!(dropped.len(), 3);
+    assert!(dropped
+        .iter()
+        .all(|(why, data)| *why == Dropped::Evicted && data.len() == 64));
+    assert!(r.take_dropped().is_empty());
+
+    r.max_memory = reassembly::DEFAULT_MAX_MEMORY;
+    let (ip, payload) = datagram(9, 128);
+    assert_eq!(
+        r.input(now, &fragment(&ip, &payload, 64, 128)),
+        Input::Pending
+    );
+    // the rest comes too late
+    let later = now + reassembly::DEFAULT_TIMEOUT;
+    assert_eq!(
+        r.input(later, &fragment(&ip, &payload, 0, 64)),
+        Input::Pending
+    );
+    let dropped = r.take_dropped();
+    assert_eq!(dropped.len(), 2);
+    assert!(dropped.iter().all(|(why, _)| *why == Dropped::TimedOut));
+}
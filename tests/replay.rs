@@ -0,0 +1,148 @@
+//! Regression tests that replay captured traffic into a stack and compare what it sends
+//! with what it sent when the capture was taken.
+//!
+//! The captures in `tests/data` come in pairs: `<name>.in.pcap` holds the packets the stack
+//! received, `<name>.out.pcap` what it answered. After a deliberate change in behaviour,
+//! `cargo test --test replay -- --ignored` records them again.
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Instant, SystemTime};
+
+use tcp_proto::ip;
+use tcp_proto::nic::{self, Medium, NetDevice};
+use tcp_proto::pcap;
+use tcp_proto::tcp::flow::{Quad, DELAYED_ACK};
+use tcp_proto::tcp::{control_message, tcp};
+
+// the addresses of run.sh, test.py connects from 192.168.0.1 to 192.168.0.2:4000
+const STACK: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+const PEER: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+const PEER_PORT: u16 = 51000;
+
+fn data_path(name: &str) -> String {
+    format!("{}/tests/data/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+fn read_pcap(name: &str) -> io::Result<Vec<Vec<u8>>> {
+    let mut reader = pcap::Reader::new(BufReader::new(File::open(data_path(name))?))?;
+    let mut packets = Vec::new();
+    while let Some(record) = reader.next_packet()? {
+        packets.push(record.data);
+    }
+    Ok(packets)
+}
+
+fn write_pcap(name: &str, packets: &[Vec<u8>]) -> io::Result<()> {
+    let file = BufWriter::new(File::create(data_path(name))?);
+    let mut writer = pcap::Writer::new(file, pcap::LINKTYPE_RAW)?;
+    for p in packets {
+        writer.write_packet(SystemTime::now(), p)?;
+    }
+    Ok(())
+}
+
+/// Feed the whole capture to the stack, then let the last delayed ACK go
+fn replay(stack: &mut tcp<pcap::Replay>) -> io::Result<()> {
+    let mut buf = [0u8; 1504];
+    loop {
+        match stack.nic.recv(&mut buf) {
+            Ok(n) => stack.action(&buf, n)?,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+    }
+    stack.poll_timers(Instant::now() + DELAYED_ACK)
+}
+
+/// what test.py sends: the same bytes as the mp4, as far as the stack can tell
+fn payload() -> Vec<u8> {
+    (0..20_000u32).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+/// The stack listening on 4000 like run.sh, fed `<name>.in.pcap`
+fn replayed(name: &str) -> io::Result<tcp<pcap::Replay>> {
+    let inbound = read_pcap(&format!("{}.in.pcap", name))?;
+    let mut stack = tcp::with_device(pcap::Replay::new(inbound, Medium::Ip), STACK);
+    stack.control(control_message::Bind(4000))?;
+    replay(&mut stack)?;
+    Ok(stack)
+}
+
+#[test]
+fn mp4_transfer() -> io::Result<()> {
+    let mut stack = replayed("mp4_transfer")?;
+    let expected = read_pcap("mp4_transfer.out.pcap")?;
+    assert_eq!(stack.nic.remaining(), 0);
+    assert_eq!(stack.nic.sent.len(), expected.len());
+    for (i, (sent, expected)) in stack.nic.sent.iter().zip(&expected).enumerate() {
+        assert_eq!(sent, expected, "packet {} differs", i);
+    }
+
+    let quad = Quad {
+        src: (IpAddr::V4(PEER), PEER_PORT),
+        dst: (IpAddr::V4(STACK), 4000),
+    };
+    let mut received = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        match stack.read(&quad, &mut buf) {
+            Ok(0) => break,
+            Ok(n) => received.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+    }
+    assert_eq!(received, payload());
+    Ok(())
+}
+
+/// Record `mp4_transfer` again: a peer stack plays test.py against the stack over a pipe
+#[test]
+#[ignore]
+fn record_mp4_transfer() -> io::Result<()> {
+    let (a, b) = nic::pipe();
+    let mut stack = tcp::with_device(a, STACK);
+    let mut peer = tcp::with_device(pcap::Capture::new(b, Vec::new())?, PEER);
+    stack.control(control_message::Bind(4000))?;
+    peer.control(control_message::Connect(PEER_PORT, STACK.into(), 4000))?;
+    let quad = Quad {
+        src: (IpAddr::V4(STACK), 4000),
+        dst: (IpAddr::V4(PEER), PEER_PORT),
+    };
+    let mut buf = [0u8; 1504];
+    let mut written = false;
+    let mut quiet = 0;
+    while quiet < 3 {
+        let mut idle = true;
+        if let Ok(n) = stack.nic.recv(&mut buf) {
+            stack.action(&buf, n)?;
+            idle = false;
+        }
+        if let Ok(n) = peer.nic.recv(&mut buf) {
+            peer.action(&buf, n)?;
+            idle = false;
+        }
+        if !written && peer.write(&quad, &payload()).is_ok() {
+            written = true;
+            idle = false;
+        }
+        if idle {
+            // the peer may be waiting for a delayed ACK
+            stack.poll_timers(Instant::now() + DELAYED_ACK)?;
+        }
+        quiet = if idle { quiet + 1 } else { 0 };
+    }
+
+    // what the peer sent is what the stack received
+    let mut reader = pcap::Reader::new(&peer.nic.get_ref()[..])?;
+    let mut inbound = Vec::new();
+    while let Some(record) = reader.next_packet()? {
+        if ip::parse(&record.data).map(|p| p.src) == Some(PEER.into()) {
+            inbound.push(record.data);
+        }
+    }
+    write_pcap("mp4_transfer.in.pcap", &inbound)?;
+    let stack = replayed("mp4_transfer")?;
+    write_pcap("mp4_transfer.out.pcap", &stack.nic.sent)
+}
@@ -0,0 +1,101 @@
+//! Two flows driven by hand, without a device or a stack around them: packets go from one
+//! `Output` to the other flow and time only moves when the test says so
+use std::collections::VecDeque;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+use tcp_proto::ip;
+use tcp_proto::nic::DEFAULT_MTU;
+use tcp_proto::tcp::flow::{flow, Quad, State};
+use tcp_proto::tcp::output::{Event, Output};
+
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+// quads name the peer first
+const AT_SERVER: Quad = Quad {
+    src: (IpAddr::V4(CLIENT), 4000),
+    dst: (IpAddr::V4(SERVER), 80),
+};
+const AT_CLIENT: Quad = Quad {
+    src: (IpAddr::V4(SERVER), 80),
+    dst: (IpAddr::V4(CLIENT), 4000),
+};
+
+fn segment(packet: &[u8]) -> (ip::Packet<'_>, etherparse::TcpHeaderSlice<'_>, &[u8]) {
+    let iph = ip::parse(packet).expect("an IP packet");
+    let tcph = etherparse::TcpHeaderSlice::from_slice(iph.payload).expect("a TCP segment");
+    let data = &iph.payload[tcph.slice().len()..];
+    (iph, tcph, data)
+}
+
+/// Hand every packet queued in `from` to `to`
+fn deliver(from: &mut VecDeque<Vec<u8>>, to: &mut flow, out: &mut Output) -> io::Result<()> {
+    while let Some(packet) = from.pop_front() {
+        let (_, tcph, data) = segment(&packet);
+        to.on_segment(out, tcph, data)?;
+    }
+    Ok(())
+}
+
+/// A handshake at `now`, with the outputs of the client and the server
+fn connected(now: Instant) -> io::Result<(flow, Output, flow, Output)> {
+    let mut client_out = Output::new(now, DEFAULT_MTU);
+    let mut server_out = Output::new(now, DEFAULT_MTU);
+    let mut client =
+        flow::active_three_way_handshake(&mut client_out, &AT_CLIENT, &[], None, false)?.unwrap();
+    let syn = client_out.packets.pop_front().unwrap();
+    let (iph, tcph, _) = segment(&syn);
+    let mut server =
+        flow::passive_three_way_handshake(&mut server_out, &iph, tcph, 1000, &[], None, false)?
+            .unwrap();
+    deliver(&mut server_out.packets, &mut client, &mut client_out)?;
+    deliver(&mut client_out.packets, &mut server, &mut server_out)?;
+    Ok((client, client_out, server, server_out))
+}
+
+#[test]
+fn handshake_and_data() -> io::Result<()> {
+    let (mut client, mut client_out, mut server, mut server_out) = connected(Instant::now())?;
+    assert_eq!(client.state, State::Estab);
+    assert_eq!(server.state, State::Estab);
+    assert_eq!(
+        client_out.events.pop_front(),
+        Some(Event::Established(AT_CLIENT))
+    );
+    assert_eq!(
+        server_out.events.pop_front(),
+        Some(Event::Established(AT_SERVER))
+    );
+
+    client.send(&mut client_out, b"no device", false)?;
+    deliver(&mut client_out.packets, &mut server, &mut server_out)?;
+    assert_eq!(
+        server_out.events.pop_front(),
+        Some(Event::Readable(AT_SERVER))
+    );
+    let mut buf = [0u8; 16];
+    let n = server.read(&mut buf);
+    assert_eq!(&buf[..n], b"no device");
+    Ok(())
+}
+
+#[test]
+fn retransmission_in_simulated_time() -> io::Result<()> {
+    let start = Instant::now();
+    let (mut client, mut client_out, mut server, mut server_out) = connected(start)?;
+    client.send(&mut client_out, b"lost once", false)?;
+    // the segment never arrives
+    assert_eq!(client_out.packets.drain(..).count(), 1);
+
+    // an hour later for the flows, at once for the test
+    client_out.now = start + Duration::from_secs(3600);
+    client.on_timer(&mut client_out)?;
+    assert_eq!(client_out.packets.len(), 1);
+    deliver(&mut client_out.packets, &mut server, &mut server_out)?;
+    let mut buf = [0u8; 16];
+    let n = server.read(&mut buf);
+    assert_eq!(&buf[..n], b"lost once");
+    Ok(())
+}
@@ -0,0 +1,60 @@
+//! SYN cookies on their own, with time moved by hand
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+use tcp_proto::tcp::flow::Quad;
+use tcp_proto::tcp::syncookie::SynCookies;
+
+const PEER: Quad = Quad {
+    src: (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 4000),
+    dst: (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 80),
+};
+
+#[test]
+fn valid_cookie() {
+    let now = Instant::now();
+    let cookies = SynCookies::new(now);
+    let cookie = cookies.generate(&PEER, 1000, 1460, now);
+    assert_eq!(cookies.validate(&PEER, 1000, cookie, now), Some(1460));
+    // still good in the next counter slots
+    let later = now + Duration::from_secs(100);
+    assert_eq!(cookies.validate(&PEER, 1000, cookie, later), Some(1460));
+}
+
+#[test]
+fn mss_is_rounded_down() {
+    let now = Instant::now();
+    let cookies = SynCookies::new(now);
+    let cookie = cookies.generate(&PEER, 1000, 1400, now);
+    assert_eq!(cookies.validate(&PEER, 1000, cookie, now), Some(1300));
+    let cookie = cookies.generate(&PEER, 1000, 100, now);
+    assert_eq!(cookies.validate(&PEER, 1000, cookie, now), Some(536));
+}
+
+#[test]
+fn expired_cookie() {
+    let now = Instant::now();
+    let cookies = SynCookies::new(now);
+    let cookie = cookies.generate(&PEER, 1000, 1460, now);
+    let later = now + Duration::from_secs(4 * 64);
+    assert_eq!(cookies.validate(&PEER, 1000, cookie, later), None);
+}
+
+#[test]
+fn forged_cookie() {
+    let now = Instant::now();
+    let cookies = SynCookies::new(now);
+    let cookie = cookies.generate(&PEER, 1000, 1460, now);
+    // another hash, another IRS, another peer or another secret
+    assert_eq!(cookies.validate(&PEER, 1000, cookie ^ 1, now), None);
+    assert_eq!(cookies.validate(&PEER, 1001, cookie, now), None);
+    let other = Quad {
+        src: (PEER.src.0, 4001),
+        ..PEER
+    };
+    assert_eq!(cookies.validate(&other, 1000, cookie, now), None);
+    assert_eq!(
+        SynCookies::new(now).validate(&PEER, 1000, cookie, now),
+        None
+    );
+}